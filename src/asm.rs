@@ -0,0 +1,411 @@
+/*
+ *  Copyright (C) 2021, Wafelack <wafelack@protonmail.com>
+ *
+ *  ------------------------------------------------------
+ *
+ *     This file is part of Orion.
+ *
+ *  Orion is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  Orion is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with Orion.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::{
+    bytecode::{Bytecode, BytecodePattern, Chunk, OpCode, Position},
+    error,
+    parser::Literal,
+    Result,
+};
+
+#[derive(Clone, PartialEq, Debug)]
+enum Token {
+    Word(String),
+    Str(String),
+    LBracket,
+    RBracket,
+}
+
+struct Line {
+    line: usize,
+    indent: usize,
+    tokens: Vec<Token>,
+}
+
+const SECTIONS: [&str; 7] = ["symbols", "constants", "constructors", "types", "patterns", "chunks", "instructions"];
+
+// Reads back the output of the disassembler.
+pub struct Assembler {
+    input: String,
+    output: Bytecode,
+    file: String,
+    line: usize,
+}
+
+impl Assembler {
+    pub fn new(input: impl ToString, file: impl ToString) -> Self {
+        Self {
+            input: input.to_string(),
+            output: Bytecode::new(),
+            file: file.to_string(),
+            line: 0,
+        }
+    }
+    fn tokenize(&self, line: usize, raw: &str) -> Result<Line> {
+        let mut chars = raw.chars().peekable();
+        let mut indent = 0;
+        while let Some(c) = chars.peek() {
+            match c {
+                ' ' => indent += 1,
+                '\t' => indent += 4,
+                _ => break,
+            }
+            chars.next();
+        }
+
+        let mut tokens = vec![];
+        while let Some(c) = chars.next() {
+            match c {
+                ';' => break,
+                '[' => tokens.push(Token::LBracket),
+                ']' => tokens.push(Token::RBracket),
+                '"' => {
+                    let mut s = String::new();
+                    loop {
+                        match chars.next() {
+                            None => return error!(E0009; self.file, line => "Unterminated string."),
+                            Some('"') => break,
+                            Some('\\') => s.push(match chars.next() {
+                                Some('n') => '\n',
+                                Some('r') => '\r',
+                                Some('t') => '\t',
+                                Some('0') => '\0',
+                                Some('\\') => '\\',
+                                Some('\'') => '\'',
+                                Some('"') => '"',
+                                Some('u') => {
+                                    if chars.next() != Some('{') {
+                                        return error!(E0009; self.file, line => "Invalid unicode escape.");
+                                    }
+                                    let code = chars.by_ref().take_while(|c| *c != '}').collect::<String>();
+                                    match u32::from_str_radix(&code, 16).ok().and_then(std::char::from_u32) {
+                                        Some(c) => c,
+                                        None => return error!(E0009; self.file, line => "Invalid unicode escape: \\u{{{}}}.", code),
+                                    }
+                                }
+                                c => return error!(E0009; self.file, line => "Invalid escape sequence: \\{}.", c.map_or("".to_string(), |c| c.to_string())),
+                            }),
+                            Some(c) => s.push(c),
+                        }
+                    }
+                    tokens.push(Token::Str(s));
+                }
+                c if c.is_whitespace() => {}
+                c => {
+                    let mut word = c.to_string();
+                    while let Some(c) = chars.peek() {
+                        if c.is_whitespace() || [';', '[', ']', '"'].contains(c) {
+                            break;
+                        }
+                        word.push(*c);
+                        chars.next();
+                    }
+                    tokens.push(Token::Word(word));
+                }
+            }
+        }
+
+        Ok(Line { line, indent, tokens })
+    }
+    fn hex(&self, token: Option<&Token>) -> Result<u32> {
+        match token {
+            Some(Token::Word(w)) => match u32::from_str_radix(w, 16) {
+                Ok(n) => Ok(n),
+                Err(_) => error!(E0009; self.file, self.line => "Expected an hexadecimal number, found `{}`.", w),
+            },
+            Some(t) => error!(E0009; self.file, self.line => "Expected an hexadecimal number, found {:?}.", t),
+            None => error!(E0009; self.file, self.line => "Expected an hexadecimal number, found nothing."),
+        }
+    }
+    fn name(&self, token: Option<&Token>) -> Result<String> {
+        match token {
+            Some(Token::Word(w)) | Some(Token::Str(w)) => Ok(w.clone()),
+            Some(t) => error!(E0009; self.file, self.line => "Expected a name, found {:?}.", t),
+            None => error!(E0009; self.file, self.line => "Expected a name, found nothing."),
+        }
+    }
+    fn literal(&self, token: Option<&Token>) -> Result<Literal> {
+        match token {
+            Some(Token::Str(s)) => Ok(Literal::String(s.clone())),
+            Some(Token::Word(w)) => match w.parse::<i32>() {
+                Ok(i) => Ok(Literal::Integer(i)),
+                Err(_) => match w.parse::<f32>() {
+                    Ok(f) => Ok(Literal::Single(f)),
+                    Err(_) => error!(E0009; self.file, self.line => "Expected a literal, found `{}`.", w),
+                },
+            },
+            Some(t) => error!(E0009; self.file, self.line => "Expected a literal, found {:?}.", t),
+            None => error!(E0009; self.file, self.line => "Expected a literal, found nothing."),
+        }
+    }
+    // Entries are prefixed with their index, which has to match their position.
+    fn entry(&self, tokens: &[Token], expected: usize) -> Result<()> {
+        let id = self.hex(tokens.first())?;
+        if id as usize != expected {
+            error!(E0009; self.file, self.line => "Expected entry 0x{:04X}, found entry 0x{:04X}.", expected, id)
+        } else {
+            Ok(())
+        }
+    }
+    fn end(&self, tokens: &[Token]) -> Result<()> {
+        match tokens.first() {
+            Some(t) => error!(E0009; self.file, self.line => "Unexpected {:?}.", t),
+            None => Ok(()),
+        }
+    }
+    fn ids(&self, tokens: &[Token]) -> Result<Vec<u32>> {
+        tokens.iter().map(|t| self.hex(Some(t))).collect()
+    }
+    // Reads a bracketed list of ids, and returns it with the following tokens.
+    fn list<'t>(&self, tokens: &'t [Token]) -> Result<(Vec<u32>, &'t [Token])> {
+        match tokens.first() {
+            Some(Token::LBracket) => {}
+            Some(t) => return error!(E0009; self.file, self.line => "Expected a `[`, found {:?}.", t),
+            None => return error!(E0009; self.file, self.line => "Expected a `[`, found nothing."),
+        }
+        match tokens.iter().position(|t| *t == Token::RBracket) {
+            Some(end) => Ok((self.ids(&tokens[1..end])?, &tokens[end + 1..])),
+            None => error!(E0009; self.file, self.line => "Unclosed list."),
+        }
+    }
+    fn instruction(&self, tokens: &[Token]) -> Result<OpCode> {
+        let mnemonic = self.name(tokens.first())?;
+        let mnemonic = mnemonic.as_str();
+        let (argc, constructor): (usize, fn(u32, u32, u32) -> OpCode) = match mnemonic {
+            "CONST" => (1, |i, _, _| OpCode::LoadConst(i)),
+            "GLOBAL" => (1, |i, _, _| OpCode::LoadGlobal(i)),
+            "LOCAL" => (1, |i, _, _| OpCode::LoadLocal(i)),
+            "UPVAL" => (1, |i, _, _| OpCode::LoadUpvalue(i)),
+            "SIBLING" => (1, |i, _, _| OpCode::LoadSibling(i)),
+            "CALL" => (1, |i, _, _| OpCode::Call(i)),
+            "BUILTIN" => (2, |i, a, _| OpCode::Builtin(i, a)),
+            "DEF" => (1, |i, _, _| OpCode::DefGlobal(i)),
+            "SET" => (1, |i, _, _| OpCode::DefLocal(i)),
+            "FUN" => (1, |i, _, _| OpCode::Lambda(i)),
+            "CONSTR" => (1, |i, _, _| OpCode::Constructor(i)),
+            "TUP" => (1, |a, _, _| OpCode::Tuple(a)),
+            "JMP" => (1, |o, _, _| OpCode::Jump(o)),
+            "JNM" => (3, OpCode::JumpIfNoMatch),
+            "SWITCH" => (3, OpCode::Switch),
+            "UNPACK" => (2, |s, d, _| OpCode::Unpack(s, d)),
+            "ENDM" => (1, |s, _, _| OpCode::EndMatch(s)),
+            "NOMATCH" => (0, |_, _, _| OpCode::NoMatch),
+            "PANIC" => (2, |f, l, _| OpCode::Panic(f, l)),
+            _ => return error!(E0009; self.file, self.line => "Unknown instruction: {}.", mnemonic),
+        };
+        let operands = self.ids(&tokens[1..])?;
+        if operands.len() != argc {
+            return error!(E0009; self.file, self.line => "`{}` takes {} operands, but {} were supplied.", mnemonic, argc, operands.len());
+        }
+        let operand = |idx: usize| operands.get(idx).copied().unwrap_or(0);
+        Ok(constructor(operand(0), operand(1), operand(2)))
+    }
+    // Positions apply from the instruction following them.
+    fn position(&self, tokens: &[Token], ip: usize) -> Result<Position> {
+        let operands = self.ids(&tokens[1..])?;
+        match operands.as_slice() {
+            [file, line, column, start, end] => Ok(Position { ip: ip as u32, file: *file, line: *line, column: *column, start: *start, end: *end }),
+            _ => error!(E0009; self.file, self.line => "`LINE` takes 5 operands, but {} were supplied.", operands.len()),
+        }
+    }
+    fn pattern(&self, tokens: &[Token]) -> Result<BytecodePattern> {
+        let kind = self.name(tokens.first())?;
+        let operands = self.ids(&tokens[1..])?;
+        let expect = |amount: usize| if operands.len() != amount {
+            error!(E0009; self.file, self.line => "`{}` takes {} operands, but {} were supplied.", kind, amount, operands.len())
+        } else {
+            Ok(())
+        };
+        match kind.as_str() {
+            "VAR" => expect(1).map(|_| BytecodePattern::Var(operands[0])),
+            "LIT" => expect(1).map(|_| BytecodePattern::Literal(operands[0])),
+            "ANY" => expect(0).map(|_| BytecodePattern::Any),
+            "TUP" => Ok(BytecodePattern::Tuple(operands)),
+            "CONSTR" => match operands.split_first() {
+                Some((idx, pats)) => Ok(BytecodePattern::Constr(*idx, pats.to_vec())),
+                None => error!(E0009; self.file, self.line => "`CONSTR` takes at least 1 operand, but 0 were supplied."),
+            },
+            _ => error!(E0009; self.file, self.line => "Unknown pattern kind: {}.", kind),
+        }
+    }
+    pub fn assemble(mut self) -> Result<Bytecode> {
+        let lines = self.input.lines().enumerate().map(|(idx, raw)| self.tokenize(idx + 1, raw)).collect::<Result<Vec<Line>>>()?;
+        let mut section: Option<&str> = None;
+        let mut seen = vec![];
+
+        for Line { line, indent, tokens } in lines.into_iter().filter(|l| !l.tokens.is_empty()) {
+            self.line = line;
+
+            if indent == 0 {
+                let name = match tokens.as_slice() {
+                    [Token::Word(w)] if w.ends_with(':') => &w[..w.len() - 1],
+                    _ => return error!(E0009; self.file, line => "Expected a section name, found {:?}.", tokens[0]),
+                };
+                section = match SECTIONS.iter().find(|s| **s == name) {
+                    Some(s) => Some(*s),
+                    None => return error!(E0009; self.file, line => "Unknown section: {}.", name),
+                };
+                if seen.contains(&name.to_string()) {
+                    return error!(E0009; self.file, line => "Duplicated section: {}.", name);
+                }
+                seen.push(name.to_string());
+                continue;
+            }
+
+            match section {
+                None => return error!(E0009; self.file, line => "Expected a section name, found {:?}.", tokens[0]),
+                Some("symbols") => {
+                    self.entry(&tokens, self.output.symbols.len())?;
+                    let name = self.name(tokens.get(1))?;
+                    self.end(&tokens[2..])?;
+                    self.output.symbols.push(name);
+                }
+                Some("constants") => {
+                    self.entry(&tokens, self.output.constants.len())?;
+                    let literal = self.literal(tokens.get(1))?;
+                    self.end(&tokens[2..])?;
+                    self.output.constants.push(literal);
+                }
+                Some("constructors") => {
+                    self.entry(&tokens, self.output.constructors.len())?;
+                    let sym = self.hex(tokens.get(1))?;
+                    let amount = self.hex(tokens.get(2))?;
+                    self.end(&tokens[3..])?;
+                    self.output.constructors.push((amount, sym));
+                }
+                Some("types") => {
+                    self.entry(&tokens, self.output.types.len())?;
+                    let name = self.name(tokens.get(1))?;
+                    let start = self.hex(tokens.get(2))?;
+                    let end = self.hex(tokens.get(3))?;
+                    self.end(&tokens[4..])?;
+                    self.output.types.push((name, start, end));
+                }
+                Some("patterns") => {
+                    self.entry(&tokens, self.output.patterns.len())?;
+                    let pattern = self.pattern(&tokens[1..])?;
+                    self.output.patterns.push(pattern);
+                }
+                Some("chunks") => if tokens.get(1) == Some(&Token::LBracket) {
+                    self.entry(&tokens, self.output.chunks.len())?;
+                    let (reference, rest) = self.list(&tokens[1..])?;
+                    let (captures, rest) = self.list(rest)?;
+                    let name = match rest.first() {
+                        Some(_) => Some(self.hex(rest.first())?),
+                        None => None,
+                    };
+                    self.end(&rest[name.iter().count()..])?;
+                    self.output.chunks.push(Chunk {
+                        instructions: vec![],
+                        reference,
+                        captures,
+                        name,
+                        lines: vec![],
+                    });
+                } else if tokens.first() == Some(&Token::Word("LINE".to_string())) {
+                    let pos = match self.output.chunks.last() {
+                        Some(chunk) => self.position(&tokens, chunk.instructions.len())?,
+                        None => return error!(E0009; self.file, line => "Position out of a chunk."),
+                    };
+                    if let Some(chunk) = self.output.chunks.last_mut() {
+                        chunk.lines.push(pos);
+                    }
+                } else {
+                    let length = match self.output.chunks.last() {
+                        Some(chunk) => chunk.instructions.len(),
+                        None => return error!(E0009; self.file, line => "Instruction out of a chunk."),
+                    };
+                    self.entry(&tokens, length)?;
+                    let instr = self.instruction(&tokens[1..])?;
+                    if let Some(chunk) = self.output.chunks.last_mut() {
+                        chunk.instructions.push(instr);
+                    }
+                },
+                Some(_) if tokens.first() == Some(&Token::Word("LINE".to_string())) => {
+                    let pos = self.position(&tokens, self.output.instructions.len())?;
+                    self.output.lines.push(pos);
+                }
+                Some(_) => {
+                    self.entry(&tokens, self.output.instructions.len())?;
+                    let instr = self.instruction(&tokens[1..])?;
+                    self.output.instructions.push(instr);
+                }
+            }
+        }
+
+        self.output.validate()?;
+        Ok(self.output)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{compiler::Compiler, disasm::Disassembler, lexer::Lexer, modules::Modules, parser::Parser, vm::{VM, Value}};
+    use quickcheck::quickcheck;
+
+    fn round_trips(bytecode: &Bytecode) -> Result<bool> {
+        Ok(Assembler::new(Disassembler::new(bytecode).disassemble(), "TEST").assemble()? == *bytecode)
+    }
+
+    quickcheck! {
+        fn lossless(bytecode: Bytecode) -> bool {
+            round_trips(&bytecode).unwrap()
+        }
+    }
+
+    #[test]
+    fn compiled() -> Result<()> {
+        let tokens = Lexer::new("(enum Maybe (Just x) Nothing) (def f (λ (m) (match m ((Just \"a;b\") 1.5) ((, _ x) (+ x 1)) (_ 0))))", "TEST").proc_tokens()?;
+        let (bytecode, ..) = Compiler::new(Parser::new(tokens, "TEST").parse()?, "TEST", Bytecode::new(), vec![], true, "".to_string(), false, vec![], Modules::default())?.compile(vec![])?;
+        assert!(round_trips(&bytecode)?);
+        Ok(())
+    }
+
+    #[test]
+    fn hand_written() -> Result<()> {
+        let bytecode = Assembler::new("
+constants:
+    0000 40
+    0001 2
+
+instructions:
+    0000 CONST   0000
+    0001 CONST   0001
+    0002 BUILTIN 00 02 ; +
+", "TEST").assemble()?;
+        let mut vm = VM::<16>::new(bytecode, vec![]);
+        vm.eval(false)?;
+        assert!(matches!(vm.stack.last().map(|v| (**v).clone()), Some(Value::Integer(42))));
+        Ok(())
+    }
+
+    #[test]
+    fn errors() {
+        let assemble = |input: &str| Assembler::new(input, "TEST").assemble().map_err(|e| (e.line, e.message.clone()));
+        assert_eq!(assemble("symbols:\n    0001 x"), Err((Some(2), "Expected entry 0x0000, found entry 0x0001.".to_string())));
+        assert_eq!(assemble("instructions:\n    0000 CALL 1 2"), Err((Some(2), "`CALL` takes 1 operands, but 2 were supplied.".to_string())));
+        assert_eq!(assemble("instructions:\n    0000 MATCH 0"), Err((Some(2), "Unknown instruction: MATCH.".to_string())));
+        assert_eq!(assemble("chunks:\n    0000 LOCAL 0"), Err((Some(2), "Instruction out of a chunk.".to_string())));
+        assert_eq!(assemble("instructions:\n    0000 GLOBAL 0"), Err((None, "Invalid bytecode: symbol 0x0000 is out of range.".to_string())));
+        assert_eq!(assemble("instructions:\n    0000 UPVAL 0"), Err((None, "Invalid bytecode: upvalue 0x0000 is out of range.".to_string())));
+        assert_eq!(assemble("instructions:\n    0000 JMP 1"), Err((None, "Invalid bytecode: instruction 0x0001 is out of range.".to_string())));
+    }
+}
@@ -18,139 +18,222 @@
  *  You should have received a copy of the GNU General Public License
  *  along with Orion.  If not, see <https://www.gnu.org/licenses/>.
  */
-use crate::{parser::Literal, error, Result};
-use std::fmt::{self, Formatter, Display};
+use crate::{parser::Literal, bug, error, Result};
+use std::{fmt::{self, Formatter, Display}, time::{SystemTime, UNIX_EPOCH}};
+
+pub const MAGIC: &[u8] = b"orion";
+pub const FORMAT_VERSION: u16 = 9;
+pub const MAX_SLOTS: u32 = 0x10000; // The amount of local slots a function may use, for its frames to be allocated safely.
 
 #[derive(PartialEq, Copy, Clone, Debug)]
 pub enum OpCode {
-    LoadConst(u16),        // (const_id)
-    LoadSym(u16),          // (sym_id)
-    Call(u16),             // (argc)
-    Builtin(u8, u8),       // (builtin_id, argc)
-    Def(u16, u16),         // (sym_id, instructions_length)
-    Lambda(u16),           // (chunk_id)
-    Constructor(u16, u16), // (constr_idx, valc)
-    Tuple(u16, u16),       // (instr_amount, amount)
-    Match(u16),            // (match_idx)
-    Panic(u16, u16),       // (file_sym, line_sym)
+    LoadConst(u32),        // (const_id)
+    LoadGlobal(u32),       // (sym_id)
+    LoadLocal(u32),        // (slot)
+    LoadUpvalue(u32),      // (capture_idx)
+    LoadSibling(u32),      // (chunk_id), a function of the same `letrec`, sharing the upvalues of the running one.
+    Call(u32),             // (argc)
+    Builtin(u32, u32),     // (builtin_id, argc)
+    DefGlobal(u32),        // (sym_id)
+    DefLocal(u32),         // (slot)
+    Lambda(u32),           // (chunk_id)
+    Constructor(u32),      // (constr_idx)
+    Tuple(u32),            // (amount)
+    Jump(u32),             // (offset)
+    JumpIfNoMatch(u32, u32, u32), // (slot, pat_idx, offset)
+    Switch(u32, u32, u32), // (slot, first_constr_idx, amount), followed by amount + 1 jumps.
+    Unpack(u32, u32),      // (slot, first_slot)
+    EndMatch(u32),         // (slots)
+    NoMatch,
+    Panic(u32, u32),       // (file_sym, line_sym)
+    Position(u32),         // (position_idx), only used by the compiler to build the line tables.
 }
 impl Display for OpCode {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         match self {
             Self::LoadConst(i)      => write!(f, "CONST   {:04X}", i),
-            Self::LoadSym(i)        => write!(f, "LOAD    {:04X}", i),
+            Self::LoadGlobal(i)     => write!(f, "GLOBAL  {:04X}", i),
+            Self::LoadLocal(i)      => write!(f, "LOCAL   {:04X}", i),
+            Self::LoadUpvalue(i)    => write!(f, "UPVAL   {:04X}", i),
+            Self::LoadSibling(i)    => write!(f, "SIBLING {:04X}", i),
             Self::Call(i)           => write!(f, "CALL    {:04X}", i),
             Self::Builtin(i, a)     => write!(f, "BUILTIN {:02X}   {:02X}", i, a),
-            Self::Def(i, l)         => write!(f, "DEF     {:04X} {:04X}", i, l),
+            Self::DefGlobal(i)      => write!(f, "DEF     {:04X}", i),
+            Self::DefLocal(i)       => write!(f, "SET     {:04X}", i),
             Self::Lambda(i)         => write!(f, "FUN     {:04X}", i),
-            Self::Constructor(i, v) => write!(f, "CONSTR  {:04X} {:04X}", i, v),
-            Self::Tuple(i, a)       => write!(f, "TUP     {:04X} {:04X}", i, a),
-            Self::Match(i)          => write!(f, "MATCH   {:04X}", i),
+            Self::Constructor(i)    => write!(f, "CONSTR  {:04X}", i),
+            Self::Tuple(a)          => write!(f, "TUP     {:04X}", a),
+            Self::Jump(o)           => write!(f, "JMP     {:04X}", o),
+            Self::JumpIfNoMatch(s, p, o) => write!(f, "JNM     {:04X} {:04X} {:04X}", s, p, o),
+            Self::Switch(s, c, a)   => write!(f, "SWITCH  {:04X} {:04X} {:04X}", s, c, a),
+            Self::Unpack(s, d)      => write!(f, "UNPACK  {:04X} {:04X}", s, d),
+            Self::EndMatch(s)       => write!(f, "ENDM    {:04X}", s),
+            Self::NoMatch           => write!(f, "NOMATCH"),
             Self::Panic(fi, l)      => write!(f, "PANIC   {:04X} {:04X}", fi, l),
+            Self::Position(i)       => write!(f, "POS     {:04X}", i),
         }
     }
 }
 impl OpCode {
     pub fn deserialize(ptr: &mut usize, bytes: &[u8]) -> Result<Self> {
-        *ptr += 1;
-        match bytes[*ptr - 1] {
-            0 => Ok(Self::LoadConst(len(ptr, bytes)?)),
-            1 => Ok(Self::LoadSym(len(ptr, bytes)?)),
-            2 => Ok(Self::Call(len(ptr, bytes)?)),
-            3 => {
-                *ptr += 2;
-                Ok(Self::Builtin(bytes[*ptr - 2], bytes[*ptr - 1])) 
-            }
-            4 => Ok(Self::Def(len(ptr, bytes)?, len(ptr, bytes)?)),
-            5 => Ok(Self::Lambda(len(ptr, bytes)?)),
-            6 => Ok(Self::Constructor(len(ptr, bytes)?, len(ptr, bytes)?)),
-            7 => Ok(Self::Tuple(len(ptr, bytes)?, len(ptr, bytes)?)),
-            8 => Ok(Self::Match(len(ptr, bytes)?)), 
-            9 => Ok(Self::Panic(len(ptr, bytes)?, len(ptr, bytes)?)),
-            x => error!(=> "Unrecognised op code: {}.", x),
+        match byte(ptr, bytes)? {
+            0 => Ok(Self::LoadConst(uint(ptr, bytes)?)),
+            1 => Ok(Self::LoadGlobal(uint(ptr, bytes)?)),
+            2 => Ok(Self::Call(uint(ptr, bytes)?)),
+            3 => Ok(Self::Builtin(uint(ptr, bytes)?, uint(ptr, bytes)?)),
+            4 => Ok(Self::DefGlobal(uint(ptr, bytes)?)),
+            5 => Ok(Self::Lambda(uint(ptr, bytes)?)),
+            6 => Ok(Self::Constructor(uint(ptr, bytes)?)),
+            7 => Ok(Self::Tuple(uint(ptr, bytes)?)),
+            8 => Ok(Self::Jump(uint(ptr, bytes)?)),
+            9 => Ok(Self::JumpIfNoMatch(uint(ptr, bytes)?, uint(ptr, bytes)?, uint(ptr, bytes)?)),
+            10 => Ok(Self::EndMatch(uint(ptr, bytes)?)),
+            11 => Ok(Self::NoMatch),
+            12 => Ok(Self::Panic(uint(ptr, bytes)?, uint(ptr, bytes)?)),
+            13 => Ok(Self::LoadLocal(uint(ptr, bytes)?)),
+            14 => Ok(Self::LoadUpvalue(uint(ptr, bytes)?)),
+            15 => Ok(Self::DefLocal(uint(ptr, bytes)?)),
+            16 => Ok(Self::Switch(uint(ptr, bytes)?, uint(ptr, bytes)?, uint(ptr, bytes)?)),
+            17 => Ok(Self::Unpack(uint(ptr, bytes)?, uint(ptr, bytes)?)),
+            18 => Ok(Self::LoadSibling(uint(ptr, bytes)?)),
+            x => error!(E0009; => "Unrecognised op code: {}.", x),
         }
     }
     pub fn serialize(&self) -> Vec<u8> {
-        match self {
-            Self::LoadConst(id) => {
-                let mut to_ret = vec![0];
-                to_ret.extend(&id.to_be_bytes());
-                to_ret
-            }
-            Self::LoadSym(id) => {
-                let mut to_ret = vec![1];
-                to_ret.extend(&id.to_be_bytes());
-                to_ret
-            }
-            Self::Call(argc) => {
-                let mut to_ret = vec![2];
-                to_ret.extend(&argc.to_be_bytes());
-                to_ret
-            }
-            Self::Builtin(idx, argc) => vec![3, *idx, *argc],
-            Self::Def(id, len) => {
-                let mut to_ret = vec![4];
-                to_ret.extend(&id.to_be_bytes());
-                to_ret.extend(&len.to_be_bytes());
-                to_ret
-            }
-            Self::Lambda(id) => {
-                let mut to_ret = vec![5];
-                to_ret.extend(&id.to_be_bytes());
-                to_ret
-            }
-            Self::Constructor(idx, contained) => {
-                let mut to_ret = vec![6];
-                to_ret.extend(&idx.to_be_bytes());
-                to_ret.extend(&contained.to_be_bytes());
-                to_ret
-            }
-            Self::Tuple(amount, vals) => {
-                let mut to_ret = vec![7];
-                to_ret.extend(&amount.to_be_bytes());
-                to_ret.extend(&vals.to_be_bytes());
-                to_ret
-            }
-            Self::Match(idx) => {
-                let mut to_ret = vec![8];
-                to_ret.extend(&idx.to_be_bytes());
-                to_ret
-            }
-            Self::Panic(file, line) => {
-                let mut to_ret = vec![9];
-                to_ret.extend(&file.to_be_bytes());
-                to_ret.extend(&line.to_be_bytes());
-                to_ret
-            }
-        }
+        let (code, operands) = match *self {
+            Self::LoadConst(id) => (0, vec![id]),
+            Self::LoadGlobal(id) => (1, vec![id]),
+            Self::Call(argc) => (2, vec![argc]),
+            Self::Builtin(idx, argc) => (3, vec![idx, argc]),
+            Self::DefGlobal(id) => (4, vec![id]),
+            Self::Lambda(id) => (5, vec![id]),
+            Self::Constructor(idx) => (6, vec![idx]),
+            Self::Tuple(amount) => (7, vec![amount]),
+            Self::Jump(offset) => (8, vec![offset]),
+            Self::JumpIfNoMatch(slot, pat, offset) => (9, vec![slot, pat, offset]),
+            Self::EndMatch(slots) => (10, vec![slots]),
+            Self::NoMatch => (11, vec![]),
+            Self::Panic(file, line) => (12, vec![file, line]),
+            Self::LoadLocal(slot) => (13, vec![slot]),
+            Self::LoadUpvalue(idx) => (14, vec![idx]),
+            Self::DefLocal(slot) => (15, vec![slot]),
+            Self::Switch(slot, first, amount) => (16, vec![slot, first, amount]),
+            Self::Unpack(slot, first) => (17, vec![slot, first]),
+            Self::LoadSibling(id) => (18, vec![id]),
+            Self::Position(_) => bug!("SERIALIZED_POSITION"),
+        };
+        let mut to_ret = vec![code];
+        operands.into_iter().for_each(|op| to_ret.extend(encode_uint(op)));
+        to_ret
     }
 }
 
-#[derive(PartialEq, Clone, Debug)]
+#[derive(PartialEq, Clone, Debug, Default)]
 pub struct Chunk {
     pub instructions: Vec<OpCode>,
-    pub reference: Vec<u16>,
+    pub reference: Vec<u32>,
+    pub captures: Vec<u32>, // Symbols taken from the frame creating the closure.
+    pub name: Option<u32>, // (sym_id) of the definition the function is the value of.
+    pub lines: Vec<Position>,
+}
+
+// The source position of the instructions from `ip` up to the next position of the line table.
+#[derive(PartialEq, Clone, Copy, Debug, Default)]
+pub struct Position {
+    pub ip: u32,
+    pub file: u32, // (const_id)
+    pub line: u32, // 0 when the instructions have no source.
+    pub column: u32,
+    pub start: u32,
+    pub end: u32,
+}
+impl Position {
+    // The position of the instruction at `ip` in a line table.
+    pub fn find(lines: &[Position], ip: usize) -> Option<Position> {
+        lines.iter().rev().find(|pos| pos.ip as usize <= ip).filter(|pos| pos.line > 0).copied()
+    }
+    fn serialize(&self) -> Vec<u8> {
+        [self.ip, self.file, self.line, self.column, self.start, self.end].iter().flat_map(|n| encode_uint(*n)).collect()
+    }
+    fn deserialize(ptr: &mut usize, bytes: &[u8]) -> Result<Self> {
+        Ok(Self {
+            ip: uint(ptr, bytes)?,
+            file: uint(ptr, bytes)?,
+            line: uint(ptr, bytes)?,
+            column: uint(ptr, bytes)?,
+            start: uint(ptr, bytes)?,
+            end: uint(ptr, bytes)?,
+        })
+    }
 }
 
 #[derive(PartialEq, Clone, Debug)]
 pub enum BytecodePattern {
-    Var(u16), // (sym_idx)
-    Constr(u16, Vec<u16>), // (constr_id, [pat_idx])
-    Tuple(Vec<u16>), // ([pat_idx])
-    Literal(u16), // (const_id)
+    Var(u32), // (sym_idx)
+    Constr(u32, Vec<u32>), // (constr_id, [pat_idx])
+    Tuple(Vec<u32>), // ([pat_idx])
+    Literal(u32), // (const_id)
     Any, // `_` variable 
 }
 
 #[derive(PartialEq, Clone, Debug)]
 pub struct Bytecode {
-    pub types: Vec<(String, u16, u16)>,
+    pub types: Vec<(String, u32, u32)>,
     pub chunks: Vec<Chunk>,
-    pub matches: Vec<Vec<(u16, Vec<OpCode>)>>,
     pub symbols: Vec<String>,
     pub constants: Vec<Literal>,
     pub instructions: Vec<OpCode>,
+    pub lines: Vec<Position>, // The line table of the main instructions.
     pub patterns: Vec<BytecodePattern>,
-    pub constructors: Vec<(u8, u16)>,
+    pub constructors: Vec<(u32, u32)>,
+}
+
+#[derive(PartialEq, Clone, Debug)]
+pub struct Header {
+    pub format: u16,
+    pub compiler: String,
+    pub source_hash: u64,
+    pub timestamp: u64,
+    pub length: u32,
+    pub checksum: u32,
+}
+
+impl Header {
+    // Magic value, format version, compiler version, source hash, timestamp, body length and body checksum.
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut to_ret = MAGIC.to_vec();
+        to_ret.extend(&self.format.to_be_bytes());
+        to_ret.extend(encode_string(&self.compiler));
+        to_ret.extend(&self.source_hash.to_be_bytes());
+        to_ret.extend(&self.timestamp.to_be_bytes());
+        to_ret.extend(&self.length.to_be_bytes());
+        to_ret.extend(&self.checksum.to_be_bytes());
+        to_ret
+    }
+    pub fn deserialize(bytes: &[u8]) -> Result<(Self, usize)> {
+        if !Bytecode::is_bytecode(bytes) {
+            return error!(E0009; => "Invalid bytecode: missing magic value.");
+        }
+        let mut ptr = MAGIC.len();
+        let format = len(&mut ptr, bytes)?;
+        // The rest of the header may be laid out differently in other versions.
+        if format != FORMAT_VERSION {
+            return error!(E0009; => "Unsupported bytecode format version {}, expected version {}.", format, FORMAT_VERSION);
+        }
+        let compiler = string(&mut ptr, bytes)?;
+        let source_hash = long(&mut ptr, bytes)?;
+        let timestamp = long(&mut ptr, bytes)?;
+        let length = int(&mut ptr, bytes)? as u32;
+        let checksum = int(&mut ptr, bytes)? as u32;
+        Ok((Self {
+            format,
+            compiler,
+            source_hash,
+            timestamp,
+            length,
+            checksum,
+        }, ptr))
+    }
 }
 
 impl Bytecode {
@@ -161,143 +244,243 @@ impl Bytecode {
             symbols: vec![],
             constants: vec![],
             instructions: vec![],
+            lines: vec![],
             constructors: vec![],
-            matches: vec![],
             patterns: vec![],
         }
     }
-    // All numbers here are big endian
+    pub fn is_bytecode(bytes: &[u8]) -> bool {
+        bytes.starts_with(MAGIC)
+    }
     pub fn deserialize(bytes: &[u8]) -> Result<Self> {
-        if &bytes[0..5] != "orion".chars().into_iter().map(|c| c as u8).collect::<Vec<u8>>() {
-            error!(=> "Invalid bytecode.")
-        } else {
-            let mut ptr = 5; // Skip timestamp
-            let sym_length = len(&mut ptr, bytes)?;
-            println!("Sym length.");
-            let mut symbols = (0..sym_length).map(|_| string(&mut ptr, bytes)).collect::<Result<Vec<String>>>()?;
-            println!("Syms.");
-            let consts_length = len(&mut ptr, bytes)?;
-            println!("Consts length.");
-            let constants = (0..consts_length).map(|_| {
-                ptr += 1;
-                if bytes[ptr - 1] == 0 {
-                    Ok(Literal::String(string(&mut ptr, bytes)?))
-                } else if bytes[ptr - 1] == 1 {
-                    Ok(Literal::Integer(int(&mut ptr, bytes)?))
-                } else if bytes[ptr - 1] == 2 {
-                    Ok(Literal::Single(single(&mut ptr, bytes)?))
-                } else {
-                    error!(=> "Invalid type identifier, expected 0, 1 or 2, found {}.", bytes[ptr - 1])
-                }
-            }).collect::<Result<Vec<Literal>>>()?;
-            println!("Consts.");
-            let contrs_length = len(&mut ptr, bytes)?;
-            println!("Constrs_length.");
-            let constructors = (0..contrs_length).map(|_| {
-                let argc = bytes[ptr];
-                ptr += 3;
-                let idx = (bytes[ptr - 2] as u16) << 8 | (bytes[ptr - 1] as u16);
-                (argc, idx)
-            }).collect::<Vec<(u8, u16)>>();
-            println!("Constrs.");
-
-            let chunks_length = len(&mut ptr, bytes)?;
-            println!("Chunks length.");
-            let chunks = (0..chunks_length).map(|_| {
-                let ref_len = len(&mut ptr, bytes)?;
-                let reference = (0..ref_len).map(|_| {
-                    len(&mut ptr, bytes)
-                }).collect::<Result<Vec<u16>>>()?;
-                let instr_len = len(&mut ptr, bytes)? as usize;
-                let instructions = (0..instr_len).map(|_| {
-                    OpCode::deserialize(&mut ptr, bytes)
-                }).collect::<Result<Vec<OpCode>>>()?;
-                Ok(Chunk {
-                    instructions,
-                    reference
-                })
-            }).collect::<Result<Vec<Chunk>>>()?;
-            println!("Chunks.");
-
-            let instrs_length = len(&mut ptr, bytes)?;
-            println!("Instrs length.");
-            let instructions = (0..instrs_length).map(|_| {
+        let (header, start) = Header::deserialize(bytes)?;
+        let body = &bytes[start..];
+        if body.len() != header.length as usize {
+            return error!(E0009; => "Corrupted bytecode: expected a {} bytes body, found {} bytes.", header.length, body.len());
+        }
+        let checksum = crc32(body);
+        if checksum != header.checksum {
+            return error!(E0009; => "Corrupted bytecode: checksum mismatch (expected 0x{:08x}, found 0x{:08x}).", header.checksum, checksum);
+        }
+        let bytecode = Self::deserialize_body(body)?;
+        bytecode.validate()?;
+        Ok(bytecode)
+    }
+    // All numbers here are big endian
+    fn deserialize_body(bytes: &[u8]) -> Result<Self> {
+        let mut ptr = 0;
+        let sym_length = uint(&mut ptr, bytes)?;
+        let symbols = (0..sym_length).map(|_| string(&mut ptr, bytes)).collect::<Result<Vec<String>>>()?;
+        let consts_length = uint(&mut ptr, bytes)?;
+        let constants = (0..consts_length).map(|_| {
+            match byte(&mut ptr, bytes)? {
+                0 => Ok(Literal::String(string(&mut ptr, bytes)?)),
+                1 => Ok(Literal::Integer(int(&mut ptr, bytes)?)),
+                2 => Ok(Literal::Single(single(&mut ptr, bytes)?)),
+                x => error!(E0009; => "Invalid type identifier, expected 0, 1 or 2, found {}.", x),
+            }
+        }).collect::<Result<Vec<Literal>>>()?;
+        let contrs_length = uint(&mut ptr, bytes)?;
+        let constructors = (0..contrs_length).map(|_| {
+            let argc = uint(&mut ptr, bytes)?;
+            let idx = uint(&mut ptr, bytes)?;
+            Ok((argc, idx))
+        }).collect::<Result<Vec<(u32, u32)>>>()?;
+
+        let chunks_length = uint(&mut ptr, bytes)?;
+        let chunks = (0..chunks_length).map(|_| {
+            let ref_len = uint(&mut ptr, bytes)?;
+            let reference = (0..ref_len).map(|_| {
+                uint(&mut ptr, bytes)
+            }).collect::<Result<Vec<u32>>>()?;
+            let captures_len = uint(&mut ptr, bytes)?;
+            let captures = (0..captures_len).map(|_| {
+                uint(&mut ptr, bytes)
+            }).collect::<Result<Vec<u32>>>()?;
+            // The name is shifted by one, 0 standing for anonymous functions.
+            let name = uint(&mut ptr, bytes)?.checked_sub(1);
+            let instr_len = uint(&mut ptr, bytes)? as usize;
+            let instructions = (0..instr_len).map(|_| {
                 OpCode::deserialize(&mut ptr, bytes)
             }).collect::<Result<Vec<OpCode>>>()?;
-            println!("Instrs.");
-
-            let types_length = len(&mut ptr, bytes)?;
-            println!("Types_length.");
-            let types = (0..types_length).map(|_| {
-                let start = len(&mut ptr, bytes)?;
-                let end = len(&mut ptr, bytes)?;
-                let t= string(&mut ptr, bytes)?;
-                Ok((t, start, end))
-            }).collect::<Result<Vec<_>>>()?;
-            println!("Types.");
-
-            let patterns_length = len(&mut ptr, bytes)?;
-            println!("Patterns_length.");
-            let patterns = (0..patterns_length).map(|_| {
-                ptr += 1;
-                match bytes[ptr - 1] {
-                    0 => Ok(BytecodePattern::Var(len(&mut ptr, bytes)?)),
-                    1 => {
-                        let id = len(&mut ptr, bytes)?;
-                        let length = len(&mut ptr, bytes)?;
-                        let pats = (0..length).map(|_| len(&mut ptr, bytes)).collect::<Result<Vec<u16>>>()?;
-                        Ok(BytecodePattern::Constr(id, pats))
+            let lines_len = uint(&mut ptr, bytes)?;
+            let lines = (0..lines_len).map(|_| {
+                Position::deserialize(&mut ptr, bytes)
+            }).collect::<Result<Vec<Position>>>()?;
+            Ok(Chunk {
+                instructions,
+                reference,
+                captures,
+                name,
+                lines,
+            })
+        }).collect::<Result<Vec<Chunk>>>()?;
+
+        let instrs_length = uint(&mut ptr, bytes)?;
+        let instructions = (0..instrs_length).map(|_| {
+            OpCode::deserialize(&mut ptr, bytes)
+        }).collect::<Result<Vec<OpCode>>>()?;
+        let lines_length = uint(&mut ptr, bytes)?;
+        let lines = (0..lines_length).map(|_| {
+            Position::deserialize(&mut ptr, bytes)
+        }).collect::<Result<Vec<Position>>>()?;
+
+        let types_length = uint(&mut ptr, bytes)?;
+        let types = (0..types_length).map(|_| {
+            let start = uint(&mut ptr, bytes)?;
+            let end = uint(&mut ptr, bytes)?;
+            let t= string(&mut ptr, bytes)?;
+            Ok((t, start, end))
+        }).collect::<Result<Vec<_>>>()?;
+
+        let patterns_length = uint(&mut ptr, bytes)?;
+        let patterns = (0..patterns_length).map(|_| {
+            match byte(&mut ptr, bytes)? {
+                0 => Ok(BytecodePattern::Var(uint(&mut ptr, bytes)?)),
+                1 => {
+                    let id = uint(&mut ptr, bytes)?;
+                    let length = uint(&mut ptr, bytes)?;
+                    let pats = (0..length).map(|_| uint(&mut ptr, bytes)).collect::<Result<Vec<u32>>>()?;
+                    Ok(BytecodePattern::Constr(id, pats))
+                }
+                2 => {
+                    let length = uint(&mut ptr, bytes)?;
+                    let pats = (0..length).map(|_| uint(&mut ptr, bytes)).collect::<Result<Vec<u32>>>()?;
+                    Ok(BytecodePattern::Tuple(pats))
+                }
+                3 => Ok(BytecodePattern::Literal(uint(&mut ptr, bytes)?)),
+                4 => Ok(BytecodePattern::Any),
+                _ => error!(E0009; => "Invalid pattern."),
+            }
+        }).collect::<Result<Vec<BytecodePattern>>>()?;
+        if ptr != bytes.len() {
+            return error!(E0009; => "Corrupted bytecode: {} trailing bytes.", bytes.len() - ptr);
+        }
+
+        Ok(Bytecode {
+            types,
+            chunks,
+            symbols,
+            constants,
+            instructions,
+            lines,
+            patterns,
+            constructors
+        })
+    }
+    pub fn serialize(&self, source_hash: u64) -> Vec<u8> {
+        let body = self.serialize_body();
+        let mut to_ret = Header {
+            format: FORMAT_VERSION,
+            compiler: env!("CARGO_PKG_VERSION").to_string(),
+            source_hash,
+            timestamp: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+            length: body.len() as u32,
+            checksum: crc32(&body),
+        }.serialize();
+        to_ret.extend(body);
+        to_ret
+    }
+    // Checks that every index used in the bytecode refers to an existing item.
+    pub fn validate(&self) -> Result<()> {
+        let check = |kind: &str, idx: usize, max: usize| if idx < max {
+            Ok(())
+        } else {
+            error!(E0009; => "Invalid bytecode: {} 0x{:04x} is out of range.", kind, idx)
+        };
+        let check_instrs = |instrs: &[OpCode], upvalues: usize| {
+            instrs.iter().enumerate().try_for_each(|(ip, instr)| match *instr {
+                OpCode::LoadConst(id) => check("constant", id as usize, self.constants.len()),
+                OpCode::LoadGlobal(id) | OpCode::DefGlobal(id) => check("symbol", id as usize, self.symbols.len()),
+                OpCode::LoadUpvalue(idx) => check("upvalue", idx as usize, upvalues),
+                // The slots in use depend on the bindings made at runtime, but are bounded.
+                OpCode::LoadLocal(slot) | OpCode::DefLocal(slot) => check("local slot", slot as usize, MAX_SLOTS as usize),
+                OpCode::Unpack(slot, first) => {
+                    check("local slot", slot as usize, MAX_SLOTS as usize)?;
+                    check("local slot", first as usize, MAX_SLOTS as usize + 1)
+                }
+                OpCode::EndMatch(slots) => check("local slot", slots as usize, MAX_SLOTS as usize + 1),
+                OpCode::Call(_) | OpCode::Builtin(..) | OpCode::Tuple(_) | OpCode::NoMatch => Ok(()),
+                OpCode::Lambda(id) => check("chunk", id as usize, self.chunks.len()),
+                OpCode::LoadSibling(id) => {
+                    check("chunk", id as usize, self.chunks.len())?;
+                    // The sibling is given the upvalues of the running closure.
+                    if self.chunks[id as usize].captures.len() > upvalues {
+                        error!(E0009; => "Invalid bytecode: chunk 0x{:04x} takes more upvalues than the closure it shares them with.", id)
+                    } else {
+                        Ok(())
                     }
-                    2 => {
-                        let length = len(&mut ptr, bytes)?;
-                        let pats = (0..length).map(|_| len(&mut ptr, bytes)).collect::<Result<Vec<u16>>>()?;
-                        Ok(BytecodePattern::Tuple(pats))
+                }
+                OpCode::Constructor(id) => check("constructor", id as usize, self.constructors.len()),
+                // Jumps may land right after the last instruction.
+                OpCode::Jump(offset) => check("instruction", ip + offset as usize, instrs.len()),
+                OpCode::Switch(slot, first, amount) => {
+                    check("local slot", slot as usize, MAX_SLOTS as usize)?;
+                    if amount > 0 {
+                        check("constructor", first as usize + amount as usize - 1, self.constructors.len())?;
                     }
-                    3 => Ok(BytecodePattern::Literal(len(&mut ptr, bytes)?)),
-                    4 => Ok(BytecodePattern::Any),
-                    _ => error!(=> "Invalid pattern."),
+                    check("instruction", ip + amount as usize + 1, instrs.len())
                 }
-            }).collect::<Result<Vec<BytecodePattern>>>()?;
-            println!("Patterns.");
-            let matches_length = len(&mut ptr, bytes)?;
-            println!("Matches length.");
-            let matches = (0..matches_length).map(|_| {
-                let match_length = len(&mut ptr, bytes)?;
-                Ok((0..match_length).map(|_| {
-                    let idx = len(&mut ptr, bytes)?;
-                    let instrs_len = len(&mut ptr, bytes)?;
-                    let instrs = (0..instrs_len).map(|_| {
-                        OpCode::deserialize(&mut ptr, bytes)
-                    }).collect::<Result<Vec<OpCode>>>()?;
-                    Ok((idx, instrs))
-                }).collect::<Result<Vec<(u16, Vec<OpCode>)>>>()?)
-            }).collect::<Result<Vec<Vec<(u16, Vec<OpCode>)>>>>()?;
-            println!("Matches.");
-
-            Ok(Bytecode {
-                types,
-                chunks,
-                matches,
-                symbols,
-                constants,
-                instructions,
-                patterns,
-                constructors
+                OpCode::JumpIfNoMatch(slot, pat, offset) => {
+                    check("local slot", slot as usize, MAX_SLOTS as usize)?;
+                    check("pattern", pat as usize, self.patterns.len())?;
+                    check("instruction", ip + offset as usize, instrs.len())
+                }
+                OpCode::Panic(file, line) => {
+                    check("constant", file as usize, self.constants.len())?;
+                    check("constant", line as usize, self.constants.len())
+                }
+                OpCode::Position(_) => error!(E0009; => "Invalid bytecode: position markers are not part of the bytecode."),
             })
-        }
+        };
+        // The positions are sorted, and may start right after the last instruction.
+        let check_lines = |lines: &[Position], length: usize| {
+            lines.iter().enumerate().try_for_each(|(idx, pos)| {
+                check("constant", pos.file as usize, self.constants.len())?;
+                match lines.get(idx + 1) {
+                    Some(next) if next.ip <= pos.ip => error!(E0009; => "Invalid bytecode: unsorted line table."),
+                    _ => check("instruction", pos.ip as usize, length + 1),
+                }
+            })
+        };
+        check_instrs(&self.instructions, 0)?;
+        check_lines(&self.lines, self.instructions.len())?;
+        self.chunks.iter().try_for_each(|chunk| {
+            chunk.reference.iter().chain(chunk.captures.iter()).chain(chunk.name.iter()).try_for_each(|id| check("symbol", *id as usize, self.symbols.len()))?;
+            check_instrs(&chunk.instructions, chunk.captures.len())?;
+            check_lines(&chunk.lines, chunk.instructions.len())
+        })?;
+        self.patterns.iter().try_for_each(|pat| match pat {
+            BytecodePattern::Var(id) => check("symbol", *id as usize, self.symbols.len()),
+            BytecodePattern::Constr(id, pats) => {
+                check("constructor", *id as usize, self.constructors.len())?;
+                pats.iter().try_for_each(|p| check("pattern", *p as usize, self.patterns.len()))
+            }
+            BytecodePattern::Tuple(pats) => pats.iter().try_for_each(|p| check("pattern", *p as usize, self.patterns.len())),
+            BytecodePattern::Literal(id) => check("constant", *id as usize, self.constants.len()),
+            BytecodePattern::Any => Ok(()),
+        })?;
+        self.constructors.iter().try_for_each(|(_, id)| check("symbol", *id as usize, self.symbols.len()))?;
+        self.types.iter().try_for_each(|(_, start, end)| {
+            check("constructor", *start as usize, *end as usize + 1)?;
+            check("constructor", *end as usize, self.constructors.len())
+        })?;
+        // The type of a value is found from its constructor.
+        (0..self.constructors.len() as u32).try_for_each(|idx| if self.types.iter().any(|(_, start, end)| (start..=end).contains(&&idx)) {
+            Ok(())
+        } else {
+            error!(E0009; => "Invalid bytecode: constructor 0x{:04x} has no type.", idx)
+        })
     }
-    pub fn serialize(&self) -> Vec<u8> {
-        let mut to_ret = "orion".chars().into_iter().map(|c| c as u8).collect::<Vec<u8>>(); // Magic value
+    fn serialize_body(&self) -> Vec<u8> {
+        let mut to_ret = vec![];
 
         // Symbols
-        to_ret.extend(&(self.symbols.len() as u16).to_be_bytes()); // Length
-        self.symbols.iter().for_each(|sym| {
-            sym.chars().for_each(|c| to_ret.push(c as u8));
-            to_ret.push(0); // Mark termination
-        });
+        to_ret.extend(encode_uint(self.symbols.len() as u32));
+        self.symbols.iter().for_each(|sym| to_ret.extend(encode_string(sym)));
 
         // Consts
-        to_ret.extend(&(self.constants.len() as u16).to_be_bytes()); // Length
+        to_ret.extend(encode_uint(self.constants.len() as u32));
         self.constants.iter().for_each(|c| {
             to_ret.push(match c {
                 Literal::String(_) => 0,
@@ -308,140 +491,391 @@ impl Bytecode {
             to_ret.extend(match c {
                 Literal::Integer(i) => i.to_be_bytes().to_vec(),
                 Literal::Single(f) => f.to_bits().to_be_bytes().to_vec(),
-                Literal::String(s) => {
-                    let mut to_ex = s.chars().map(|c| c as u8).collect::<Vec<_>>();
-                    to_ex.push(0); // Mark termination
-                    to_ex
-                }
+                Literal::String(s) => encode_string(s),
             })
         });
 
         // Constructors
-        to_ret.extend(&(self.constructors.len() as u16).to_be_bytes());
-        let (mut argc, mut idx) = (vec![], vec![]);
-        for (a, i) in &self.constructors {
-            argc.push(*a);
-            idx.push(*i);
-        }
-        to_ret.extend(argc);
-        to_ret.extend(idx.into_iter().map(|u| u.to_be_bytes().to_vec()).collect::<Vec<Vec<_>>>().into_iter().flatten());
+        to_ret.extend(encode_uint(self.constructors.len() as u32));
+        self.constructors.iter().for_each(|(argc, idx)| {
+            to_ret.extend(encode_uint(*argc));
+            to_ret.extend(encode_uint(*idx));
+        });
 
         // Chunks
-        to_ret.extend(&(self.chunks.len() as u16).to_be_bytes());
+        to_ret.extend(encode_uint(self.chunks.len() as u32));
         self.chunks.iter().for_each(|chunk| {
-            to_ret.extend(&(chunk.reference.len() as u16).to_be_bytes());
+            to_ret.extend(encode_uint(chunk.reference.len() as u32));
             chunk.reference.iter().for_each(|link| {
-                to_ret.extend(&link.to_be_bytes());
+                to_ret.extend(encode_uint(*link));
+            });
+            to_ret.extend(encode_uint(chunk.captures.len() as u32));
+            chunk.captures.iter().for_each(|sym| {
+                to_ret.extend(encode_uint(*sym));
             });
 
-            let serialized = chunk.instructions.iter().map(|instr| {
+            to_ret.extend(encode_uint(chunk.name.map_or(0, |sym| sym + 1)));
+
+            let serialized = chunk.instructions.iter().flat_map(|instr| {
                 instr.serialize()
-            }).flatten();
-            to_ret.extend(&(chunk.instructions.len() as u16).to_be_bytes());
-            to_ret.extend(serialized)
+            });
+            to_ret.extend(encode_uint(chunk.instructions.len() as u32));
+            to_ret.extend(serialized);
+            to_ret.extend(encode_uint(chunk.lines.len() as u32));
+            to_ret.extend(chunk.lines.iter().flat_map(|pos| pos.serialize()));
         });
 
         // Instructions
-        let serialized = self.instructions.iter().map(|instr| {
+        let serialized = self.instructions.iter().flat_map(|instr| {
             instr.serialize()
-        }).flatten();
-        to_ret.extend(&(self.instructions.len() as u16).to_be_bytes());
+        });
+        to_ret.extend(encode_uint(self.instructions.len() as u32));
         to_ret.extend(serialized);
+        to_ret.extend(encode_uint(self.lines.len() as u32));
+        to_ret.extend(self.lines.iter().flat_map(|pos| pos.serialize()));
 
         // Types
-        to_ret.extend(&(self.types.len() as u16).to_be_bytes());
+        to_ret.extend(encode_uint(self.types.len() as u32));
         self.types.iter().for_each(|(name, start, end)| {
-            to_ret.extend(&start.to_be_bytes());
-            to_ret.extend(&end.to_be_bytes());
-            to_ret.extend(name.chars().map(|c| c as u8));
-            to_ret.push(0);
+            to_ret.extend(encode_uint(*start));
+            to_ret.extend(encode_uint(*end));
+            to_ret.extend(encode_string(name));
         });
 
         // Patterns
-        to_ret.extend(&(self.patterns.len() as u16).to_be_bytes());
-        to_ret.extend(self.patterns.iter().map(|p| {
+        to_ret.extend(encode_uint(self.patterns.len() as u32));
+        to_ret.extend(self.patterns.iter().flat_map(|p| {
             match p {
                 BytecodePattern::Var(idx) => {
                     let mut to_ret = vec![0];
-                    to_ret.extend(&idx.to_be_bytes());
+                    to_ret.extend(encode_uint(*idx));
                     to_ret
                 }
                 BytecodePattern::Constr(id, pats) => {
                     let mut to_ret = vec![1];
-                    to_ret.extend(&id.to_be_bytes());
-                    to_ret.extend(&(pats.len() as u16).to_be_bytes());
-                    to_ret.extend(pats.into_iter().map(|p| {
-                        p.to_be_bytes().to_vec()
-                    }).flatten());
+                    to_ret.extend(encode_uint(*id));
+                    to_ret.extend(encode_uint(pats.len() as u32));
+                    to_ret.extend(pats.iter().flat_map(|p| encode_uint(*p)));
                     to_ret
                 }
                 BytecodePattern::Tuple(pats) =>  {
                     let mut to_ret = vec![2];
-                    to_ret.extend(&(pats.len() as u16).to_be_bytes());
-                    to_ret.extend(pats.into_iter().map(|p| {
-                        p.to_be_bytes().to_vec()
-                    }).flatten());
+                    to_ret.extend(encode_uint(pats.len() as u32));
+                    to_ret.extend(pats.iter().flat_map(|p| encode_uint(*p)));
                     to_ret
                 }
                 BytecodePattern::Literal(idx) => {
                     let mut to_ret = vec![3];
-                    to_ret.extend(&idx.to_be_bytes());
+                    to_ret.extend(encode_uint(*idx));
                     to_ret
                 }
                 BytecodePattern::Any => vec![4],
             }
-        }).flatten());
-
-        // Matches
-        to_ret.extend(&(self.matches.len() as u16).to_be_bytes());
-        to_ret.extend(self.matches.iter().map(|patterns| {
-            let mut to_ret = (patterns.len() as u16).to_be_bytes().to_vec();
-            to_ret.extend(patterns.into_iter().map(|(idx, instrs)| {
-                let mut to_ret = idx.to_be_bytes().to_vec();
-                to_ret.extend(&(instrs.len() as u16).to_be_bytes());
-                to_ret.extend(instrs.into_iter().map(|instr| instr.serialize()).flatten());
-                to_ret
-            }).flatten());
-            to_ret
-        }).flatten());
+        }));
+
         to_ret
     }
 }
 
+fn byte(ptr: &mut usize, bytes: &[u8]) -> Result<u8> {
+    match bytes.get(*ptr) {
+        Some(b) => {
+            *ptr += 1;
+            Ok(*b)
+        }
+        None => error!(E0009; => "Unexpected end of bytecode."),
+    }
+}
+// Unsigned integers are stored as LEB128, 7 bits per byte, the high bit marking continuation.
+fn encode_uint(mut n: u32) -> Vec<u8> {
+    let mut to_ret = vec![];
+    loop {
+        let byte = (n & 0x7f) as u8;
+        n >>= 7;
+        if n == 0 {
+            to_ret.push(byte);
+            return to_ret;
+        }
+        to_ret.push(byte | 0x80);
+    }
+}
+fn uint(ptr: &mut usize, bytes: &[u8]) -> Result<u32> {
+    let mut to_ret = 0u32;
+    for shift in (0..35).step_by(7) {
+        let b = byte(ptr, bytes)?;
+        if shift == 28 && b > 0x0f {
+            return error!(E0009; => "Unsigned integer overflows 32 bits.");
+        }
+        to_ret |= ((b & 0x7f) as u32) << shift;
+        if b & 0x80 == 0 {
+            return Ok(to_ret);
+        }
+    }
+    bug!("UNREACHABLE_LEB128_END")
+}
+// Strings are stored as their UTF-8 length followed by their UTF-8 bytes.
+fn encode_string(s: &str) -> Vec<u8> {
+    let mut to_ret = encode_uint(s.len() as u32);
+    to_ret.extend(s.as_bytes());
+    to_ret
+}
 fn string(ptr: &mut usize, bytes: &[u8]) -> Result<String> {
-    let mut to_ret = String::new();
-    while *ptr < bytes.len() && bytes[*ptr] != 0 {
-        to_ret.push(bytes[*ptr] as char);
-        *ptr += 1;
-    }
-    if bytes.iter().nth(*ptr) == Some(&0) {
-        *ptr += 1;
-        Ok(to_ret)
+    let length = uint(ptr, bytes)? as usize;
+    if *ptr + length <= bytes.len() {
+        *ptr += length;
+        match String::from_utf8(bytes[*ptr - length..*ptr].to_vec()) {
+            Ok(s) => Ok(s),
+            Err(e) => error!(E0009; => "Invalid UTF-8 string: {}.", e),
+        }
     } else {
-        error!(=> "Unterminated string.")
+        error!(E0009; => "Unterminated string.")
     }
 }
 fn single(ptr: &mut usize, bytes: &[u8]) -> Result<f32> {
-    if *ptr + 4 < bytes.len() {
+    if *ptr + 4 <= bytes.len() {
         *ptr += 4;
         Ok(f32::from_bits((bytes[*ptr - 4] as u32) << 24 | (bytes[*ptr - 3] as u32) << 16 | (bytes[*ptr - 2] as u32) << 8 | (bytes[*ptr - 1] as u32)))
     } else {
-        error!(=> "Unterminated single precision floating point number.")
+        error!(E0009; => "Unterminated single precision floating point number.")
     }
 }
 fn int(ptr: &mut usize, bytes: &[u8]) -> Result<i32> {
-    if *ptr + 4 < bytes.len() {
+    if *ptr + 4 <= bytes.len() {
         *ptr += 4;
         Ok(((bytes[*ptr - 4] as u32) << 24 | (bytes[*ptr - 3] as u32) << 16 | (bytes[*ptr - 2] as u32) << 8 | (bytes[*ptr - 1] as u32)) as i32)
     } else {
-        error!(=> "Unterminated 32 bits signed integer.")
+        error!(E0009; => "Unterminated 32 bits signed integer.")
+    }
+}
+fn long(ptr: &mut usize, bytes: &[u8]) -> Result<u64> {
+    if *ptr + 8 <= bytes.len() {
+        let mut buffer = [0; 8];
+        buffer.copy_from_slice(&bytes[*ptr..*ptr + 8]);
+        *ptr += 8;
+        Ok(u64::from_be_bytes(buffer))
+    } else {
+        error!(E0009; => "Unterminated 64 bits unsigned integer.")
     }
 }
 fn len(ptr: &mut usize, bytes: &[u8]) -> Result<u16> {
-    if *ptr + 2 < bytes.len() {
+    if *ptr + 2 <= bytes.len() {
         *ptr += 2;
         Ok((bytes[*ptr - 2] as u16) << 8 | (bytes[*ptr - 1] as u16))
     } else {
-        error!(=> "Unterminated 16 bits unsigned integer.")
+        error!(E0009; => "Unterminated 16 bits unsigned integer.")
+    }
+}
+
+// CRC-32 (IEEE 802.3) of the bytecode body.
+pub fn crc32(bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!0u32, |crc, b| {
+        (0..8).fold(crc ^ *b as u32, |crc, _| if crc & 1 == 1 {
+            (crc >> 1) ^ 0xEDB88320
+        } else {
+            crc >> 1
+        })
+    })
+}
+// FNV-1a hash of the source code the bytecode was compiled from.
+pub fn hash(source: &str) -> u64 {
+    source.bytes().fold(0xcbf29ce484222325, |hash, b| (hash ^ b as u64).wrapping_mul(0x100000001b3))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{compiler::Compiler, lexer::Lexer, modules::Modules, parser::Parser};
+    use quickcheck::{Arbitrary, Gen, quickcheck};
+
+    fn index(g: &mut Gen, length: usize) -> u32 {
+        (usize::arbitrary(g) % length) as u32
+    }
+
+    // Instructions whose operands are all in range, for `upvalues` captured values and the sizes of `bytecode`.
+    fn code(g: &mut Gen, bytecode: &Bytecode, captures: &[usize], upvalues: usize) -> Vec<OpCode> {
+        let length = u8::arbitrary(g) as usize;
+        let slot = |g: &mut Gen| u32::arbitrary(g) % MAX_SLOTS;
+        (0..length).map(|ip| {
+            let after = (length - ip) as u32; // The instruction pointer is past the jump when it is added.
+            let siblings = (0..captures.len()).filter(|id| captures[*id] <= upvalues).collect::<Vec<_>>();
+            match u8::arbitrary(g) % 19 {
+                0 => OpCode::LoadConst(index(g, bytecode.constants.len())),
+                1 => OpCode::LoadGlobal(index(g, bytecode.symbols.len())),
+                2 => OpCode::LoadLocal(slot(g)),
+                3 if upvalues > 0 => OpCode::LoadUpvalue(index(g, upvalues)),
+                4 if !siblings.is_empty() => OpCode::LoadSibling(siblings[index(g, siblings.len()) as usize] as u32),
+                5 => OpCode::Call(u32::arbitrary(g)),
+                6 => OpCode::Builtin(u32::arbitrary(g), u32::arbitrary(g)),
+                7 => OpCode::DefGlobal(index(g, bytecode.symbols.len())),
+                8 => OpCode::DefLocal(slot(g)),
+                9 if !captures.is_empty() => OpCode::Lambda(index(g, captures.len())),
+                10 => OpCode::Constructor(index(g, bytecode.constructors.len())),
+                11 => OpCode::Tuple(u32::arbitrary(g)),
+                12 => OpCode::Jump(index(g, after as usize)),
+                13 => OpCode::JumpIfNoMatch(slot(g), index(g, bytecode.patterns.len()), index(g, after as usize)),
+                14 if after > 1 => {
+                    let first = index(g, bytecode.constructors.len());
+                    let amount = index(g, (bytecode.constructors.len() as u32 - first).min(after - 2) as usize + 1);
+                    OpCode::Switch(slot(g), first, amount)
+                }
+                15 => OpCode::Unpack(slot(g), slot(g)),
+                16 => OpCode::EndMatch(slot(g)),
+                17 => OpCode::Panic(index(g, bytecode.constants.len()), index(g, bytecode.constants.len())),
+                _ => OpCode::NoMatch,
+            }
+        }).collect()
+    }
+
+    fn lines(g: &mut Gen, bytecode: &Bytecode, length: usize) -> Vec<Position> {
+        (0..=length as u32).filter_map(|ip| if u8::arbitrary(g) % 4 == 0 {
+            Some(Position {
+                ip,
+                file: index(g, bytecode.constants.len()),
+                line: u32::arbitrary(g),
+                column: u32::arbitrary(g),
+                start: u32::arbitrary(g),
+                end: u32::arbitrary(g),
+            })
+        } else {
+            None
+        }).collect()
+    }
+
+    impl Arbitrary for Bytecode {
+        fn arbitrary(g: &mut Gen) -> Self {
+            let mut symbols = Vec::<String>::arbitrary(g);
+            symbols.push(String::arbitrary(g));
+            // Every kind of literal is used at least once.
+            let constants = (0..u8::arbitrary(g) % 16 + 3).map(|i| match if i < 3 { i } else { u8::arbitrary(g) % 3 } {
+                0 => Literal::Integer(i32::arbitrary(g)),
+                1 => Literal::Single(Some(f32::arbitrary(g)).filter(|s| !s.is_nan()).unwrap_or(0.5)), // NaN != NaN
+                _ => Literal::String(String::arbitrary(g)),
+            }).collect::<Vec<_>>();
+            let constructors = (0..u8::arbitrary(g) % 8 + 1).map(|argc| (argc as u32, index(g, symbols.len()))).collect::<Vec<_>>();
+            let types = (0..constructors.len() as u32).map(|i| (String::arbitrary(g), i, i)).collect();
+            let mut bytecode = Self {
+                symbols,
+                constants,
+                constructors,
+                types,
+                ..Bytecode::new()
+            };
+            let amount = u8::arbitrary(g) % 8 + 1;
+            bytecode.patterns = (0..amount).map(|_| match u8::arbitrary(g) % 5 {
+                0 => BytecodePattern::Var(index(g, bytecode.symbols.len())),
+                1 => BytecodePattern::Constr(index(g, bytecode.constructors.len()), (0..u8::arbitrary(g) % 4).map(|_| index(g, amount as usize)).collect()),
+                2 => BytecodePattern::Tuple((0..u8::arbitrary(g) % 4).map(|_| index(g, amount as usize)).collect()),
+                3 => BytecodePattern::Literal(index(g, bytecode.constants.len())),
+                _ => BytecodePattern::Any,
+            }).collect();
+            let symbols = |g: &mut Gen, bytecode: &Bytecode| (0..u8::arbitrary(g) % 4).map(|_| index(g, bytecode.symbols.len())).collect::<Vec<_>>();
+            let captures = (0..u8::arbitrary(g) % 4).map(|_| symbols(g, &bytecode)).collect::<Vec<_>>();
+            let lengths = captures.iter().map(Vec::len).collect::<Vec<_>>();
+            bytecode.chunks = captures.into_iter().map(|captures| {
+                let instructions = code(g, &bytecode, &lengths, captures.len());
+                Chunk {
+                    lines: lines(g, &bytecode, instructions.len()),
+                    instructions,
+                    reference: symbols(g, &bytecode),
+                    name: Option::<()>::arbitrary(g).map(|_| index(g, bytecode.symbols.len())),
+                    captures,
+                }
+            }).collect();
+            bytecode.instructions = code(g, &bytecode, &lengths, 0);
+            bytecode.lines = lines(g, &bytecode, bytecode.instructions.len());
+            bytecode
+        }
+    }
+
+    quickcheck! {
+        fn lossless(bytecode: Bytecode) -> bool {
+            Bytecode::deserialize(&bytecode.serialize(0)).ok() == Some(bytecode)
+        }
+        fn uints(n: u32) -> bool {
+            let mut ptr = 0;
+            uint(&mut ptr, &encode_uint(n)).ok() == Some(n)
+        }
+        fn strings(s: String) -> bool {
+            let mut ptr = 0;
+            string(&mut ptr, &encode_string(&s)).ok() == Some(s)
+        }
+    }
+
+    #[test]
+    fn round_trip() -> Result<()> {
+        let tokens = Lexer::new("(enum Maybe (Just x) Nothing)
+        (def f (λ (x) (match x ((Just v) (, v 3.5 \"fôo\\0bar\")) (Nothing ()))))
+        (f (Just 42))", "TEST").proc_tokens()?;
+        let ast = Parser::new(tokens, "TEST").parse()?;
+        let (bytecode, ..) = Compiler::new(ast, "TEST", Bytecode::new(), vec![], true, "".to_string(), false, vec![], Modules::default())?.compile(vec![])?;
+        assert_eq!(Bytecode::deserialize(&bytecode.serialize(0))?, bytecode);
+        Ok(())
+    }
+
+    #[test]
+    fn large_operands() -> Result<()> {
+        let source = format!("(, {})", "1 ".repeat(70000));
+        let ast = Parser::new(Lexer::new(source, "TEST").proc_tokens()?, "TEST").parse()?;
+        let (bytecode, ..) = Compiler::new(ast, "TEST", Bytecode::new(), vec![], true, "".to_string(), false, vec![], Modules::default())?.compile(vec![])?;
+        assert_eq!(bytecode.instructions[70000], OpCode::Tuple(70000));
+        assert_eq!(Bytecode::deserialize(&bytecode.serialize(0))?, bytecode);
+        Ok(())
+    }
+
+    #[test]
+    fn header() -> Result<()> {
+        let bytes = Bytecode::new().serialize(hash("(def a 5)"));
+        let (header, _) = Header::deserialize(&bytes)?;
+        assert_eq!(header.format, FORMAT_VERSION);
+        assert_eq!(header.compiler, env!("CARGO_PKG_VERSION"));
+        assert_eq!(header.source_hash, hash("(def a 5)"));
+        // Only the magic value and the format version are read from another format.
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend(&(FORMAT_VERSION + 1).to_be_bytes());
+        assert_eq!(Header::deserialize(&bytes).unwrap_err().message, format!("Unsupported bytecode format version {}, expected version {}.", FORMAT_VERSION + 1, FORMAT_VERSION));
+        Ok(())
+    }
+
+    #[test]
+    fn corrupted() {
+        let mut bytes = Bytecode {
+            constants: vec![Literal::Integer(42)],
+            instructions: vec![OpCode::LoadConst(0)],
+            ..Bytecode::new()
+        }.serialize(0);
+        assert!(Bytecode::deserialize(b"orc").is_err());
+        assert!(Bytecode::deserialize(&bytes[..bytes.len() - 1]).is_err());
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        assert!(Bytecode::deserialize(&bytes).is_err());
+    }
+
+    #[test]
+    fn out_of_range() {
+        let bytes = Bytecode {
+            instructions: vec![OpCode::LoadConst(3)],
+            ..Bytecode::new()
+        }.serialize(0);
+        assert!(Bytecode::deserialize(&bytes).is_err());
+    }
+
+    #[test]
+    fn local_slots() {
+        let error = |instructions| Bytecode { instructions, ..Bytecode::new() }.validate().unwrap_err().message.clone();
+        assert_eq!(error(vec![OpCode::Tuple(0), OpCode::DefLocal(u32::MAX - 1)]), "Invalid bytecode: local slot 0xfffffffe is out of range.");
+        assert_eq!(error(vec![OpCode::Unpack(0, MAX_SLOTS + 1)]), format!("Invalid bytecode: local slot 0x{:04x} is out of range.", MAX_SLOTS + 1));
+        assert_eq!(error(vec![OpCode::EndMatch(u32::MAX)]), "Invalid bytecode: local slot 0xffffffff is out of range.");
+        assert!(Bytecode { instructions: vec![OpCode::Tuple(0), OpCode::DefLocal(MAX_SLOTS - 1)], ..Bytecode::new() }.validate().is_ok());
+    }
+
+    #[test]
+    fn typeless_constructor() {
+        let bytecode = Bytecode {
+            symbols: vec!["Just".to_string()],
+            constructors: vec![(1, 0)],
+            ..Bytecode::new()
+        };
+        assert_eq!(bytecode.validate().unwrap_err().message, "Invalid bytecode: constructor 0x0000 has no type.");
+        assert!(Bytecode { types: vec![("Maybe".to_string(), 0, 0)], ..bytecode }.validate().is_ok());
     }
 }
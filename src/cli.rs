@@ -18,12 +18,31 @@
  *  You should have received a copy of the GNU General Public License
  *  along with Orion.  If not, see <https://www.gnu.org/licenses/>.
  */
-use clap::{App, Arg};
+use clap::{App, Arg, ArgMatches, SubCommand};
 use rustyline::{error::ReadlineError, Editor};
 use std::{rc::Rc, time::Instant, path::Path, fs, io::Write};
-use crate::{Result, print_err, error, lexer::{Lexer, Token}, parser::{Parser, Expr}, bytecode::Bytecode, compiler::{Compiler, Macro}, vm::{VM, Value}};
+use crate::{Result, print_err, error, errors::ErrorFormat, lexer::Lexer, parser::{Expr, Parser}, printer::show, bytecode::{Bytecode, hash}, compiler::Compiler, modules::Modules, types::Checker, asm::Assembler, disasm::Disassembler, vm::{VM, Value, MAX_DEPTH}};
 
-fn repl(dbg_level: u8, lib: String) -> Result<()> {
+// Infers the type of the expression following `:type` in the REPL.
+fn type_of(checker: Option<&Checker>, input: &str, line: usize) -> Result<String> {
+    let checker = match checker {
+        Some(checker) => checker,
+        None => return error!(=> "The REPL has to be started with --typecheck to infer types."),
+    };
+    let tokens = Lexer::new(input, "REPL").line(line).proc_tokens()?;
+    match Parser::new(tokens, "REPL").parse()?.as_slice() {
+        [expr] => checker.type_of(expr),
+        exprs => error!(E0003; => "Expected 1 expression, found {}.", exprs.len()),
+    }
+}
+// Prints the expressions of `input` with their macro calls expanded by the compiler made by `compiler`,
+// or only their outermost call if `once`.
+fn expand(input: &str, file: &str, line: usize, once: bool, compiler: impl FnOnce(Vec<Expr>) -> Result<Compiler>) -> Result<String> {
+    let tokens = Lexer::new(input, file).line(line).proc_tokens()?;
+    let expressions = Parser::new(tokens, file).parse()?;
+    Ok(compiler(expressions)?.expand(once)?.iter().map(show).collect::<Vec<_>>().join("\n"))
+}
+fn repl(dbg_level: u8, max_depth: usize, lib: String, typecheck: bool, format: ErrorFormat) -> Result<()> {
     println!(
         ";; Orion REPL v{}.\n
 ;; Copyright (C) 2021  Wafelack <wafelack@protonmail.com>
@@ -32,14 +51,13 @@ fn repl(dbg_level: u8, lib: String) -> Result<()> {
 ;; under certain conditions.",
 env!("CARGO_PKG_VERSION")
 );
-    let mut ctx = vec![];
+    let mut globals = vec![];
     let mut symbols = vec![];
     let mut bytecode = Bytecode::new();
     let mut constructors = vec![];
-    let mut sym_ref = vec![];
-    let mut saves = vec![];
     let mut macros = vec![];
-    let mut vm = VM::new(Bytecode::new(), vec![]);
+    let mut modules = Modules::default();
+    let mut checker = if typecheck { Some(Checker::new("REPL", lib.clone(), true)) } else { None };
 
     let mut rl = Editor::<()>::new();
     let mut i = 0;
@@ -54,11 +72,34 @@ env!("CARGO_PKG_VERSION")
                 if line == "(quit)" {
                     return Ok(());
                 }
+                if let Some(input) = line.strip_prefix(":type") {
+                    match type_of(checker.as_ref(), input, i) {
+                        Ok(ty) => println!("{} :: {}", input.trim(), ty),
+                        Err(e) => print_err(&e, Some(("REPL", input)), format),
+                    }
+                    if i == 1 {
+                        i = 0;
+                    }
+                    continue;
+                }
+                let command = line.strip_prefix(":macroexpand-1").map(|input| (input, true))
+                    .or_else(|| line.strip_prefix(":macroexpand").map(|input| (input, false)));
+                if let Some((input, once)) = command {
+                    let compiler = |expressions| Compiler::new(expressions, "REPL", bytecode.clone(), constructors.clone(), i > 1, lib.clone(), true, macros.clone(), modules.clone());
+                    match expand(input, "REPL", i, once, compiler) {
+                        Ok(expanded) => println!("{}", expanded),
+                        Err(e) => print_err(&e, Some(("REPL", input)), format),
+                    }
+                    if i == 1 {
+                        i = 0;
+                    }
+                    continue;
+                }
                 let start = Instant::now();
-                let tokens = match Lexer::new(line, "REPL").line(i).proc_tokens() {
+                let tokens = match Lexer::new(&line, "REPL").line(i).proc_tokens() {
                     Ok(t) => t,
                     Err(e) => {
-                        print_err(e);
+                        print_err(&e, Some(("REPL", &line)), format);
                         continue;
                     }
                 };
@@ -66,52 +107,61 @@ env!("CARGO_PKG_VERSION")
                 let expressions = match Parser::new(tokens, "REPL").parse() {
                    Ok(e) => e,
                    Err(e) => {
-                       print_err(e);
+                       print_err(&e, Some(("REPL", &line)), format);
                        continue;
                    }
                 };
-                let (new_bytecode, new_syms, new_constructors, new_macros) = match (match Compiler::new(expressions, "REPL", bytecode.clone(), constructors.clone(),  i > 1, lib.clone(), true, macros.clone()) {
+                if let Some(Err(e)) = checker.as_mut().map(|checker| checker.check(expressions.clone())) {
+                    if i == 1 {
+                        i = 0;
+                    }
+                    print_err(&e, Some(("REPL", &line)), format);
+                    continue;
+                }
+                let mut compiler = match Compiler::new(expressions, "REPL", bytecode.clone(), constructors.clone(),  i > 1, lib.clone(), true, macros.clone(), modules.clone()) {
                     Ok(c) => c,
                     Err(e) => {
                         if i == 1 {
                             i = 0;
                         }
-                        print_err(e);
+                        print_err(&e, Some(("REPL", &line)), format);
                         continue;
                     }
-                }).compile(symbols.clone()) {
+                };
+                let (new_bytecode, new_syms, new_constructors, new_macros, new_modules) = match compiler.compile(symbols.clone()) {
                     Ok(b) => b,
                     Err(e) => {
                         if i == 1 {
                             i = 0;
                         } 
-                        print_err(e);
+                        print_err(&e, Some(("REPL", &line)), format);
                         continue;
                     }
                 };
+                compiler.warnings().iter().for_each(|w| print_err(w, Some(("REPL", &line)), format));
                 bytecode = new_bytecode;
                 symbols = new_syms;
                 constructors = new_constructors;
                 macros = new_macros;
+                modules = new_modules;
                 let elapsed = start.elapsed();
                 if dbg_level > 1 {
                     println!("{} Compiled in {}ms.", STAR, elapsed.as_millis());
                 }
-                vm = VM::<16000>::new(bytecode.clone(), saves.clone());
-                let (new_ctx, new_ref, new_saves) = match vm.eval(sym_ref.clone(), ctx.clone(), dbg_level > 2) {
+                let mut vm = VM::<16000>::new(bytecode.clone(), globals.clone()).depth(max_depth);
+                globals = match vm.eval(dbg_level > 2) {
                     Ok(v) => v,
                     Err(e) => {
-                        print_err(e);
+                        // The error may come from a function defined on a previous line.
+                        let source = if e.line == Some(i) { Some(("REPL", line.as_str())) } else { None };
+                        print_err(&e, source, format);
                         continue;
                     }
                 };
-                ctx = new_ctx;
-                sym_ref = new_ref;
-                saves = new_saves;
-                let top = &vm.stack.iter().nth(match vm.stack.len() as isize - 1 {
+                let top = &vm.stack.get(match vm.stack.len() as isize - 1 {
                     x if x < 0 => 0,
                     x => x as usize,
-                }).and_then(|v| Some((**v).clone()));
+                }).map(|v| (**v).clone());
                 if let Some(Value::Tuple(v)) = top {
                     if !v.is_empty() {
                         println!("=> {}", vm.display_value(Rc::new(top.clone().unwrap()), true))
@@ -154,7 +204,7 @@ There is NO WARRANTY, to the extent permitted by law.", $version).as_str())
                  .index(1)
                  .takes_value(true)
                  .value_name("FILE")
-                 .help("The source file to compile."))
+                 .help("The source file to compile, or the bytecode file to run."))
             .arg(Arg::with_name("lib")
                  .short("l")
                  .long("lib")
@@ -164,30 +214,133 @@ There is NO WARRANTY, to the extent permitted by law.", $version).as_str())
                  .short("c")
                  .long("compile-only")
                  .help("Compile, but do not run."))
+            .arg(Arg::with_name("expand")
+                 .short("E")
+                 .long("expand")
+                 .help("Print the source file with its macros expanded, instead of running it."))
             .arg(Arg::with_name("output")
                  .short("o")
                  .long("output")
                  .takes_value(true)
                  .value_name("FILE")
                  .help("Place the output into FILE."))
+            .arg(Arg::with_name("typecheck")
+                 .short("t")
+                 .long("typecheck")
+                 .help("Infer the types of the program and reject the mismatching ones before compiling it."))
             .arg(Arg::with_name("debug-level")
                  .short("d")
                  .long("debug")
                  .value_name("LEVEL")
                  .takes_value(true)
                  .help("Set the debug level. Defaults to 0."))
+            .arg(Arg::with_name("error-format")
+                 .long("error-format")
+                 .value_name("FORMAT")
+                 .takes_value(true)
+                 .possible_values(&["human", "json"])
+                 .help("Print the errors and warnings as text, or as one JSON object per line. Defaults to human."))
+            .arg(Arg::with_name("max-depth")
+                 .long("max-depth")
+                 .value_name("DEPTH")
+                 .takes_value(true)
+                 .help(&format!("Set the maximum amount of nested calls. Defaults to {}.", MAX_DEPTH)))
+            .subcommand(SubCommand::with_name("disasm")
+                        .about("Disassemble a source or bytecode file.")
+                        .help_message("Print help information.")
+                        .arg(Arg::with_name("file")
+                             .index(1)
+                             .required(true)
+                             .takes_value(true)
+                             .value_name("FILE")
+                             .help("The source or bytecode file to disassemble."))
+                        .arg(Arg::with_name("lib")
+                             .short("l")
+                             .long("lib")
+                             .takes_value(true)
+                             .help("The library folder to use instead of $ORION_LIB.")))
+            .subcommand(SubCommand::with_name("asm")
+                        .about("Assemble a textual bytecode file, as printed by `disasm`.")
+                        .help_message("Print help information.")
+                        .arg(Arg::with_name("file")
+                             .index(1)
+                             .required(true)
+                             .takes_value(true)
+                             .value_name("FILE")
+                             .help("The assembly file to assemble."))
+                        .arg(Arg::with_name("output")
+                             .short("o")
+                             .long("output")
+                             .takes_value(true)
+                             .value_name("FILE")
+                             .help("Place the output into FILE.")))
     }
 }
 use std::env;
-pub fn cli() -> Result<()> {
-    let matches = get_app!("Orion", env!("CARGO_PKG_VERSION")).get_matches();
-    let lib = match matches.value_of("lib") {
-        Some(l) => l.to_string(),
+fn get_lib(lib: Option<&str>) -> Result<String> {
+    match lib {
+        Some(l) => Ok(l.to_string()),
         None => match env::var("ORION_LIB") {
-            Ok(v) => v.to_string(),
-            Err(_) => return error!(=> "No such environment variable: ORION_LIB."),
+            Ok(v) => Ok(v),
+            Err(_) => error!(E0007; => "No such environment variable: ORION_LIB."),
         }
+    }
+}
+// Returns the source hash alongside the bytecode if it had to be compiled.
+fn load(file: &str, lib: Option<&str>, dbg_level: u8, typecheck: bool, format: ErrorFormat) -> Result<(Bytecode, Option<u64>)> {
+    let content = match fs::read(file) {
+        Ok(b) => b,
+        Err(e) => return error!(E0007; => "Failed to read file: {}: {}.", file, e)
+    };
+    if Bytecode::is_bytecode(&content) {
+        // Precompiled bytecode, nothing to compile.
+        return Ok((Bytecode::deserialize(&content)?, None));
+    }
+    let content = match String::from_utf8(content) {
+        Ok(s) => s,
+        Err(e) => return error!(E0007; => "Failed to read file: {}: {}.", file, e)
     };
+    let lib = get_lib(lib)?;
+    let source_hash = hash(&content);
+    let start = Instant::now();
+    let tokens = Lexer::new(content, file).proc_tokens()?;
+    let expressions = Parser::new(tokens, file).parse()?;
+    if typecheck {
+        Checker::new(file, lib.clone(), false).check(expressions.clone())?;
+    }
+    let mut compiler = Compiler::new(expressions, file, Bytecode::new(), vec![], false, lib, false, vec![], Modules::default())?;
+    let (bytecode, ..) = compiler.compile(vec![])?;
+    compiler.warnings().iter().for_each(|w| print_err(w, None, format));
+    let elapsed = start.elapsed();
+    if dbg_level > 0 {
+        println!("{} Compiled in {}ms.", STAR, elapsed.as_millis());
+    }
+    Ok((bytecode, Some(source_hash)))
+}
+fn write(bytecode: &Bytecode, source_hash: u64, file: &str, output: Option<&str>) -> Result<()> {
+    let output = match output {
+        Some(f) => f.to_string(),
+        None => format!("{}.orc", Path::new(file).file_stem().unwrap().to_str().unwrap()),
+    };
+    let to_write = bytecode.serialize(source_hash);
+    match (match fs::File::create(&output) {
+        Ok(f) => f,
+        Err(e) => return error!(E0007; => "Failed to create file: {}: {}.", output, e)
+    }).write_all(to_write.as_slice()) {
+        Ok(()) => Ok(()),
+        Err(e) => error!(E0007; => "Failed to write file: {}: {}.", output, e),
+    }
+}
+// Runs the command line, and returns how its errors have to be printed.
+pub fn cli() -> (ErrorFormat, Result<()>) {
+    let matches = get_app!("Orion", env!("CARGO_PKG_VERSION")).get_matches();
+    let format = match matches.value_of("error-format") {
+        Some("json") => ErrorFormat::Json,
+        _ => ErrorFormat::Human,
+    };
+    (format, run(&matches, format))
+}
+fn run(matches: &ArgMatches, format: ErrorFormat) -> Result<()> {
     let dbg_level = match matches.value_of("debug-level") {
         Some(lvl) => match lvl.parse::<u8>() {
             Ok(u) => if u > 3 {
@@ -199,36 +352,43 @@ pub fn cli() -> Result<()> {
         }
         None => 0,
     };
-    if let Some(file) = matches.value_of("file") {
-        let output = match matches.value_of("output") {
-            Some(f) => f.to_string(),
-            None => format!("{}.orc", Path::new(file).file_stem().unwrap().to_str().unwrap()),
+    let max_depth = match matches.value_of("max-depth") {
+        Some(depth) => match depth.parse::<usize>() {
+            Ok(u) => u,
+            Err(_) => return error!(=> "Invalid maximum depth: {}.", depth),
+        }
+        None => MAX_DEPTH,
+    };
+    if let Some(disasm) = matches.subcommand_matches("disasm") {
+        let file = disasm.value_of("file").unwrap();
+        let (bytecode, _) = load(file, disasm.value_of("lib").or_else(|| matches.value_of("lib")), dbg_level, false, format)?;
+        print!("{}", Disassembler::new(&bytecode).disassemble());
+    } else if let Some(asm) = matches.subcommand_matches("asm") {
+        let file = asm.value_of("file").unwrap();
+        let content = match fs::read_to_string(file) {
+            Ok(s) => s,
+            Err(e) => return error!(E0007; => "Failed to read file: {}: {}.", file, e)
         };
+        let source_hash = hash(&content);
+        let bytecode = Assembler::new(content, file).assemble()?;
+        write(&bytecode, source_hash, file, asm.value_of("output"))?;
+    } else if let (Some(file), true) = (matches.value_of("file"), matches.is_present("expand")) {
         let content = match fs::read_to_string(file) {
             Ok(s) => s,
-            Err(e) => return error!(=> "Failed to read file: {}: {}.", file, e)
+            Err(e) => return error!(E0007; => "Failed to read file: {}: {}.", file, e)
         };
-        let start = Instant::now();
-        let tokens = Lexer::new(content, file).proc_tokens()?;
-        let expressions = Parser::new(tokens, file).parse()?;
-        let (bytecode, ..) = Compiler::new(expressions, file, Bytecode::new(), vec![], false, lib, false, vec![])?.compile(vec![])?;
-        let elapsed = start.elapsed();
-        if dbg_level > 0 {
-            println!("{} Compiled in {}ms.", STAR, elapsed.as_millis());
+        let lib = get_lib(matches.value_of("lib"))?;
+        println!("{}", expand(&content, file, 1, false, |expressions| Compiler::new(expressions, file, Bytecode::new(), vec![], false, lib, false, vec![], Modules::default()))?);
+    } else if let Some(file) = matches.value_of("file") {
+        let (bytecode, source_hash) = load(file, matches.value_of("lib"), dbg_level, matches.is_present("typecheck"), format)?;
+        if let Some(source_hash) = source_hash {
+            write(&bytecode, source_hash, file, matches.value_of("output"))?;
         }
-        let to_write = bytecode.serialize();
-        match (match fs::File::create(&output) {
-            Ok(f) => f,
-            Err(e) => return error!(=> "Failed to create file: {}: {}.", output, e)
-        }).write_all(to_write.as_slice()) {
-            Ok(()) => {}
-            Err(e) => return error!(=> "Failed to write file: {}: {}.", output, e),
-        };
         if !matches.is_present("compile-only") {
-            VM::<16000>::new(bytecode, vec![]).eval(vec![], vec![], dbg_level > 2)?;
+            VM::<16000>::new(bytecode, vec![]).depth(max_depth).eval(dbg_level > 2)?;
         }
     } else {
-        repl(dbg_level, lib)?;
+        repl(dbg_level, max_depth, get_lib(matches.value_of("lib"))?, matches.is_present("typecheck"), format)?;
     }
     Ok(())
 }
@@ -19,118 +19,271 @@
  *  along with Orion.  If not, see <https://www.gnu.org/licenses/>.
  */
 use crate::{
-    bytecode::{BytecodePattern, Bytecode, Chunk, OpCode},
-    error,
-    lexer::Lexer,
-    parser::{Expr, ExprT, Literal, Parser, Pattern as ParserPattern},
-    Result,
+    bytecode::{Bytecode, Chunk, OpCode, Position, MAX_SLOTS},
+    disasm::literal,
+    error, bug,
+    errors::{closest, Diagnostic, Severity},
+    lexer::{Lexer, Span},
+    macros::Macro,
+    matching::{Builder, Enums, Pat, Slots},
+    modules::{Import, Modules},
+    parser::{Binding, Expr, ExprT, Literal, Parser, Pattern as ParserPattern},
+    OrionError, Result,
 };
 use std::{fs, path::Path};
-#[derive(Clone)]
-pub struct Macro {
-    pub args: Vec<String>,
-    pub content: Box<Expr>,
+type Compiled = (Vec<OpCode>, Vec<(String, bool)>);
+pub type CompileOutput = (Bytecode, Vec<(String, bool)>, Vec<String>, Vec<(String, Macro)>, Modules);
+
+// The builtins, in the same order as they are registered in the VM.
+pub const BUILTINS: [(&str, bool); 17] = [ // (name, impure?)
+    ("+", false),
+    ("-", false),
+    ("*", false),
+    ("/", false),
+    ("neg", false),
+    ("cos", false),
+    ("sin", false),
+    ("tan", false),
+    ("acos", false),
+    ("asin", false),
+    ("atan", false),
+    ("format", false),
+    ("get", false),
+    ("putStr", true),
+    ("getLine", true),
+    ("type", false),
+    ("_cmp", false),
+];
+
+const PURITY_NOTE: &str = "Impure functions and builtins may only be used by the definitions declared with `(def 'impure ...)`.";
+
+// The slots of a function being compiled, and the variables it takes from the enclosing functions.
+#[derive(Default)]
+struct Scope {
+    locals: Vec<Option<u32>>, // (sym_id) of each slot, the first one holding the closure itself.
+    captures: Vec<u32>, // (sym_id) of each upvalue.
+    siblings: Vec<(u32, u32)>, // (sym_id, chunk_id) of the functions of the same `letrec`, sharing the upvalues.
 }
 pub struct Compiler {
     input: Vec<Expr>,
     output: Bytecode,
-    load_history: Vec<String>,
+    modules: Modules,
     builtins: Vec<(String, bool)>, // (name, impure?)
     constructors: Vec<String>,
     file: String,
     lib: String,
     repl: bool,
     macros: Vec<(String, Macro)>,
+    scopes: Vec<Scope>, // The first scope is the main code, whose definitions are globals.
+    name: Option<u32>, // The local being defined by the next lambda.
+    warnings: Vec<OrionError>,
+    effects: usize, // The amount of impure functions and builtins used so far.
+    escapes: usize, // The amount of impure functions used as values so far, that an expression may evaluate to.
+    positions: Vec<Position>, // The positions the markers in the compiled code refer to.
+    gensyms: usize, // The amount of names made up by the macro expansions so far.
+    expanding: usize, // The amount of macro expansions the code being compiled comes from.
 }
 
 impl Compiler {
-    pub fn new(input: Vec<Expr>, file: impl ToString, mut bcode: Bytecode, constructors: Vec<String>, already_loaded: bool, lib: String, repl: bool, macros: Vec<(String, Macro)>) -> Result<Self> {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(input: Vec<Expr>, file: impl ToString, mut bcode: Bytecode, constructors: Vec<String>, already_loaded: bool, lib: String, repl: bool, macros: Vec<(String, Macro)>, modules: Modules) -> Result<Self> {
         bcode.instructions = vec![];
+        bcode.lines = vec![];
         let mut new_input = if already_loaded { vec![] } else { vec![Expr::new(ExprT::Load(vec!["prelude.orn".to_string()])).line(0)]};
         new_input.extend(input);
-        let mut to_ret = Self {
+        Ok(Self {
             input: new_input,
             constructors,
             lib,
             macros,
+            modules,
             repl,
             output: bcode,
-            load_history: vec![],
-            builtins: vec![],
+            scopes: vec![Scope::default()],
+            name: None,
+            warnings: vec![],
+            effects: 0,
+            escapes: 0,
+            positions: vec![],
+            gensyms: 0,
+            expanding: 0,
+            builtins: BUILTINS.iter().map(|(name, impure)| (name.to_string(), *impure)).collect(),
             file: file.to_string(),
-        };
-        to_ret.register_builtin("+", false);
-        to_ret.register_builtin("-", false);
-        to_ret.register_builtin("*", false);
-        to_ret.register_builtin("/", false);
-        to_ret.register_builtin("neg", false);
-        to_ret.register_builtin("cos", false);
-        to_ret.register_builtin("sin", false);
-        to_ret.register_builtin("tan", false);
-        to_ret.register_builtin("acos", false);
-        to_ret.register_builtin("asin", false);
-        to_ret.register_builtin("atan", false);
-
-        to_ret.register_builtin("format", false);
-        to_ret.register_builtin("get", false);
-
-        to_ret.register_builtin("putStr", true);
-        to_ret.register_builtin("getLine", true);
-
-        to_ret.register_builtin("type", false);
-        to_ret.register_builtin("_cmp", false);
-
-        Ok(to_ret)
+        })
+    }
+    fn index(&self, value: usize, what: &str, line: usize) -> Result<u32> {
+        if value > u32::MAX as usize {
+            error!(E0010; self.file, line => "Too much {} are used, the limit is {}.", what, u32::MAX)
+        } else {
+            Ok(value as u32)
+        }
+    }
+    fn slots(&self, value: usize, line: usize) -> Result<u32> {
+        if value > MAX_SLOTS as usize {
+            error!(E0010; self.file, line => "Too much local variables are used, the limit is {}.", MAX_SLOTS)
+        } else {
+            Ok(value as u32)
+        }
+    }
+    pub fn warnings(&self) -> &[OrionError] {
+        &self.warnings
+    }
+    fn scope(&mut self) -> &mut Scope {
+        self.scopes.last_mut().unwrap_or_else(|| bug!("COMPILER_NO_SCOPE"))
     }
-    fn register_builtin(&mut self, name: impl ToString, impure: bool) {
-        self.builtins.push((name.to_string(), impure))
+    // Finds the slot or upvalue holding a variable, making the enclosing functions capture it if needed.
+    fn resolve(&mut self, depth: usize, sym: u32) -> Option<OpCode> {
+        let scope = &self.scopes[depth];
+        if let Some(slot) = scope.locals.iter().rposition(|local| *local == Some(sym)) {
+            return Some(OpCode::LoadLocal(slot as u32));
+        } else if let Some((_, chunk)) = scope.siblings.iter().find(|(id, _)| *id == sym) {
+            return Some(OpCode::LoadSibling(*chunk));
+        } else if depth == 0 {
+            return None;
+        } else if let Some(idx) = scope.captures.iter().position(|id| *id == sym) {
+            return Some(OpCode::LoadUpvalue(idx as u32));
+        }
+        self.resolve(depth - 1, sym)?;
+        let scope = &mut self.scopes[depth];
+        scope.captures.push(sym);
+        Some(OpCode::LoadUpvalue(scope.captures.len() as u32 - 1))
+    }
+    // Whether a variable is bound by a function or a local binding, rather than by a definition of a file.
+    fn is_local(&self, name: &str, symbols: &[(String, bool)]) -> bool {
+        symbols.iter().position(|(sym, _)| sym == name).is_some_and(|sym| {
+            let sym = sym as u32;
+            self.scopes.iter().any(|scope| scope.locals.contains(&Some(sym)) || scope.siblings.iter().any(|(id, _)| *id == sym))
+        })
+    }
+    // The path of a loaded file, looked for in the library first.
+    fn locate(&self, file: String, span: Span) -> Result<String> {
+        let lib_path = format!("{}/{}", self.lib, file);
+        if Path::new(&lib_path).exists() {
+            Ok(lib_path)
+        } else if Path::new(&file).exists() {
+            Ok(file)
+        } else {
+            error!(E0007; self.file, span => "File not found: {}.", file)
+        }
     }
-    fn register_constant(&mut self, constant: Literal, line: usize) -> Result<u16> {
+    fn load(&mut self, sym: u32) -> OpCode {
+        self.resolve(self.scopes.len() - 1, sym).unwrap_or(OpCode::LoadGlobal(sym))
+    }
+    // Loads a variable, globals being marked as they may not be bound yet.
+    fn variable(&mut self, sym: u32, span: Span) -> Result<Vec<OpCode>> {
+        match self.load(sym) {
+            OpCode::LoadGlobal(sym) => Ok(vec![self.mark(span)?, OpCode::LoadGlobal(sym)]),
+            op => Ok(vec![op]),
+        }
+    }
+    // Marks the following instructions as coming from `span`, for runtime errors to be located.
+    fn mark(&mut self, span: Span) -> Result<OpCode> {
+        let file = self.register_constant(Literal::String(self.file.clone()), span.line)?;
+        let idx = self.index(self.positions.len(), "positions", span.line)?;
+        let number = |n: usize| n.min(u32::MAX as usize) as u32;
+        self.positions.push(Position {
+            ip: 0,
+            file,
+            line: number(span.line),
+            column: number(span.column),
+            start: number(span.start),
+            end: number(span.end),
+        });
+        Ok(OpCode::Position(idx))
+    }
+    // Removes the position markers from compiled code, turning them into its line table.
+    fn strip(&self, code: Vec<OpCode>) -> (Vec<OpCode>, Vec<Position>) {
+        // The index of each instruction once the markers are removed.
+        let indices = std::iter::once(0).chain(code.iter().scan(0, |count, op| {
+            if !matches!(op, OpCode::Position(_)) {
+                *count += 1;
+            }
+            Some(*count)
+        })).collect::<Vec<u32>>();
+        let offset = |ip: usize, offset: u32| indices[ip + 1 + offset as usize] - indices[ip] - 1;
+        let mut instructions = Vec::with_capacity(code.len());
+        let mut lines: Vec<Position> = vec![];
+        code.into_iter().enumerate().for_each(|(ip, op)| match op {
+            OpCode::Position(idx) => {
+                let pos = Position { ip: indices[ip], ..self.positions[idx as usize] };
+                // The marker closest to an instruction wins.
+                if lines.last().is_some_and(|last| last.ip == pos.ip) {
+                    lines.pop();
+                }
+                if lines.last().is_none_or(|last| Position { ip: pos.ip, ..*last } != pos) {
+                    lines.push(pos);
+                }
+            }
+            OpCode::Jump(o) => instructions.push(OpCode::Jump(offset(ip, o))),
+            OpCode::JumpIfNoMatch(slot, pat, o) => instructions.push(OpCode::JumpIfNoMatch(slot, pat, offset(ip, o))),
+            op => instructions.push(op),
+        });
+        (instructions, lines)
+    }
+    // Pushes the upvalues of a chunk before creating its closure.
+    fn closure(&mut self, chunk: Chunk, line: usize) -> Result<Vec<OpCode>> {
+        self.output.chunks.push(Chunk::default());
+        self.closure_at(self.output.chunks.len() - 1, chunk, line)
+    }
+    // Same as `closure`, the chunk going to the index reserved for it.
+    fn closure_at(&mut self, idx: usize, mut chunk: Chunk, line: usize) -> Result<Vec<OpCode>> {
+        let mut to_ret = chunk.captures.iter().map(|sym| self.load(*sym)).collect::<Vec<_>>();
+        let (instructions, lines) = self.strip(chunk.instructions);
+        chunk.instructions = instructions;
+        chunk.lines = lines;
+        self.output.chunks[idx] = chunk;
+        to_ret.push(OpCode::Lambda(self.index(idx, "chunks", line)?));
+        Ok(to_ret)
+    }
+    fn register_constant(&mut self, constant: Literal, line: usize) -> Result<u32> {
         if !self.output.constants.contains(&constant) {
             self.output.constants.push(constant.clone());
         }
-        if self.output.constants.len() > u16::MAX as usize {
-            error!(self.file, line => "Too much constants are used.")
+        if self.output.constants.len() > u32::MAX as usize {
+            error!(E0010; self.file, line => "Too much constants are used.")
         } else {
             Ok(self
                .output
                .constants
                .iter()
                .position(|c| c == &constant)
-               .unwrap() as u16)
+               .unwrap() as u32)
         }
     }
-    fn register_constructor(&mut self, name: impl ToString, symbols: Vec<(String, bool)>, contained_amount: u8, line: usize) -> Result<Vec<(String, bool)>> {
+    fn register_constructor(&mut self, name: impl ToString, symbols: Vec<(String, bool)>, contained_amount: usize, span: Span) -> Result<Vec<(String, bool)>> {
+        let line = span.line;
         let name = name.to_string();
         if self.constructors.contains(&name) {
-            error!(
+            error!(E0006;
                 self.file,
-                line =>
+                span =>
                 "Enum Variant {} has already been defined (Index 0x{:04x})",
                 &name,
                 self.constructors
                 .iter()
-                .position(|var| var.to_string() == name)
+                .position(|var| *var == name)
                 .unwrap()
                 )
         } else {
             self.constructors.push(name.clone());
             let (idx, symbols) = self.declare(name, symbols, false, line)?;
+            let contained_amount = self.index(contained_amount, "constructor values", line)?;
+            self.index(self.output.constructors.len(), "constructors", line)?;
             self.output.constructors.push((contained_amount, idx));
             Ok(symbols)
         }
     }
-    fn get_constructor(&self, name: impl ToString, line: usize) -> Result<(u8, u16)> {
+    fn get_constructor(&self, name: impl ToString, span: Span) -> Result<(u32, u32)> {
         let name = name.to_string();
         if self.constructors.contains(&name) {
             let idx = self
                 .constructors
                 .iter()
-                .position(|variant| name == variant.to_string())
+                .position(|variant| name == *variant)
                 .unwrap();
-            Ok((self.output.constructors[idx].0, idx as u16))
+            Ok((self.output.constructors[idx].0, idx as u32))
         } else {
-            error!(self.file, line => "Enum variant {} does not exist.", name)
+            error!(E0002; self.file, span => "Enum variant {} does not exist.", name)
         }
     }
     fn declare(
@@ -139,9 +292,9 @@ impl Compiler {
         mut symbols: Vec<(String, bool)>,
         impure: bool,
         line: usize,
-        ) -> Result<(u16, Vec<(String, bool)>)> {
-        if symbols.len() >= u16::MAX as usize {
-            error!(self.file, line => "Too much symbols are declared.")
+        ) -> Result<(u32, Vec<(String, bool)>)> {
+        if symbols.len() >= u32::MAX as usize {
+            error!(E0010; self.file, line => "Too much symbols are declared.")
         } else {
             Ok((
                     if symbols.contains(&(name.to_string(), impure))
@@ -154,7 +307,7 @@ impl Compiler {
                     } else {
                         symbols.push((name.to_string(), impure));
                         symbols.len() - 1
-                    } as u16,
+                    } as u32,
                     symbols,
                     ))
         }
@@ -162,41 +315,148 @@ impl Compiler {
     fn load_file(
         &mut self,
         fname: impl ToString,
-        mut symbols: Vec<(String, bool)>,
-        line: usize,
-        ) -> Result<(Vec<OpCode>, Vec<(String, bool)>)> {
+        symbols: Vec<(String, bool)>,
+        span: Span,
+        ) -> Result<Compiled> {
         let fname = fname.to_string();
-        if self.load_history.contains(&fname) {
+        if self.modules.is_loaded(&fname) {
             // Avoid error-prone reloading if file has already been loaded.
             Ok((vec![], symbols))
         } else {
-            self.load_history.push(fname.clone());
             match fs::read_to_string(&fname) {
                 Ok(content) => {
                     let tokens = Lexer::new(content, &fname).proc_tokens()?;
                     let expressions = Parser::new(tokens, &fname).parse()?;
-                    let saved = self.file.clone();
-                    self.file = fname;
-                    let to_ret = Ok((
-                            expressions
-                            .into_iter()
-                            .map(|e| {
-                                let to_ret = self.compile_expr(e, symbols.clone(), true)?;
-                                symbols = to_ret.1; // Update symbols.
-                                Ok(to_ret.0)
-                            })
-                            .collect::<Result<Vec<Vec<OpCode>>>>()?
-                            .into_iter()
-                            .flatten()
-                            .collect(),
-                            symbols,
-                            ));
-                    self.file = saved;
-                    to_ret
+                    let saved = (self.file.clone(), self.modules.enter(&fname));
+                    self.file = fname.clone();
+                    let to_ret = self.top_level(expressions, symbols, true);
+                    self.file = saved.0;
+                    let left = self.modules.leave(saved.1, &fname);
+                    let to_ret = to_ret?;
+                    left.map(|_| to_ret)
+                }
+                Err(e) => error!(E0007; self.file, span => "Failed to read file: {}: {}.", fname, e),
+            }
+        }
+    }
+    // Compiles the expressions of a file, going on after the failing ones to report all their errors.
+    fn top_level(&mut self, expressions: Vec<Expr>, mut symbols: Vec<(String, bool)>, impure: bool) -> Result<Compiled> {
+        let mut instructions = vec![];
+        let mut errors = vec![];
+        for expr in expressions {
+            let (scopes, file) = (self.scopes.len(), self.file.clone());
+            let defined = match &expr.exprt {
+                ExprT::Def(name, _, purity, _) => Some((name.clone(), *purity, expr.line)),
+                _ => None,
+            };
+            match self.compile_expr(expr, symbols.clone(), impure) {
+                Ok((code, new_symbols)) => {
+                    instructions.extend(code);
+                    symbols = new_symbols;
+                }
+                Err(e) => {
+                    // The following expressions may still use the failed definition.
+                    if let Some((name, purity, line)) = defined {
+                        let name = self.modules.resolve(&name, &self.file, Span::default())?;
+                        symbols = self.declare(name, symbols, purity, line)?.1;
+                    }
+                    self.scopes.truncate(scopes);
+                    self.file = file;
+                    self.name = None;
+                    errors.push(e);
                 }
-                Err(e) => error!(self.file, line => "Failed to read file: {}: {}.", fname, e),
             }
         }
+        match OrionError::merge(errors) {
+            Some(e) => Err(e),
+            None => Ok((instructions, symbols)),
+        }
+    }
+    // Compiles the body of a function in `scope`, after its arguments.
+    fn function(
+        &mut self,
+        args: Vec<String>,
+        body: Expr,
+        mut symbols: Vec<(String, bool)>,
+        impure: bool,
+        mut scope: Scope,
+        line: usize,
+        ) -> Result<(Chunk, Vec<(String, bool)>)> {
+        self.slots(args.len() + 1, line)?; // The closure comes before its arguments.
+        let args_reference = args
+            .iter()
+            .map(|a| {
+                let (idx, syms) = self.declare(a, symbols.clone(), false, line)?;
+                symbols = syms;
+                Ok(idx)
+            })
+        .collect::<Result<Vec<_>>>()?; // Position in the symbol table for each arg.
+        let run_with = symbols
+            .iter()
+            .enumerate()
+            .map(|(idx, sym)| {
+                match args_reference.iter().position(|id| *id as usize == idx) {
+                    // Check if the current symbol is part of the arguments
+                    // If it is, then replace it by the argument's value, else
+                    // use the value that is already in the table.
+                    Some(arg_i) => (args[arg_i].to_string(), false),
+                    None => sym.clone(),
+                }
+            })
+        .collect::<Vec<(String, bool)>>();
+        scope.locals.extend(args_reference.iter().map(|id| Some(*id)));
+        self.scopes.push(scope);
+        let (instructions, symbols) = self.compile_expr(body, run_with, impure)?;
+        let scope = self.scopes.pop().unwrap_or_else(|| bug!("COMPILER_NO_SCOPE"));
+        Ok((Chunk {
+            instructions,
+            reference: args_reference,
+            captures: scope.captures,
+            name: None,
+            lines: vec![],
+        }, symbols))
+    }
+    // Compiles the lambdas of a `letrec` into the slots from `start`. Their closures share the same
+    // upvalues, so that each of them can make the closure of the others without capturing them.
+    fn group(
+        &mut self,
+        ids: &[u32],
+        bindings: Vec<(String, Expr)>,
+        mut symbols: Vec<(String, bool)>,
+        impure: bool,
+        start: usize,
+        line: usize,
+        ) -> Result<Compiled> {
+        let first = self.output.chunks.len();
+        self.index(first + ids.len(), "chunks", line)?;
+        self.output.chunks.resize(first + ids.len(), Chunk::default());
+        let siblings = ids.iter().enumerate().map(|(i, idx)| (*idx, (first + i) as u32)).collect::<Vec<_>>();
+        let mut chunks = vec![];
+        let mut captures = vec![];
+        for ((_, value), idx) in bindings.into_iter().zip(ids) {
+            let (args, body) = match value.exprt {
+                ExprT::Lambda(args, body) => (args, body),
+                _ => return error!(E0001; self.file, value.span => "Expected a lambda, as the values of `letrec` are functions."),
+            };
+            // The upvalues of the previous functions keep their index.
+            let scope = Scope {
+                locals: vec![Some(*idx)],
+                captures,
+                siblings: siblings.clone(),
+            };
+            let (chunk, syms) = self.function(args, *body, symbols, impure, scope, value.line)?;
+            symbols = syms;
+            captures = chunk.captures.clone();
+            chunks.push(chunk);
+        }
+        let mut to_ret = vec![];
+        for (i, mut chunk) in chunks.into_iter().enumerate() {
+            chunk.captures = captures.clone();
+            chunk.name = Some(ids[i]);
+            to_ret.extend(self.closure_at(first + i, chunk, line)?);
+            to_ret.push(OpCode::DefLocal((start + i) as u32));
+        }
+        Ok((to_ret, symbols))
     }
     fn r#macro(
         &mut self,
@@ -204,31 +464,27 @@ impl Compiler {
         args: Vec<Expr>,
         symbols: Vec<(String, bool)>,
         impure: bool,
-        line: usize,
-        ) -> Result<(Vec<OpCode>, Vec<(String, bool)>)> {
-        let content = self.macros[idx].1.clone();
-        if content.args.len() != args.len() {
-            error!(self.file, line => "Expected {} arguments, found {}.", content.args.len(), args.len())
-        } else {
-            let mut expr = *content.content;
-            for (idx, arg) in args.into_iter().enumerate() {
-                expr = expr.replace(content.args[idx].clone(), arg);
-            }
-            self.compile_expr(expr, symbols, impure)
-        }
+        span: Span,
+        ) -> Result<Compiled> {
+        let expr = self.macros[idx].1.expand(args, span, &self.file, &mut self.gensyms, self.expanding)?;
+        self.expanding += 1;
+        let compiled = self.compile_expr(expr, symbols, impure);
+        self.expanding -= 1;
+        compiled
     }
     fn compile_expr(
         &mut self,
         expr: Expr,
         mut symbols: Vec<(String, bool)>,
         impure: bool,
-        ) -> Result<(Vec<OpCode>, Vec<(String, bool)>)> {
+        ) -> Result<Compiled> {
         match expr.exprt.clone() {
             ExprT::Literal(lit) => Ok((
                     vec![(OpCode::LoadConst(self.register_constant(lit, expr.line)?))],
                     symbols,
                     )),
             ExprT::Var(name) => {
+                let name = if self.is_local(&name, &symbols) { name } else { self.modules.resolve(&name, &self.file, expr.span)? };
                 if name.as_str() == "__LINE__" {
                     self.compile_expr(Expr::new(ExprT::Literal(Literal::Integer(expr.line as i32))).line(expr.line), symbols, impure)
                 } else if name.as_str() == "__FILE__" {
@@ -236,38 +492,37 @@ impl Compiler {
                 } else if !symbols.contains(&(name.clone(), impure)) {
                     if impure && symbols.contains(&(name.clone(), false)) {
                         let (idx, symbols) = self.declare(name, symbols, impure, expr.line)?;
-                        Ok((vec![OpCode::LoadSym(idx)], symbols))
+                        Ok((self.variable(idx, expr.span)?, symbols))
                     } else if !impure && symbols.contains(&(name.clone(), true)) {
-                        error!(
+                        error!(E0005;
                             self.file,
-                            expr.line =>
+                            expr.span =>
                             "Impure function used out of an `impure` declaration: {}",
                             name
-                            )
+                            ).map_err(|e| e.note(PURITY_NOTE))
                     } else {
-                        error!(self.file, expr.line => "Variable not in scope: {}.", name)
+                        let similar = closest(&name, symbols.iter().map(|(sym, _)| sym.as_str()));
+                        error!(E0002; self.file, expr.span => "Variable not in scope: {}.", name).map_err(|e| match similar {
+                            Some(similar) => e.suggest("A variable with a similar name exists", Some((expr.span, similar))),
+                            None => e,
+                        })
                     }
                 } else {
+                    if impure {
+                        self.effects += 1;
+                        self.escapes += 1;
+                    }
                     let (idx, symbols) = self.declare(name, symbols, impure, expr.line)?;
-                    Ok((vec![OpCode::LoadSym(idx)], symbols))
+                    Ok((self.variable(idx, expr.span)?, symbols))
                 }
             }
             ExprT::Load(files) => {
-                let lib_link = self.lib.to_string();
-
                 let instrs = files
                         .into_iter()
                         .map(|file| {
-                            let lib_path = format!("{}/{}", lib_link, file);
-                            let fname = if Path::new(&lib_path).exists() {
-                                Ok(lib_path)
-                            } else if Path::new(&file).exists() {
-                                Ok(file)
-                            } else {
-                                error!(self.file, expr.line => "File not found: {}.", file)
-                            }?;
-
-                            let to_ret = self.load_file(fname, symbols.clone(), expr.line)?;
+                            let fname = self.locate(file, expr.span)?;
+                            let to_ret = self.load_file(&fname, symbols.clone(), expr.span)?;
+                            self.modules.import(&fname, Import::All, &self.file, expr.span)?;
                             symbols = to_ret.1; // Update symbols.
                             Ok(to_ret.0)
                         })
@@ -275,33 +530,88 @@ impl Compiler {
                         .into_iter()
                         .flatten()
                         .collect::<Vec<OpCode>>();
+                if expr.line == 0 {
+                    // The implicit prelude.
+                    self.modules.prelude();
+                }
 
                 Ok((
                         instrs,
                         symbols,
                         ))
             }
-            ExprT::Def(name, value, purity) => {
+            ExprT::Import(file, alias, names) => {
+                let fname = self.locate(file, expr.span)?;
+                let to_ret = self.load_file(&fname, symbols, expr.span)?;
+                self.modules.import(&fname, Import::Module(alias.as_deref(), &names), &self.file, expr.span)?;
+                Ok(to_ret)
+            }
+            ExprT::Module(name, exports) => {
+                self.modules.declare(&name, &exports, &self.file, expr.span)?;
+                Ok((vec![], symbols))
+            }
+            ExprT::Def(name, value, purity, _) => {
+                let global = self.scopes.len() == 1;
+                let name = if global { self.modules.define(&name) } else { name };
                 let (idx, symbols) = self.declare(name, symbols, purity, expr.line)?;
-                let (to_push, symbols) = self.compile_expr(*value, symbols, purity)?; // Update symbols.
-                let mut to_ret = vec![OpCode::Def(idx, to_push.len() as u16)];
-                to_ret.extend(to_push);
+                let lambda = matches!(value.exprt, ExprT::Lambda(..));
+                if !global && lambda {
+                    // Lets a local function refer to itself.
+                    self.name = Some(idx);
+                }
+                let (mut to_ret, symbols) = self.compile_expr(*value, symbols, purity)?; // Update symbols.
+                if lambda {
+                    // The closure of the lambda is the last chunk, its body being compiled first.
+                    if let Some(chunk) = self.output.chunks.last_mut() {
+                        chunk.name = Some(idx);
+                    }
+                }
+                if global {
+                    to_ret.push(OpCode::DefGlobal(idx));
+                } else {
+                    let scope = self.scope();
+                    let slot = match scope.locals.iter().rposition(|local| *local == Some(idx)) {
+                        Some(slot) => slot,
+                        None => {
+                            scope.locals.push(Some(idx));
+                            scope.locals.len() - 1
+                        }
+                    };
+                    self.slots(slot + 1, expr.line)?;
+                    to_ret.push(OpCode::DefLocal(slot as u32));
+                }
                 Ok((to_ret, symbols))
             }
             ExprT::Call(func, args) => {
                 if let ExprT::Var(v) = func.clone().exprt {
-                    match self.macros.iter().position(|(name, ..)| &v == name) {
-                        Some(i) => return self.r#macro(i, args, symbols, impure, expr.line),
-                        None => {}
+                    if let Some(i) = self.macros.iter().position(|(name, ..)| &v == name) {
+                        return self.r#macro(i, args, symbols, impure, expr.span);
                     }
                 }
+                let callee = match &func.exprt {
+                    ExprT::Var(name) => name.clone(),
+                    _ => String::new(),
+                };
+                let callee_span = func.span;
+                let escapes = self.escapes;
                 let (mut to_ret, mut symbols) = self.compile_expr(*func, symbols, impure)?; // The λ to execute.
-                let argc = args.len() as u16;
+                if !callee.is_empty() {
+                    // Calling an impure function is an effect, but its result is a plain value.
+                    self.escapes = escapes;
+                }
+                // A global pure function may only be given pure functions, as it could call them.
+                let pure_callee = !callee.is_empty() && matches!(to_ret.last(), Some(OpCode::LoadGlobal(idx)) if !symbols[*idx as usize].1);
+                let argc = self.index(args.len(), "arguments", expr.line)?;
                 to_ret.extend(
                     // Push arguments onto the stack, and keep the amount in order to pop all the arguments.
                     args.into_iter()
                     .map(|a| {
+                        let (span, escapes) = (a.span, self.escapes);
                         let (opcodes, syms) = self.compile_expr(a, symbols.clone(), impure)?;
+                        if pure_callee && self.escapes > escapes {
+                            return error!(E0005; self.file, span => "Impure function given to a pure function: {}.", callee)
+                                .map_err(|e| e.label(callee_span, format!("`{}` is pure, and may call its arguments", callee)));
+                        }
                         symbols = syms; // Update symbols.
                         Ok(opcodes)
                     })
@@ -310,52 +620,82 @@ impl Compiler {
                     .flatten()
                     .collect::<Vec<OpCode>>(),
                     );
+                to_ret.push(self.mark(expr.span)?);
                 to_ret.push(OpCode::Call(argc));
                 Ok((to_ret, symbols))
             }
             ExprT::Begin(expressions) => {
+                self.scopes.push(Scope {
+                    locals: vec![None],
+                    ..Scope::default()
+                });
                 let instructions = expressions.into_iter().map(|expr| {
                     let (instruction, new_syms) = self.compile_expr(expr, symbols.clone(), impure)?;
                     symbols = new_syms;
                     Ok(instruction)
                 }).collect::<Result<Vec<Vec<OpCode>>>>()?.into_iter().flatten().collect::<Vec<OpCode>>();
-                self.output.chunks.push(Chunk {
+                let scope = self.scopes.pop().unwrap_or_else(|| bug!("COMPILER_NO_SCOPE"));
+                let mut to_ret = self.closure(Chunk {
                     instructions,
                     reference: vec![],
-                });
-                Ok((vec![OpCode::Lambda(self.output.chunks.len() as u16 - 1), OpCode::Call(0)], symbols))
+                    captures: scope.captures,
+                    name: None,
+                    lines: vec![],
+                }, expr.line)?;
+                to_ret.push(OpCode::Call(0));
+                Ok((to_ret, symbols))
             }
             ExprT::Lambda(args, body) => {
-                let args_reference = args
-                    .iter()
-                    .map(|a| {
-                        let (idx, syms) = self.declare(a, symbols.clone(), false, expr.line)?;
+                let scope = Scope {
+                    locals: vec![self.name.take()],
+                    ..Scope::default()
+                };
+                let effects = self.effects;
+                let (chunk, symbols) = self.function(args, *body, symbols, impure, scope, expr.line)?;
+                if self.effects > effects {
+                    // A closure using impure code is itself an impure function.
+                    self.escapes += 1;
+                }
+                Ok((self.closure(chunk, expr.line)?, symbols))
+            }
+            ExprT::Let(binding, bindings, body) => {
+                // The variables are kept in slots of the running function, like the ones of a pattern.
+                let start = self.scope().locals.len();
+                self.slots(start + bindings.len(), expr.line)?;
+                self.scope().locals.resize(start + bindings.len(), None);
+                let mut to_ret = vec![];
+                if binding == Binding::Recursive {
+                    let mut ids = vec![];
+                    for (name, _) in &bindings {
+                        let (idx, syms) = self.declare(name, symbols, impure, expr.line)?;
                         symbols = syms;
-                        Ok(idx)
-                    })
-                .collect::<Result<Vec<_>>>()?; // Position in the symbol table for each arg.
-                let run_with = symbols
-                    .iter()
-                    .enumerate()
-                    .map(|(idx, sym)| {
-                        match args_reference.iter().position(|id| *id as usize == idx) {
-                            // Check if the current symbol is part of the arguments
-                            // If it is, then replace it by the argument's value, else
-                            // use the value that is already in the table.
-                            Some(arg_i) => (args[arg_i].to_string(), false),
-                            None => sym.clone(),
+                        ids.push(idx);
+                    }
+                    let (code, syms) = self.group(&ids, bindings, symbols, impure, start, expr.line)?;
+                    to_ret.extend(code);
+                    symbols = syms;
+                    ids.into_iter().enumerate().for_each(|(i, idx)| self.scope().locals[start + i] = Some(idx));
+                } else {
+                    let mut ids = vec![];
+                    for (i, (name, value)) in bindings.into_iter().enumerate() {
+                        let escapes = self.escapes;
+                        let (code, syms) = self.compile_expr(value, symbols, impure)?;
+                        to_ret.extend(code);
+                        to_ret.push(OpCode::DefLocal((start + i) as u32));
+                        // The variable is impure only if its value may be an impure function.
+                        let (idx, syms) = self.declare(name, syms, self.escapes > escapes, expr.line)?;
+                        symbols = syms;
+                        if binding == Binding::Sequential {
+                            self.scope().locals[start + i] = Some(idx);
                         }
-                    })
-                .collect::<Vec<(String, bool)>>();
-                let (chunk_instructions, symbols) = self.compile_expr(*body, run_with, impure)?;
-                self.output.chunks.push(Chunk {
-                    instructions: chunk_instructions,
-                    reference: args_reference,
-                });
-                Ok((
-                        vec![OpCode::Lambda(self.output.chunks.len() as u16 - 1)],
-                        symbols,
-                        ))
+                        ids.push(idx);
+                    }
+                    ids.into_iter().enumerate().for_each(|(i, idx)| self.scope().locals[start + i] = Some(idx));
+                }
+                let (code, symbols) = self.compile_expr(*body, symbols, impure)?;
+                to_ret.extend(code);
+                self.scope().locals.truncate(start);
+                Ok((to_ret, symbols))
             }
             ExprT::Builtin(name, args) => {
                 let argc = args.len();
@@ -375,7 +715,7 @@ impl Compiler {
 
                 if name.as_str() == "panic" {
                     if argc != 1 {
-                        return error!(self.file, expr.line => "Intrisic panic takes 1 argument but {} arguments were supplied.", argc)
+                        return error!(E0003; self.file, expr.span => "Intrisic panic takes 1 argument but {} arguments were supplied.", argc)
                     } else {
                         to_ret.push(OpCode::Panic(self.register_constant(Literal::String(self.file.clone()), expr.line)?, self.register_constant(Literal::Integer(expr.line as i32), expr.line)?));
                         return Ok((to_ret, symbols));
@@ -386,41 +726,43 @@ impl Compiler {
                     .builtins
                     .iter()
                     .position(|builtin| builtin.0 == name)
-                    .map_or(error!(self.file, expr.line => "No such builtin: {}.", name), |i| Ok(i))?;
-                let impure_builtin = self.builtins[idx as usize].1;
+                    .map_or(error!(E0002; self.file, expr.span => "No such builtin: {}.", name), Ok)?;
+                let impure_builtin = self.builtins[idx].1;
                 if !impure && impure_builtin {
-                    return error!(self.file, expr.line => "Impure builtin used out of an `impure` function: {}.", name);
+                    return error!(E0005; self.file, expr.span => "Impure builtin used out of an `impure` function: {}.", name).map_err(|e| e.note(PURITY_NOTE));
+                } else if impure_builtin {
+                    self.effects += 1;
                 }
-                to_ret.push(OpCode::Builtin(idx as u8, argc as u8));
+                to_ret.push(self.mark(expr.span)?);
+                to_ret.push(OpCode::Builtin(self.index(idx, "builtins", expr.line)?, self.index(argc, "arguments", expr.line)?));
                 Ok((to_ret, symbols))
             }
             ExprT::Enum(name, constructors) => {
-                let start = self.output.constructors.len() as u16;
+                let start = self.index(self.output.constructors.len(), "constructors", expr.line)?;
                 constructors
                     .into_iter()
-                    .map(|(k, v)| {
-                        symbols = self.register_constructor(k, symbols.clone(), v, expr.line)?;
+                    .try_for_each(|(k, v)| -> Result<()> {
+                        symbols = self.register_constructor(k, symbols.clone(), v.len(), expr.span)?;
                         Ok(())
-                    })
-                .collect::<Result<()>>()?;
-                let end = self.output.constructors.len() as u16 - 1;
+                    })?;
+                let end = self.index(self.output.constructors.len() - 1, "constructors", expr.line)?;
                 self.output.types.push((name, start, end));
                 Ok((vec![], symbols))
             }
             ExprT::Constr(name, contained) => {
-                let (amount, idx) = self.get_constructor(&name, expr.line)?;
-                self.check_constr(idx, amount, contained.len() as u8, expr.line)?;
-                if amount != contained.len() as u8 {
-                    error!(
+                let (amount, idx) = self.get_constructor(&name, expr.span)?;
+                self.check_constr(idx, amount, contained.len(), expr.span)?;
+                if amount as usize != contained.len() {
+                    error!(E0003;
                         self.file,
-                        expr.line =>
+                        expr.span =>
                         "Enum Constructor {} takes {} values, but {} values were given.",
                         name,
                         amount,
                         contained.len()
                         )
                 } else {
-                    let values = contained
+                    let mut to_ret = contained
                         .into_iter()
                         .map(|expr| {
                             let (compiled, new_syms) =
@@ -432,14 +774,13 @@ impl Compiler {
                         .into_iter()
                         .flatten()
                         .collect::<Vec<OpCode>>();
-                    let mut to_ret = vec![OpCode::Constructor(idx, values.len() as u16)];
-                    to_ret.extend(values);
+                    to_ret.push(OpCode::Constructor(idx));
                     Ok((to_ret, symbols))
                 }
             }
             ExprT::Tuple(vals) => {
                 let length = vals.len();
-                let values = vals
+                let mut to_ret = vals
                     .into_iter()
                     .map(|expr| {
                         let (compiled, new_syms) =
@@ -451,105 +792,210 @@ impl Compiler {
                     .into_iter()
                     .flatten()
                     .collect::<Vec<OpCode>>();
-                let op_count = values.len();
-                let mut to_ret = vec![OpCode::Tuple(op_count as u16, length as u16)];
-                to_ret.extend(values);
+                to_ret.push(OpCode::Tuple(self.index(length, "tuple values", expr.line)?));
                 Ok((to_ret, symbols))
             }
-            ExprT::Match(expr, patterns) => {
-                let (mut compiled, mut symbols) = self.compile_expr(*expr.clone(), symbols, impure)?;
-                let match_content = patterns.into_iter().map(|(pat, expr)| {
-                    let (pat_id, new_symbols) = self.declare_pat(pat, symbols.clone(), impure, expr.line)?;
+            ExprT::Match(value, patterns) => {
+                let (line, escapes) = (value.line, self.escapes);
+                let (mut to_ret, mut symbols) = self.compile_expr(*value, symbols, impure)?;
+                // The pattern variables are impure only if the matched value may hold an impure function.
+                let bound = self.escapes > escapes;
+                let spans = patterns.iter().map(|(_, span, _)| *span).collect::<Vec<_>>();
+                let (pats, bodies): (Vec<Pat>, Vec<Expr>) = patterns.into_iter().map(|(pat, span, body)| {
+                    let (pat, new_symbols) = self.pattern(pat, symbols.clone(), bound, span)?;
                     symbols = new_symbols;
-                    let (compiled, new_syms) = self.compile_expr(expr, symbols.clone(), impure)?;
+                    Ok((pat, body))
+                }).collect::<Result<Vec<_>>>()?.into_iter().unzip();
+                self.check_match(&pats, &spans, &symbols, expr.span);
+
+                // The matched value and its fields are kept in slots, that the pattern variables refer to.
+                let locals = self.scope().locals.len();
+                let scrutinee = self.slots(locals, line)?;
+                let slots = Slots::new(scrutinee, &pats);
+                self.slots(slots.end as usize, line)?;
+                self.scope().locals.resize(slots.end as usize, None);
+                to_ret.push(OpCode::DefLocal(scrutinee));
+                let arms = pats.iter().zip(bodies).map(|(pat, body)| {
+                    let bindings = slots.bindings(pat, scrutinee);
+                    bindings.iter().for_each(|(sym, slot)| self.scope().locals[*slot as usize] = Some(*sym));
+                    let (compiled, new_syms) = self.compile_expr(body, symbols.clone(), impure)?;
                     symbols = new_syms;
-                    Ok((pat_id, compiled))
-                }).collect::<Result<Vec<(u16, Vec<OpCode>)>>>()?;
+                    let locals = &mut self.scope().locals;
+                    locals.truncate(slots.end as usize);
+                    bindings.iter().for_each(|(_, slot)| locals[*slot as usize] = None);
+                    Ok(compiled)
+                }).collect::<Result<Vec<Vec<OpCode>>>>()?;
+                self.scope().locals.truncate(scrutinee as usize);
 
-                let idx = if self.output.matches.contains(&match_content) {
-                    self.output.matches.iter().position(|m| m == &match_content).unwrap()
-                } else {
-                    self.output.matches.push(match_content);
-                    self.output.matches.len() - 1
-                } as u16;
-                compiled.push(OpCode::Match(idx));
-                Ok((compiled, symbols))
+                let tree = Builder::new(&mut self.output.patterns, &slots).build(scrutinee, &pats);
+                // The tree comes before the arms, the marker thus covers all the tests.
+                to_ret.push(self.mark(expr.span)?);
+                to_ret.extend(tree.link(arms, scrutinee));
+                Ok((to_ret, symbols))
             }
-            ExprT::Macro(name, args, content) => {
-                let r#macro = Macro { args, content };
+            ExprT::Macro(name, clauses) => {
+                let r#macro = Macro::new(clauses, &self.file)?;
                 match self.macros.iter().position(|(n, _)| n == &name) {
                     None => {
                         self.macros.push((name, r#macro));
                         Ok((vec![], symbols))
                     }
-                    Some(_) => error!(self.file, expr.line => "Macro has already been defined: {}.", name)
+                    Some(_) => error!(E0006; self.file, expr.span => "Macro has already been defined: {}.", name)
                 }
             }
+            // The parser only accepts them in the body of a macro, that is expanded before compilation.
+            ExprT::Quasiquote(_) | ExprT::Unquote(_) | ExprT::Splice(_) => bug!("UNEXPANDED_QUASIQUOTE"),
         }
     }
-    fn declare_pat(&mut self, pat: ParserPattern, mut symbols: Vec<(String, bool)>, impure: bool, line: usize) -> Result<(u16, Vec<(String, bool)>)> {
-        let flattened = match pat {
-            ParserPattern::Var(s) => {
-                if s.as_str() == "_" {
-                    BytecodePattern::Any
-                } else {
-                    let (sym_id, new_symbols) = self.declare(s, symbols.clone(), impure, line)?;
-                    symbols = new_symbols;
-                    BytecodePattern::Var(sym_id)
-                }
+    // Warns about the values that no pattern matches and about the arms that cannot be reached.
+    fn check_match(&mut self, pats: &[Pat], spans: &[Span], symbols: &[(String, bool)], span: Span) {
+        let enums = Enums {
+            constructors: &self.output.constructors,
+            types: &self.output.types,
+        };
+        let rows = pats.iter().map(|pat| vec![pat.clone()]).collect::<Vec<_>>();
+        // Only the missing enum variants are reported, literals never covering all the values.
+        let missing = enums.missing(&rows, 1).into_iter().filter(|row| row[0].has_constr()).collect::<Vec<_>>();
+        let unreachable = (0..rows.len()).filter(|idx| !enums.useful(&rows[..*idx], &rows[*idx])).collect::<Vec<_>>();
+
+        if !missing.is_empty() {
+            let shown = missing.iter().take(3).map(|row| format!("`{}`", self.show(&row[0], symbols))).collect::<Vec<_>>().join(", ");
+            let more = if missing.len() > 3 { format!(" and {} more", missing.len() - 3) } else { String::new() };
+            let warning = Diagnostic::new(Severity::Warning, Some(self.file.clone()), Some(span), format!("Non-exhaustive patterns: {}{} not covered.", shown, more));
+            self.warnings.push(OrionError::from(warning).code("W0001"));
+        }
+        unreachable.into_iter().for_each(|idx| {
+            let pat = self.show(&pats[idx], symbols);
+            let warning = Diagnostic::new(Severity::Warning, Some(self.file.clone()), Some(spans[idx]), format!("Unreachable pattern: `{}`.", pat));
+            self.warnings.push(OrionError::from(warning).code("W0002"));
+        });
+    }
+    fn show(&self, pat: &Pat, symbols: &[(String, bool)]) -> String {
+        let fields = |fields: &[Pat]| fields.iter().map(|pat| self.show(pat, symbols)).collect::<Vec<_>>().join(" ");
+        match pat {
+            Pat::Any(None) => "_".to_string(),
+            Pat::Any(Some(sym)) => symbols.get(*sym as usize).map_or("_".to_string(), |(name, _)| name.clone()),
+            Pat::Constr(idx, inside) if inside.is_empty() => self.constructors[*idx as usize].clone(),
+            Pat::Constr(idx, inside) => format!("({} {})", self.constructors[*idx as usize], fields(inside)),
+            Pat::Tuple(inside) if inside.is_empty() => "()".to_string(),
+            Pat::Tuple(inside) => format!("(, {})", fields(inside)),
+            Pat::Literal(id) => literal(&self.output.constants[*id as usize]),
+        }
+    }
+    fn pattern(&mut self, pat: ParserPattern, mut symbols: Vec<(String, bool)>, impure: bool, span: Span) -> Result<(Pat, Vec<(String, bool)>)> {
+        let line = span.line;
+        let mut fields = |compiler: &mut Self, inside: Vec<ParserPattern>| inside.into_iter().map(|pat| {
+            let (pat, new_syms) = compiler.pattern(pat, symbols.clone(), impure, span)?;
+            symbols = new_syms;
+            Ok(pat)
+        }).collect::<Result<Vec<Pat>>>();
+        let pat = match pat {
+            ParserPattern::Var(s) => if s.as_str() == "_" {
+                Pat::Any(None)
+            } else {
+                let (sym_id, new_symbols) = self.declare(s, symbols.clone(), impure, line)?;
+                symbols = new_symbols;
+                Pat::Any(Some(sym_id))
             }
             ParserPattern::Constr(constr, inside) => {
-                let (amount, constr_id)= self.get_constructor(constr, line)?;
-                self.check_constr(constr_id, amount, inside.len() as u8, line)?;
-                BytecodePattern::Constr(constr_id, inside.into_iter().map(|pat| {
-                    let (idx, new_syms) = self.declare_pat(pat, symbols.clone(), impure, line)?;
-                    symbols = new_syms;
-                    Ok(idx)
-                }).collect::<Result<Vec<u16>>>()?)
-            }
-            ParserPattern::Tuple(inside) => {
-                BytecodePattern::Tuple(inside.into_iter().map(|pat| {
-                    let (idx, new_syms) = self.declare_pat(pat, symbols.clone(), impure, line)?;
-                    symbols = new_syms;
-                    Ok(idx)
-                }).collect::<Result<Vec<u16>>>()?)
-            }
-            ParserPattern::Literal(lit) => {
-                let idx = self.register_constant(lit, line)?;
-                BytecodePattern::Literal(idx)
+                let (amount, constr_id) = self.get_constructor(constr, span)?;
+                self.check_constr(constr_id, amount, inside.len(), span)?;
+                Pat::Constr(constr_id, fields(self, inside)?)
             }
+            ParserPattern::Tuple(inside) => Pat::Tuple(fields(self, inside)?),
+            ParserPattern::Literal(lit) => Pat::Literal(self.register_constant(lit, line)?),
         };
-
-        Ok((if self.output.patterns.contains(&flattened) {
-            self.output.patterns.iter().position(|pat| pat == &flattened).unwrap() as u16
-        } else {
-            self.output.patterns.push(flattened);
-            self.output.patterns.len() as u16 - 1
-        }, symbols))
+        Ok((pat, symbols))
     }
-    fn check_constr(&self, idx: u16, expected: u8, given: u8, line: usize) -> Result<()> {
-        if given != expected {
-            error!(self.file, line => "Constructor {} takes {} values, but {} values were given.", self.constructors[idx as usize], expected, given)
+    fn check_constr(&self, idx: u32, expected: u32, given: usize, span: Span) -> Result<()> {
+        if given != expected as usize {
+            error!(E0003; self.file, span => "Constructor {} takes {} values, but {} values were given.", self.constructors[idx as usize], expected, given)
         } else {
             Ok(())
         }
     }
-    pub fn compile(&mut self, mut symbols: Vec<(String, bool)>) -> Result<(Bytecode, Vec<(String, bool)>, Vec<String>, Vec<(String, Macro)>)> {
+    // The input with the calls to the macros expanded, or only the outermost call of each expression
+    // if `once`. The loads and the macro definitions are compiled to register their macros, the
+    // definitions being left out of the result.
+    pub fn expand(&mut self, once: bool) -> Result<Vec<Expr>> {
+        let mut symbols = vec![];
+        let mut expanded = vec![];
         for expr in self.input.clone() {
-            let (to_push, new_symbols) = self.compile_expr(expr, symbols, self.repl)?;
-            symbols = new_symbols;
-            self.output.instructions.extend(to_push);
+            match &expr.exprt {
+                ExprT::Load(_) | ExprT::Import(..) | ExprT::Macro(..) => {
+                    symbols = self.compile_expr(expr.clone(), symbols, true)?.1;
+                    // The implicit load of the prelude is on line 0.
+                    if !matches!(expr.exprt, ExprT::Macro(..)) && expr.line != 0 {
+                        expanded.push(expr);
+                    }
+                }
+                _ => expanded.push(self.expand_expr(expr, once)?),
+            }
+        }
+        Ok(expanded)
+    }
+    fn expand_expr(&mut self, expr: Expr, once: bool) -> Result<Expr> {
+        if let ExprT::Call(func, args) = &expr.exprt {
+            if let ExprT::Var(v) = &func.exprt {
+                if let Some(i) = self.macros.iter().position(|(name, ..)| v == name) {
+                    let expansion = self.macros[i].1.expand(args.clone(), expr.span, &self.file, &mut self.gensyms, self.expanding)?;
+                    if once {
+                        return Ok(expansion);
+                    }
+                    self.expanding += 1;
+                    let expanded = self.expand_expr(expansion, once);
+                    self.expanding -= 1;
+                    return expanded;
+                }
+            }
         }
+        if once {
+            return Ok(expr);
+        }
+        let exprt = match expr.exprt {
+            ExprT::Call(func, args) => ExprT::Call(Box::new(self.expand_expr(*func, once)?), self.expand_all(args)?),
+            ExprT::Lambda(params, body) => ExprT::Lambda(params, Box::new(self.expand_expr(*body, once)?)),
+            ExprT::Def(name, value, impure, signature) => ExprT::Def(name, Box::new(self.expand_expr(*value, once)?), impure, signature),
+            ExprT::Constr(name, args) => ExprT::Constr(name, self.expand_all(args)?),
+            ExprT::Tuple(args) => ExprT::Tuple(self.expand_all(args)?),
+            ExprT::Begin(exprs) => ExprT::Begin(self.expand_all(exprs)?),
+            ExprT::Builtin(name, args) => ExprT::Builtin(name, self.expand_all(args)?),
+            ExprT::Let(binding, bindings, body) => ExprT::Let(
+                binding,
+                bindings.into_iter().map(|(name, value)| Ok((name, self.expand_expr(value, once)?))).collect::<Result<Vec<_>>>()?,
+                Box::new(self.expand_expr(*body, once)?),
+                ),
+            ExprT::Match(value, arms) => ExprT::Match(
+                Box::new(self.expand_expr(*value, once)?),
+                arms.into_iter().map(|(pat, span, body)| Ok((pat, span, self.expand_expr(body, once)?))).collect::<Result<Vec<_>>>()?,
+                ),
+            exprt => exprt,
+        };
+        Ok(Expr { exprt, ..expr })
+    }
+    fn expand_all(&mut self, exprs: Vec<Expr>) -> Result<Vec<Expr>> {
+        exprs.into_iter().map(|expr| self.expand_expr(expr, false)).collect()
+    }
+    pub fn compile(&mut self, symbols: Vec<(String, bool)>) -> Result<CompileOutput> {
+        let (instructions, symbols) = self.top_level(self.input.clone(), symbols, self.repl)?;
+        self.output.instructions.extend(instructions);
         self.output.symbols = symbols
             .iter()
             .map(|(name, _)| name.to_string())
             .collect::<Vec<String>>();
 
-        if self.output.symbols.contains(&"main".to_string()) {
-            self.output.instructions.extend(vec![OpCode::LoadSym(self.output.symbols.iter().position(|s| s == "main").unwrap() as u16), OpCode::Call(0)]);
+        let main = self.modules.resolve("main", &self.file, Span::default())?;
+        if let Some(idx) = self.output.symbols.iter().position(|s| *s == main) {
+            // The implicit call has no source position.
+            let unknown = self.mark(Span::default())?;
+            self.output.instructions.extend(vec![unknown, OpCode::LoadGlobal(idx as u32), OpCode::Call(0)]);
         }
+        let code = std::mem::take(&mut self.output.instructions);
+        let (instructions, lines) = self.strip(code);
+        self.output.instructions = instructions;
+        self.output.lines = lines;
 
-        Ok((self.output.clone(), symbols, self.constructors.clone(), self.macros.clone()))
+        Ok((self.output.clone(), symbols, self.constructors.clone(), self.macros.clone(), self.modules.clone()))
     }
 }
 
@@ -561,9 +1007,150 @@ mod test {
     fn def() -> Result<()> {
         let tokens = Lexer::new("(def a 42)(def 'impure b 34)", 0).proc_tokens()?;
         let ast = Parser::new(tokens, "TEST").parse()?;
-        let (bcode, symbols, ..) = Compiler::new(ast, "TEST", Bytecode::new(), vec![], true, "".to_string(), false, vec![])?.compile(vec![])?;
-        assert_eq!(bcode.instructions, vec![OpCode::Def(0, 1),OpCode::LoadConst(0),  OpCode::Def(1, 1), OpCode::LoadConst(1)]);
+        let (bcode, symbols, ..) = Compiler::new(ast, "TEST", Bytecode::new(), vec![], true, "".to_string(), false, vec![], Modules::default())?.compile(vec![])?;
+        assert_eq!(bcode.instructions, vec![OpCode::LoadConst(0), OpCode::DefGlobal(0), OpCode::LoadConst(1), OpCode::DefGlobal(1)]);
         assert_eq!(symbols, vec![("a".to_string(), false), ("b".to_string(), true)]);
         Ok(())
     }
+
+    #[test]
+    fn captures() -> Result<()> {
+        let tokens = Lexer::new("(def k 1)(def f (λ (a b) (λ (c) (+ (+ a c) k))))", 0).proc_tokens()?;
+        let ast = Parser::new(tokens, "TEST").parse()?;
+        let (bcode, ..) = Compiler::new(ast, "TEST", Bytecode::new(), vec![], true, "".to_string(), false, vec![], Modules::default())?.compile(vec![])?;
+        // Only the locals of the enclosing functions are captured, not the globals.
+        assert_eq!(bcode.chunks[0].captures, vec![2]);
+        assert_eq!(bcode.chunks[0].instructions[..3], [OpCode::LoadUpvalue(0), OpCode::LoadLocal(1), OpCode::Builtin(0, 2)]);
+        assert_eq!(bcode.chunks[0].instructions[3], OpCode::LoadGlobal(0));
+        assert!(bcode.chunks[1].captures.is_empty());
+        assert_eq!(bcode.chunks[1].instructions, vec![OpCode::LoadLocal(1), OpCode::Lambda(0)]);
+        Ok(())
+    }
+
+    #[test]
+    fn decision_tree() -> Result<()> {
+        let tokens = Lexer::new("(enum Shape (Circle r) (Rect w h) Dot)
+        (def area (λ (s) (match s ((Circle r) r) ((Rect w 0) 0) ((Rect w h) (* w h)) (Dot 7))))", 0).proc_tokens()?;
+        let ast = Parser::new(tokens, "TEST").parse()?;
+        let (bcode, ..) = Compiler::new(ast, "TEST", Bytecode::new(), vec![], true, "".to_string(), false, vec![], Modules::default())?.compile(vec![])?;
+        let count = |f: fn(&OpCode) -> bool| bcode.chunks[0].instructions.iter().filter(|i| f(i)).count();
+        // The constructor is tested once, and both `Rect` arms share the unpacking of its fields.
+        assert_eq!(count(|i| matches!(i, OpCode::Switch(_, _, 3))), 1);
+        assert_eq!(count(|i| matches!(i, OpCode::Unpack(..))), 2);
+        assert_eq!(count(|i| matches!(i, OpCode::JumpIfNoMatch(..))), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn match_warnings() -> Result<()> {
+        let tokens = Lexer::new("(enum Shape (Circle r) (Rect w h) Dot)
+        (def area (λ (s) (match s ((Circle r) r) ((Rect w h) (* w h)))))
+        (def dot? (λ (s)
+          (match s
+            (_ 0)
+            (Dot 1))))
+        (def both (λ (p) (match p ((, Dot Dot) 0) ((, (Circle _) _) 1) ((, _ (Circle r)) r))))
+        (def zero? (λ (n) (match n (0 1) (_ 0))))", 0).proc_tokens()?;
+        let ast = Parser::new(tokens, "TEST").parse()?;
+        let mut compiler = Compiler::new(ast, "TEST", Bytecode::new(), vec![], true, "".to_string(), false, vec![], Modules::default())?;
+        compiler.compile(vec![])?;
+        let warnings = compiler.warnings().iter().map(|e| (e.line, e.message.clone())).collect::<Vec<_>>();
+        assert_eq!(warnings[..2], [
+            (Some(2), "Non-exhaustive patterns: `Dot` not covered.".to_string()),
+            (Some(6), "Unreachable pattern: `Dot`.".to_string()),
+        ]);
+        // The order of the variants of an enum is not fixed.
+        assert_eq!(warnings[2].0, Some(7));
+        let mut missing = warnings[2].1.trim_start_matches("Non-exhaustive patterns: `").trim_end_matches("` not covered.").split("`, `").collect::<Vec<_>>();
+        missing.sort_unstable();
+        assert_eq!(missing, vec!["(, (Rect _ _) (Rect _ _))", "(, (Rect _ _) Dot)", "(, Dot (Rect _ _))"]);
+        assert_eq!(warnings.len(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn impure_arguments() -> Result<()> {
+        let compile = |code: &str, repl| -> Result<Bytecode> {
+            let tokens = Lexer::new(code, 0).proc_tokens()?;
+            let ast = Parser::new(tokens, "TEST").parse()?;
+            Ok(Compiler::new(ast, "TEST", Bytecode::new(), vec![], true, "".to_string(), repl, vec![], Modules::default())?.compile(vec![])?.0)
+        };
+        let code = "(enum Maybe (Just x) Nothing)(macro twice (x) `(, ,x ,x))(def show (λ (x) (format \"{}\" (, x))))
+        (def apply (λ (f x) (f x)))(def 'impure say (λ (s) (putStr s)))(def 'impure each (λ (f) (f \"a\")))(def 'impure ask (λ () (getLine)))";
+        let error = |main| compile(&format!("{}(def 'impure main (λ () {}))", code, main), false).err().map(|e| e.message.clone());
+        assert_eq!(error("(apply say \"a\")"), Some("Impure function given to a pure function: apply.".to_string()));
+        assert_eq!(error("(apply (λ (s) (putStr s)) \"a\")"), Some("Impure function given to a pure function: apply.".to_string()));
+        assert_eq!(error("(apply (let ((g say)) g) \"a\")"), Some("Impure function given to a pure function: apply.".to_string()));
+        assert_eq!(error("(apply (match 1 (_ say)) \"a\")"), Some("Impure function given to a pure function: apply.".to_string()));
+        assert_eq!(error("(apply (begin say) \"a\")"), Some("Impure function given to a pure function: apply.".to_string()));
+        assert_eq!(error("(begin (each say) (apply (λ (s) s) \"a\"))"), None);
+        // The results of impure calls and the variables bound to plain values are not impure functions.
+        assert_eq!(error("(say (show (apply show (ask))))"), None);
+        assert_eq!(error("(let ((qqq 3)) (say (show qqq)))"), None);
+        assert_eq!(error("(match (Just 3) ((Just zzz) (say (show zzz))) (Nothing (ask)))"), None);
+        assert_eq!(error("(say (show (twice (show 1))))"), None);
+        assert!(compile(&format!("{}(match (Just 4) ((Just k) (show k)) (Nothing \"\"))", code), true).is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn impure_modules() -> Result<()> {
+        let error = |main: &str| -> Result<Option<String>> {
+            let code = format!("(def apply (λ (f x) (f x)))(def 'impure main (λ () {}))", main);
+            let ast = Parser::new(Lexer::new(&code, "TEST").proc_tokens()?, "TEST").parse()?;
+            let lib = format!("{}/lib", env!("CARGO_MANIFEST_DIR"));
+            Ok(Compiler::new(ast, "TEST", Bytecode::new(), vec![], false, lib, false, vec![], Modules::default())?.compile(vec![]).err().map(|e| e.message.clone()))
+        };
+        assert_eq!(error("(apply putStrLn \"a\")")?, Some("Impure function given to a pure function: apply.".to_string()));
+        assert_eq!(error("(apply io/putStrLn \"a\")")?, Some("Impure function given to a pure function: apply.".to_string()));
+        assert_eq!(error("(apply list/length [1])")?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn expand() -> Result<()> {
+        let expand = |code: &str, once| {
+            let ast = Parser::new(Lexer::new(code, "TEST").proc_tokens()?, "TEST").parse()?;
+            Compiler::new(ast, "TEST", Bytecode::new(), vec![], true, "".to_string(), false, vec![], Modules::default())?.expand(once)
+        };
+        let code = "(macro twice (x) `(, ,x ,x))(macro pair (x y) (, (twice x) y))(def p (pair 1 (twice 2)))";
+        let (once, all) = (expand(code, true)?, expand(code, false)?);
+        assert_eq!(once.len(), 1);
+        assert_eq!(crate::printer::show(&once[0]), "(def p (pair 1 (twice 2)))");
+        assert_eq!(crate::printer::show(&all[0]), "(def p (, (, 1 1) (, 2 2)))");
+        assert_eq!(crate::printer::show(&expand("(macro twice (x) `(, ,x ,x))(twice (twice 1))", true)?[0]), "(match (twice 1) (x#1 (, x#1 x#1)))");
+        Ok(())
+    }
+
+    #[test]
+    fn local_bindings() -> Result<()> {
+        let compile = |code: &str| -> Result<Bytecode> {
+            let ast = Parser::new(Lexer::new(code, "TEST").proc_tokens()?, "TEST").parse()?;
+            Ok(Compiler::new(ast, "TEST", Bytecode::new(), vec![], true, "".to_string(), false, vec![], Modules::default())?.compile(vec![])?.0)
+        };
+        // The variables go in slots, without any closure.
+        let bcode = compile("(def f (λ (a) (let* ((b a) (c b)) c)))")?;
+        assert_eq!(bcode.chunks[0].instructions, vec![OpCode::LoadLocal(1), OpCode::DefLocal(2), OpCode::LoadLocal(2), OpCode::DefLocal(3), OpCode::LoadLocal(3)]);
+        // The functions of a `letrec` share the upvalues, so that each one makes the others from them.
+        let bcode = compile("(def f (λ (a) (letrec ((g (λ () (h))) (h (λ () (, a (g))))) g)))")?;
+        assert_eq!(bcode.chunks[0].instructions[0], OpCode::LoadSibling(1));
+        assert_eq!(bcode.chunks[0].captures, bcode.chunks[1].captures);
+        assert_eq!(bcode.chunks[1].instructions[..2], [OpCode::LoadUpvalue(0), OpCode::LoadSibling(0)]);
+        assert_eq!(compile("(def f (letrec ((g 1)) g))").unwrap_err().message, "Expected a lambda, as the values of `letrec` are functions.");
+        Ok(())
+    }
+
+    #[test]
+    fn several_errors() {
+        let ast = Parser::new(Lexer::new("(def value b)\n(def c (value d))\n(def e (valu 1))", "TEST").proc_tokens().unwrap(), "TEST").parse().unwrap();
+        let errors = Compiler::new(ast, "TEST", Bytecode::new(), vec![], true, "".to_string(), false, vec![], Modules::default()).unwrap().compile(vec![]).err().unwrap();
+        let messages = errors.diagnostics().iter().map(|e| (e.code, e.line, e.message.as_str())).collect::<Vec<_>>();
+        assert_eq!(messages, vec![
+            (Some("E0002"), Some(1), "Variable not in scope: b."),
+            (Some("E0002"), Some(2), "Variable not in scope: d."),
+            (Some("E0002"), Some(3), "Variable not in scope: valu."),
+        ]);
+        let suggestion = &errors.diagnostics()[2].suggestions[0];
+        assert_eq!(suggestion.replacement.as_ref().map(|(span, code)| (span.column, code.as_str())), Some((9, "value")));
+    }
 }
@@ -0,0 +1,240 @@
+/*
+ *  Copyright (C) 2021, Wafelack <wafelack@protonmail.com>
+ *
+ *  ------------------------------------------------------
+ *
+ *     This file is part of Orion.
+ *
+ *  Orion is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  Orion is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with Orion.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::{
+    bytecode::{Bytecode, BytecodePattern, OpCode, Position},
+    compiler::BUILTINS,
+    parser::Literal,
+};
+
+pub fn literal(literal: &Literal) -> String {
+    match literal {
+        Literal::Integer(i) => i.to_string(),
+        Literal::Single(f) => format!("{:?}", f),
+        Literal::String(s) => format!("{:?}", s),
+    }
+}
+
+// Names that would not read back as a single word are quoted.
+pub fn name(name: &str) -> String {
+    if name.is_empty() || name.contains(|c: char| c.is_whitespace() || c.is_control() || ['"', ';', '[', ']'].contains(&c)) {
+        format!("{:?}", name)
+    } else {
+        name.to_string()
+    }
+}
+
+fn ids(ids: &[u32]) -> String {
+    ids.iter().map(|id| format!("{:04X}", id)).collect::<Vec<_>>().join(" ")
+}
+
+pub struct Disassembler<'a> {
+    input: &'a Bytecode,
+    output: String,
+}
+
+impl<'a> Disassembler<'a> {
+    pub fn new(input: &'a Bytecode) -> Self {
+        Self {
+            input,
+            output: String::new(),
+        }
+    }
+    fn symbol(&self, id: u32) -> String {
+        self.input.symbols.get(id as usize).map_or("?".to_string(), |sym| name(sym))
+    }
+    fn constant(&self, id: u32) -> String {
+        self.input.constants.get(id as usize).map_or("?".to_string(), literal)
+    }
+    fn constructor(&self, idx: u32) -> String {
+        self.input.constructors.get(idx as usize).map_or("?".to_string(), |(_, sym)| self.symbol(*sym))
+    }
+    fn line(&mut self, indent: usize, content: impl ToString, comment: Option<String>) {
+        let content = format!("{}{}", "    ".repeat(indent), content.to_string());
+        match comment {
+            Some(comment) => self.output.push_str(&format!("{:<31} ; {}\n", content, comment)),
+            None => self.output.push_str(&format!("{}\n", content)),
+        }
+    }
+    fn section(&mut self, name: &str) {
+        if !self.output.is_empty() {
+            self.output.push('\n');
+        }
+        self.line(0, format!("{}:", name), None);
+    }
+    // Positions are written before the first instruction they apply to.
+    fn positions(&mut self, indent: usize, lines: &[Position], ip: usize) {
+        lines.iter().filter(|pos| pos.ip as usize == ip).for_each(|pos| {
+            let comment = match self.input.constants.get(pos.file as usize) {
+                Some(Literal::String(file)) => format!("{}:{}:{}", name(file), pos.line, pos.column),
+                _ => "?".to_string(),
+            };
+            self.line(indent, format!("LINE {:04X} {:04X} {:04X} {:04X} {:04X}", pos.file, pos.line, pos.column, pos.start, pos.end), Some(comment));
+        })
+    }
+    fn instructions(&mut self, indent: usize, instructions: &[OpCode], captures: &[u32], lines: &[Position]) {
+        instructions.iter().enumerate().for_each(|(ip, instr)| {
+            self.positions(indent, lines, ip);
+            let comment = match *instr {
+                OpCode::LoadConst(id) => Some(self.constant(id)),
+                OpCode::LoadGlobal(id) | OpCode::DefGlobal(id) => Some(self.symbol(id)),
+                OpCode::LoadUpvalue(idx) => Some(captures.get(idx as usize).map_or("?".to_string(), |sym| self.symbol(*sym))),
+                OpCode::Builtin(idx, _) => Some(BUILTINS.get(idx as usize).map_or("?", |(name, _)| name).to_string()),
+                OpCode::Constructor(idx) => Some(self.constructor(idx)),
+                OpCode::Panic(file, line) => Some(format!("{}:{}", self.constant(file), self.constant(line))),
+                OpCode::Jump(offset) => Some(format!("-> {:04X}", ip + 1 + offset as usize)),
+                OpCode::JumpIfNoMatch(_, _, offset) => Some(format!("else -> {:04X}", ip + 1 + offset as usize)),
+                OpCode::Switch(_, first, amount) => Some((first..first + amount).map(|idx| self.constructor(idx)).collect::<Vec<_>>().join(" ")),
+                _ => None,
+            };
+            self.line(indent, format!("{:04X} {}", ip, instr), comment);
+        });
+        self.positions(indent, lines, instructions.len());
+    }
+    pub fn disassemble(mut self) -> String {
+        let input = self.input;
+
+        self.section("symbols");
+        input.symbols.iter().enumerate().for_each(|(id, sym)| self.line(1, format!("{:04X} {}", id, name(sym)), None));
+
+        self.section("constants");
+        input.constants.iter().enumerate().for_each(|(id, constant)| self.line(1, format!("{:04X} {}", id, literal(constant)), None));
+
+        self.section("constructors");
+        input.constructors.iter().enumerate().for_each(|(idx, (amount, sym))| {
+            self.line(1, format!("{:04X} {:04X} {:04X}", idx, sym, amount), Some(format!("{} ({} values)", self.symbol(*sym), amount)))
+        });
+
+        self.section("types");
+        input.types.iter().enumerate().for_each(|(id, (type_name, start, end))| {
+            self.line(1, format!("{:04X} {} {:04X} {:04X}", id, name(type_name), start, end), Some((*start..=*end).map(|idx| self.constructor(idx)).collect::<Vec<_>>().join(" | ")))
+        });
+
+        self.section("patterns");
+        input.patterns.iter().enumerate().for_each(|(idx, pattern)| {
+            let (content, comment) = match pattern {
+                BytecodePattern::Var(sym) => (format!("VAR     {:04X}", sym), Some(self.symbol(*sym))),
+                BytecodePattern::Constr(idx, pats) => (format!("CONSTR  {:04X} {}", idx, ids(pats)), Some(self.constructor(*idx))),
+                BytecodePattern::Tuple(pats) => (format!("TUP     {}", ids(pats)), None),
+                BytecodePattern::Literal(id) => (format!("LIT     {:04X}", id), Some(self.constant(*id))),
+                BytecodePattern::Any => ("ANY".to_string(), None),
+            };
+            self.line(1, format!("{:04X} {}", idx, content), comment);
+        });
+
+        self.section("chunks");
+        input.chunks.iter().enumerate().for_each(|(id, chunk)| {
+            let names = |syms: &[u32]| syms.iter().map(|sym| self.symbol(*sym)).collect::<Vec<_>>().join(" ");
+            let comment = match (chunk.reference.is_empty(), chunk.captures.is_empty()) {
+                (true, true) => None,
+                (_, true) => Some(names(&chunk.reference)),
+                _ => Some(format!("{} | {}", names(&chunk.reference), names(&chunk.captures)).trim_start().to_string()),
+            };
+            let (header, comment) = match chunk.name {
+                Some(sym) => (format!(" {:04X}", sym), Some(format!("{}: {}", self.symbol(sym), comment.unwrap_or_default()).trim_end().to_string())),
+                None => (String::new(), comment),
+            };
+            self.line(1, format!("{:04X} [{}] [{}]{}", id, ids(&chunk.reference), ids(&chunk.captures), header), comment);
+            self.instructions(2, &chunk.instructions, &chunk.captures, &chunk.lines);
+        });
+
+        self.section("instructions");
+        self.instructions(1, &input.instructions, &[], &input.lines);
+
+        self.output
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bytecode::Chunk;
+
+    #[test]
+    fn disassemble() {
+        let mut bytecode = Bytecode::new();
+        bytecode.symbols = vec!["x".to_string(), "Just".to_string()];
+        bytecode.constants = vec![Literal::Integer(42), Literal::String("a\"b".to_string())];
+        bytecode.constructors = vec![(1, 1)];
+        bytecode.types = vec![("Maybe".to_string(), 0, 0)];
+        bytecode.patterns = vec![BytecodePattern::Var(0), BytecodePattern::Constr(0, vec![0])];
+        bytecode.chunks = vec![Chunk {
+            instructions: vec![OpCode::LoadLocal(0), OpCode::LoadUpvalue(0), OpCode::Call(1)],
+            reference: vec![0],
+            captures: vec![1],
+            name: Some(0),
+            lines: vec![Position { ip: 2, file: 1, line: 3, column: 5, start: 40, end: 48 }],
+        }];
+        bytecode.instructions = vec![
+            OpCode::LoadConst(0),
+            OpCode::Constructor(0),
+            OpCode::DefLocal(0),
+            OpCode::Switch(0, 0, 1),
+            OpCode::Jump(1),
+            OpCode::Jump(2),
+            OpCode::Unpack(0, 1),
+            OpCode::Jump(2),
+            OpCode::JumpIfNoMatch(0, 1, 0),
+            OpCode::NoMatch,
+            OpCode::LoadLocal(1),
+            OpCode::EndMatch(0),
+        ];
+
+        assert_eq!(Disassembler::new(&bytecode).disassemble(), r#"symbols:
+    0000 x
+    0001 Just
+
+constants:
+    0000 42
+    0001 "a\"b"
+
+constructors:
+    0000 0001 0001              ; Just (1 values)
+
+types:
+    0000 Maybe 0000 0000        ; Just
+
+patterns:
+    0000 VAR     0000           ; x
+    0001 CONSTR  0000 0000      ; Just
+
+chunks:
+    0000 [0000] [0001] 0000     ; x: x | Just
+        0000 LOCAL   0000
+        0001 UPVAL   0000       ; Just
+        LINE 0001 0003 0005 0028 0030 ; "a\"b":3:5
+        0002 CALL    0001
+
+instructions:
+    0000 CONST   0000           ; 42
+    0001 CONSTR  0000           ; Just
+    0002 SET     0000
+    0003 SWITCH  0000 0000 0001 ; Just
+    0004 JMP     0001           ; -> 0006
+    0005 JMP     0002           ; -> 0008
+    0006 UNPACK  0000 0001
+    0007 JMP     0002           ; -> 000A
+    0008 JNM     0000 0001 0000 ; else -> 0009
+    0009 NOMATCH
+    000A LOCAL   0001
+    000B ENDM    0000
+"#);
+    }
+}
@@ -18,13 +18,254 @@
  *  You should have received a copy of the GNU General Public License
  *  along with Orion.  If not, see <https://www.gnu.org/licenses/>.
  */
+use crate::lexer::Span;
+use std::ops::{Deref, DerefMut};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+impl Severity {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Error => "error",
+            Self::Warning => "warning",
+        }
+    }
+}
+
+// How the diagnostics are printed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorFormat {
+    Human,
+    Json,
+}
+
+// A secondary span of a diagnostic, in the same file as its main span.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Label {
+    pub span: Span,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Suggestion {
+    pub message: String,
+    pub replacement: Option<(Span, String)>, // The code to put in place of the span.
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub code: Option<&'static str>,
+    pub file: Option<String>,
+    pub line: Option<usize>,
+    pub message: String,
+    pub span: Option<Span>,
+    pub labels: Vec<Label>,
+    pub notes: Vec<String>,
+    pub suggestions: Vec<Suggestion>,
+    pub backtrace: Vec<String>, // The calls that led to a runtime error, innermost first.
+}
+
+// One or more diagnostics, the first one being the main error.
 #[derive(Debug)]
-pub struct OrionError(pub Option<String>, pub Option<usize>, pub String);
+pub struct OrionError(Vec<Diagnostic>);
 
 pub type Result<T> = std::result::Result<T, OrionError>;
 
+// What an error can be located with: a line, or a span that also gives the columns.
+pub trait Location {
+    fn locate(self) -> (usize, Option<Span>);
+}
+impl Location for usize {
+    fn locate(self) -> (usize, Option<Span>) {
+        (self, None)
+    }
+}
+impl Location for Span {
+    fn locate(self) -> (usize, Option<Span>) {
+        (self.line, if self.end > self.start { Some(self) } else { None })
+    }
+}
+
+// The name closest to `name` among `candidates`, if one is close enough to be a typo.
+pub fn closest<'a>(name: &str, candidates: impl Iterator<Item = &'a str>) -> Option<String> {
+    let distance = |a: &str, b: &str| {
+        let b = b.chars().collect::<Vec<_>>();
+        let mut row = (0..=b.len()).collect::<Vec<_>>();
+        a.chars().enumerate().for_each(|(i, ca)| {
+            let mut previous = row[0];
+            row[0] = i + 1;
+            (1..=b.len()).for_each(|j| {
+                let current = row[j];
+                row[j] = (row[j] + 1).min(row[j - 1] + 1).min(previous + if ca == b[j - 1] { 0 } else { 1 });
+                previous = current;
+            });
+        });
+        row[b.len()]
+    };
+    let max = (name.chars().count() / 3).max(1);
+    // On ties, the last candidate wins: later definitions are closer to the user's code.
+    candidates.filter(|candidate| *candidate != name)
+        .map(|candidate| (distance(name, candidate), candidate))
+        .filter(|(d, _)| *d <= max)
+        .max_by_key(|(d, _)| std::cmp::Reverse(*d))
+        .map(|(_, candidate)| candidate.to_string())
+}
+
+// Where the line of `source` a span is on starts.
+fn line_start(span: Span, source: &str) -> Option<usize> {
+    Some(source.get(..span.start)?.rfind('\n').map_or(0, |idx| idx + 1))
+}
+// The line of `source` some spans are on, shown once with each span underlined below it.
+fn underline(spans: &[(Span, &str)], source: &str) -> Option<String> {
+    let (first, _) = spans.first()?;
+    let start = line_start(*first, source)?;
+    let end = source.get(first.start..)?.find('\n').map_or(source.len(), |idx| first.start + idx);
+    let text = source[start..end].trim_end_matches('\r');
+    let gutter = " ".repeat(first.line.to_string().len());
+    let marks = spans.iter().map(|(span, message)| {
+        let padding = source[start..span.start].chars().map(|c| if c == '\t' { '\t' } else { ' ' }).collect::<String>();
+        let width = source.get(span.start..span.end.min(end)).map_or(1, |s| s.chars().count().max(1));
+        let message = if message.is_empty() { String::new() } else { format!(" {}", message) };
+        format!("\n{} | {}{}{}", gutter, padding, "^".repeat(width), message)
+    }).collect::<String>();
+    Some(format!("{} |\n{} | {}{}", gutter, first.line, text, marks))
+}
+
+impl Diagnostic {
+    pub fn new(severity: Severity, file: Option<String>, location: Option<impl Location>, message: impl ToString) -> Self {
+        let (line, span) = match location.map(Location::locate) {
+            Some((line, span)) => (Some(line), span),
+            None => (None, None),
+        };
+        Self {
+            severity,
+            code: None,
+            file,
+            line,
+            message: message.to_string(),
+            span,
+            labels: vec![],
+            notes: vec![],
+            suggestions: vec![],
+            backtrace: vec![],
+        }
+    }
+    // The lines of `source` the diagnostic and its labels are on, each shown once with the spans on it underlined and followed by their messages.
+    pub fn snippets(&self, source: &str) -> Vec<String> {
+        let mut lines: Vec<(usize, Vec<(Span, &str)>)> = vec![];
+        let spans = self.span.map(|span| (span, "")).into_iter().chain(self.labels.iter().map(|label| (label.span, label.message.as_str())));
+        spans.filter_map(|(span, message)| Some((line_start(span, source)?, span, message))).for_each(|(start, span, message)| {
+            match lines.iter_mut().find(|(line, _)| *line == start) {
+                Some((_, spans)) => spans.push((span, message)),
+                None => lines.push((start, vec![(span, message)])),
+            }
+        });
+        lines.iter().filter_map(|(_, spans)| underline(spans, source)).collect()
+    }
+    pub fn to_json(&self) -> String {
+        let list = |items: Vec<String>| format!("[{}]", items.join(","));
+        let labels = self.labels.iter().map(|label| format!("{{\"span\":{},\"message\":{}}}", json_span(Some(label.span)), json_string(&label.message))).collect();
+        let suggestions = self.suggestions.iter().map(|suggestion| {
+            let replacement = match &suggestion.replacement {
+                Some((span, code)) => format!("{{\"span\":{},\"code\":{}}}", json_span(Some(*span)), json_string(code)),
+                None => "null".to_string(),
+            };
+            format!("{{\"message\":{},\"replacement\":{}}}", json_string(&suggestion.message), replacement)
+        }).collect();
+        format!(
+            "{{\"severity\":{},\"code\":{},\"message\":{},\"file\":{},\"line\":{},\"span\":{},\"labels\":{},\"notes\":{},\"suggestions\":{},\"backtrace\":{}}}",
+            json_string(self.severity.name()),
+            self.code.map_or("null".to_string(), json_string),
+            json_string(&self.message),
+            self.file.as_deref().map_or("null".to_string(), json_string),
+            self.line.map_or("null".to_string(), |line| line.to_string()),
+            json_span(self.span),
+            list(labels),
+            list(self.notes.iter().map(|note| json_string(note)).collect()),
+            list(suggestions),
+            list(self.backtrace.iter().map(|call| json_string(call)).collect()),
+        )
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut to_ret = String::from("\"");
+    s.chars().for_each(|c| match c {
+        '"' => to_ret.push_str("\\\""),
+        '\\' => to_ret.push_str("\\\\"),
+        '\n' => to_ret.push_str("\\n"),
+        '\r' => to_ret.push_str("\\r"),
+        '\t' => to_ret.push_str("\\t"),
+        c if (c as u32) < 0x20 => to_ret.push_str(&format!("\\u{:04x}", c as u32)),
+        c => to_ret.push(c),
+    });
+    to_ret.push('"');
+    to_ret
+}
+fn json_span(span: Option<Span>) -> String {
+    match span {
+        Some(span) => format!("{{\"start\":{},\"end\":{},\"line\":{},\"column\":{}}}", span.start, span.end, span.line, span.column),
+        None => "null".to_string(),
+    }
+}
+
+impl From<Diagnostic> for OrionError {
+    fn from(diagnostic: Diagnostic) -> Self {
+        Self(vec![diagnostic])
+    }
+}
+impl Deref for OrionError {
+    type Target = Diagnostic;
+    fn deref(&self) -> &Diagnostic {
+        &self.0[0]
+    }
+}
+impl DerefMut for OrionError {
+    fn deref_mut(&mut self) -> &mut Diagnostic {
+        &mut self.0[0]
+    }
+}
+impl OrionError {
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        &self.0
+    }
+    // Gathers the diagnostics of several errors, if there is any.
+    pub fn merge(errors: Vec<OrionError>) -> Option<OrionError> {
+        let diagnostics = errors.into_iter().flat_map(|e| e.0).collect::<Vec<_>>();
+        if diagnostics.is_empty() {
+            None
+        } else {
+            Some(Self(diagnostics))
+        }
+    }
+    pub fn code(mut self, code: &'static str) -> Self {
+        self.0[0].code = Some(code);
+        self
+    }
+    pub fn label(mut self, span: Span, message: impl ToString) -> Self {
+        self.0[0].labels.push(Label { span, message: message.to_string() });
+        self
+    }
+    pub fn note(mut self, note: impl ToString) -> Self {
+        self.0[0].notes.push(note.to_string());
+        self
+    }
+    pub fn suggest(mut self, message: impl ToString, replacement: Option<(Span, String)>) -> Self {
+        self.0[0].suggestions.push(Suggestion { message: message.to_string(), replacement });
+        self
+    }
+}
+
+// Errors may be given a code, before their location: `error!(E0001; file, span => "...")`.
 #[macro_export]
 macro_rules! error {
+    ($code:ident; $($file:expr, $line:expr)? => $($arg:tt)*) => {
+        $crate::error!($($file, $line)? => $($arg)*).map_err(|e: $crate::errors::OrionError| e.code(stringify!($code)))
+    };
     ($($file:expr, $line:expr)? => $($arg:tt)*) => {
         {
             let _file: std::option::Option<std::string::String> = std::option::Option::None;
@@ -33,8 +274,40 @@ macro_rules! error {
                 let _file = std::option::Option::Some($file.to_string());
                 let _line = std::option::Option::Some($line);
               )?
-                std::result::Result::Err(crate::errors::OrionError(_file, _line, format_args!($($arg)*).to_string()))
-
+                std::result::Result::Err($crate::errors::OrionError::from($crate::errors::Diagnostic::new($crate::errors::Severity::Error, _file, _line, format_args!($($arg)*))))
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn snippet() {
+        let source = "(def x 1)\n(def\ty (+ x 2.5))\n";
+        let span = Span { start: 22, end: 25, line: 2, column: 13 };
+        let e = OrionError::from(Diagnostic::new(Severity::Error, None, Some(span), ""));
+        assert_eq!(e.snippets(source), vec!["  |\n2 | (def\ty (+ x 2.5))\n  |     \t       ^^^"]);
+        let e = e.label(Span { start: 13, end: 14, line: 2, column: 4 }, "here").label(Span { start: 5, end: 6, line: 1, column: 6 }, "there");
+        assert_eq!(e.snippets(source), vec![
+            "  |\n2 | (def\ty (+ x 2.5))\n  |     \t       ^^^\n  |    ^ here",
+            "  |\n1 | (def x 1)\n  |      ^ there",
+        ]);
+        assert!(Diagnostic::new(Severity::Error, None, Some(2), "").snippets(source).is_empty());
+    }
+
+    #[test]
+    fn json() {
+        let span = Span { start: 1, end: 4, line: 1, column: 2 };
+        let e = OrionError::from(Diagnostic::new(Severity::Warning, Some("a.orn".to_string()), Some(span), "Bad \"x\"\n."))
+            .code("W0001")
+            .note("tab\there");
+        assert_eq!(e.to_json(), r#"{"severity":"warning","code":"W0001","message":"Bad \"x\"\n.","file":"a.orn","line":1,"span":{"start":1,"end":4,"line":1,"column":2},"labels":[],"notes":["tab\there"],"suggestions":[],"backtrace":[]}"#);
+        let e: Result<()> = error!(E0002; "b.orn", 3 => "Oops.");
+        let e = e.unwrap_err();
+        assert_eq!(e.to_json(), r#"{"severity":"error","code":"E0002","message":"Oops.","file":"b.orn","line":3,"span":null,"labels":[],"notes":[],"suggestions":[],"backtrace":[]}"#);
+        assert_eq!(OrionError::merge(vec![e, OrionError::from(Diagnostic::new(Severity::Error, None, None::<usize>, "Again."))]).map(|e| e.diagnostics().len()), Some(2));
+        assert!(OrionError::merge(vec![]).is_none());
+    }
+}
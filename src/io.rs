@@ -25,20 +25,20 @@ impl <const STACK_SIZE: usize> VM<STACK_SIZE> {
     pub fn put_str(&mut self) -> Result<Rc<Value>> {
         let to_print = self.pop()?;
 
-        match (*to_print).clone() {
+        match &*to_print {
             Value::String(s) => {
                 print!("{}", s);
                 io::stdout().flush().unwrap();
                 Ok(Rc::new(Value::Tuple(vec![])))
             },
-            _ => error!(=> "Expected a String, found a {}.", self.val_type(&*to_print)?)
+            _ => error!(E0008; => "Expected a String, found a {}.", self.val_type(&to_print)?)
         }
     }
     pub fn get_line(&mut self) -> Result<Rc<Value>> {
         let mut buffer = String::new();
         match io::stdin().read_line(&mut buffer) {
             Ok(_) => Ok(Rc::new(Value::String(buffer.trim().to_string()))),
-            Err(_) => error!(=> "Failed to get line from user input."),
+            Err(_) => error!(E0008; => "Failed to get line from user input."),
         }
     }
 }
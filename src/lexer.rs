@@ -20,6 +20,9 @@
  */
 use crate::{error, Result};
 
+// The characters ending an identifier.
+const STOP: [char; 6] = ['(', ')', ' ', '\t', '\n', '\r'];
+
 #[derive(Clone, PartialEq, Debug)]
 pub enum TType {
     LParen,
@@ -34,13 +37,21 @@ pub enum TType {
     Float(f32),
     Ident(String),
     Quote,
+    Quasiquote,
+    Unquote,
+    Splice,
     Def,
     Enum,
     Tuple,
     Lambda,
     Match,
     Load,
+    Module,
+    Import,
     Begin,
+    Let,
+    LetStar,
+    LetRec,
 
     Builtin(String),
 }
@@ -52,6 +63,8 @@ impl TType {
             Self::RParen => "Closing Parenthese",
             Self::LBrace => "Opening Brace",
             Self::RBrace => "Closing Brace",
+            Self::LBracket => "Opening Bracket",
+            Self::RBracket => "Closing Bracket",
             Self::Str(_) => "String",
             Self::Number(_) => "Integer",
             Self::Float(_) => "Float",
@@ -62,24 +75,46 @@ impl TType {
     }
 }
 
+// A range of bytes in the source, with the line and the column (in characters) it starts at.
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Span {
+    // The span going from the start of `self` to the end of `other`.
+    pub fn to(self, other: Span) -> Self {
+        Self {
+            end: other.end.max(self.start),
+            ..self
+        }
+    }
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub struct Token {
-    pub line: usize,
+    pub span: Span,
     pub ttype: TType,
 }
 
 impl Token {
-    pub fn new(ttype: TType, line: usize) -> Self {
-        Self { line, ttype }
+    pub fn new(ttype: TType, span: Span) -> Self {
+        Self { span, ttype }
     }
 }
 
 pub struct Lexer {
-    input: String,
+    input: Vec<char>,
     output: Vec<Token>,
     current: usize,
     line: usize,
     start: usize,
+    offset: usize, // The byte offset of `current`.
+    line_start: usize, // The index of the first character of the line.
+    token: Span, // Where the current token starts.
     builtins: Vec<String>,
     file: String,
 }
@@ -87,11 +122,14 @@ pub struct Lexer {
 impl Lexer {
     pub fn new(input: impl ToString, file: impl ToString) -> Self {
         Self {
-            input: input.to_string().replace("λ", "\\").to_string(),
+            input: input.to_string().chars().collect(),
             output: vec![],
             current: 0,
             line: 1,
             start: 0,
+            offset: 0,
+            line_start: 0,
+            token: Span::default(),
             file: file.to_string(),
             builtins: vec![],
         }
@@ -103,38 +141,52 @@ impl Lexer {
             current: self.current,
             line,
             start: self.start,
+            offset: self.offset,
+            line_start: self.line_start,
+            token: self.token,
             file: self.file,
             builtins: self.builtins,
         }
     }
     fn is_at_end(&self) -> bool {
-        self.current >= self.input.chars().count()
+        self.current >= self.input.len()
     }
     fn peek(&self) -> char {
-        self.input.chars().nth(self.current).unwrap()
+        self.input[self.current]
     }
     fn advance(&mut self) -> char {
+        let c = self.input[self.current];
         self.current += 1;
-        self.input.chars().nth(self.current - 1).unwrap()
+        self.offset += c.len_utf8();
+        if c == '\n' {
+            self.line += 1;
+            self.line_start = self.current;
+        }
+        c
     }
     fn add_token(&mut self, ttype: TType) {
-        self.output.push(Token::new(ttype, self.line));
+        let span = Span {
+            end: self.offset,
+            ..self.token
+        };
+        self.output.push(Token::new(ttype, span));
     }
     fn string(&mut self) -> Result<()> {
         while !self.is_at_end() && self.peek() != '"' {
-            if self.peek() == '\n' {
-                self.line += 1;
-            }
             self.advance();
         }
         if self.is_at_end() {
-            return error!(self.file, self.line => "Unterminated string.");
+            let span = Span {
+                end: self.offset,
+                ..self.token
+            };
+            return error!(E0001; self.file, span => "Unterminated string.");
         }
 
         self.advance(); // Closing double quotes
 
         self.add_token(TType::Str(apply_ansi_codes(
-            &self.input[self.start + 1..self.current - 1], // Remove opening and closing double quotes
+            &self.input[self.start + 1..self.current - 1].iter().collect::<String>(), // Remove opening and closing double quotes
         )));
 
         Ok(())
@@ -149,9 +201,15 @@ impl Lexer {
             ']' => self.add_token(TType::RBracket),
             '{' => self.add_token(TType::LBrace),
             '}' => self.add_token(TType::RBrace),
-            ' ' | '\r' | '\t' => {}
-            '\n' => self.line += 1,
+            ' ' | '\r' | '\t' | '\n' => {}
             '\'' => self.add_token(TType::Quote),
+            '`' => self.add_token(TType::Quasiquote),
+            ',' if !self.is_at_end() && self.peek() == '@' => {
+                self.advance();
+                self.add_token(TType::Splice);
+            }
+            // A lone comma is the tuple keyword.
+            ',' if !self.is_at_end() && !STOP.contains(&self.peek()) => self.add_token(TType::Unquote),
             '"' => self.string()?,
             '#' => {
                 if !self.is_at_end() && self.peek() == '!' && self.line == 1 {
@@ -161,11 +219,9 @@ impl Lexer {
                 } else if !self.is_at_end() && self.peek() == '|' {
                     self.advance();
                     while !self.is_at_end() {
-                        if self.peek() == '|' {
-                            if !self.is_at_end() && self.peek() == '#' {
-                                self.advance();
-                                break;
-                            }
+                        if self.peek() == '|' && !self.is_at_end() && self.peek() == '#' {
+                            self.advance();
+                            break;
                         }
                         self.advance();
                     }
@@ -179,7 +235,7 @@ impl Lexer {
                 }
             }
             _ => {
-                if c.is_digit(10) {
+                if c.is_ascii_digit() {
                     self.number();
                 } else {
                     self.identifier();
@@ -193,7 +249,7 @@ impl Lexer {
         self.builtins.push(builtin.to_string());
     }
     fn number(&mut self) {
-        while !self.is_at_end() && self.peek().is_digit(10) {
+        while !self.is_at_end() && self.peek().is_ascii_digit() {
             self.advance();
         }
 
@@ -201,11 +257,11 @@ impl Lexer {
             self.advance(); // Decimal part delimiter
         }
 
-        while !self.is_at_end() && self.peek().is_digit(10) {
+        while !self.is_at_end() && self.peek().is_ascii_digit() {
             self.advance();
         }
 
-        let raw = self.input[self.start..self.current].to_string();
+        let raw = self.input[self.start..self.current].iter().collect::<String>();
 
         match raw.parse::<i32>() {
             Ok(i) => self.add_token(TType::Number(i)),
@@ -213,13 +269,11 @@ impl Lexer {
         }
     }
     fn identifier(&mut self) {
-        let stop = vec!['(', ')', ' ', '\t', '\n', '\r'];
-
-        while !self.is_at_end() && !stop.contains(&self.peek()) {
+        while !self.is_at_end() && !STOP.contains(&self.peek()) {
             self.advance();
         }
 
-        let raw = self.input[self.start..self.current].to_string();
+        let raw = self.input[self.start..self.current].iter().collect::<String>();
 
         if self.builtins.contains(&raw) {
             self.add_token(TType::Builtin(raw));
@@ -228,11 +282,16 @@ impl Lexer {
                 "def" => self.add_token(TType::Def),
                 "macro" => self.add_token(TType::Macro),
                 "enum" => self.add_token(TType::Enum),
-                "\\" => self.add_token(TType::Lambda),
+                "\\" | "λ" => self.add_token(TType::Lambda),
                 "," => self.add_token(TType::Tuple),
                 "match" => self.add_token(TType::Match),
                 "load" => self.add_token(TType::Load),
+                "module" => self.add_token(TType::Module),
+                "import" => self.add_token(TType::Import),
                 "begin" => self.add_token(TType::Begin),
+                "let" => self.add_token(TType::Let),
+                "let*" => self.add_token(TType::LetStar),
+                "letrec" => self.add_token(TType::LetRec),
                 _ => self.add_token(TType::Ident(raw)),
             }
         }
@@ -264,6 +323,12 @@ impl Lexer {
         self.register_builtin("atan");
 
         while !self.is_at_end() {
+            self.token = Span {
+                start: self.offset,
+                end: self.offset,
+                line: self.line,
+                column: self.current - self.line_start + 1,
+            };
             self.proc_token()?;
             self.start = self.current;
         }
@@ -291,6 +356,15 @@ mod test {
         input.into_iter().map(|t| t.ttype).collect::<Vec<_>>()
     }
 
+    #[test]
+    fn spans() -> Result<()> {
+        let spans = Lexer::new("(def été\n  \"a\nb\" 4)", "").proc_tokens()?.into_iter().map(|t| t.span).collect::<Vec<_>>();
+        assert_eq!(spans[2], Span { start: 5, end: 10, line: 1, column: 6 });
+        assert_eq!(spans[3], Span { start: 13, end: 18, line: 2, column: 3 });
+        assert_eq!(spans[4], Span { start: 19, end: 20, line: 3, column: 4 });
+        Ok(())
+    }
+
     #[test]
     fn parentheses() -> Result<()> {
         let ttypes = get_ttypes(Lexer::new("()", "").proc_tokens()?);
@@ -313,6 +387,7 @@ mod test {
     }
 
     #[test]
+    #[allow(clippy::approx_constant, clippy::excessive_precision)]
     fn numbers() -> Result<()> {
         let ttypes = get_ttypes(Lexer::new("42 3.1415926535897932", "").proc_tokens()?);
         assert_eq!(
@@ -329,6 +404,13 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn unicode() -> Result<()> {
+        let ttypes = get_ttypes(Lexer::new(r#""àéλ" été"#, "").proc_tokens()?);
+        assert_eq!(ttypes, vec![TType::Str("àéλ".to_string()), TType::Ident("été".to_string())]);
+        Ok(())
+    }
+
     #[test]
     fn def() -> Result<()> {
         let ttypes = get_ttypes(Lexer::new("def", "").proc_tokens()?);
@@ -0,0 +1,432 @@
+/*
+ *  Copyright (C) 2021, Wafelack <wafelack@protonmail.com>
+ *
+ *  ------------------------------------------------------
+ *
+ *     This file is part of Orion.
+ *
+ *  Orion is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  Orion is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with Orion.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::{
+    bug, error,
+    lexer::Span,
+    parser::{Binding, Expr, ExprT, MacroClause, Pattern},
+    Result,
+};
+
+// The amount of expansions a call may be nested in, past which the macro is deemed to expand forever.
+pub const MAX_EXPANSION_DEPTH: usize = 128;
+
+#[derive(Clone, Debug)]
+pub struct Macro {
+    pub clauses: Vec<Clause>, // Tried in order, the first one matching the arguments being expanded.
+}
+
+#[derive(Clone, Debug)]
+pub struct Clause {
+    pub args: Vec<String>, // The arguments written `'name` only match the identifier `name`.
+    pub rest: Option<String>, // Given a tuple of the arguments following the other ones.
+    pub template: Expr, // The quasiquoted body, without the quasiquote.
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Use {
+    Value,
+    Splice,
+    Binder,
+}
+
+impl Macro {
+    pub fn new(clauses: Vec<MacroClause>, file: &str) -> Result<Self> {
+        clauses.into_iter().map(|clause| Clause::new(clause, file)).collect::<Result<Vec<_>>>().map(|clauses| Self { clauses })
+    }
+    // The code of a call to the macro written at `span`. The arguments unquoted several times are
+    // evaluated once, before the expansion, and the binders of the template are renamed to fresh
+    // names, so that they cannot capture the variables of the arguments. `depth` is the amount of
+    // expansions the call comes from.
+    pub fn expand(&self, args: Vec<Expr>, span: Span, file: &str, fresh: &mut usize, depth: usize) -> Result<Expr> {
+        if depth >= MAX_EXPANSION_DEPTH {
+            return error!(E0010; file, span => "Macro expansions nested too deeply, more than {}.", MAX_EXPANSION_DEPTH);
+        }
+        let clause = match self.clauses.iter().find(|clause| clause.matches(&args)) {
+            Some(clause) => clause,
+            None => return match &self.clauses[..] {
+                [clause] if !clause.args.iter().any(|arg| arg.starts_with('\'')) => error!(E0003;
+                    file,
+                    span =>
+                    "Expected {}{} arguments, found {}.",
+                    if clause.rest.is_some() { "at least " } else { "" },
+                    clause.args.len(),
+                    args.len()
+                    ),
+                _ => error!(E0003; file, span => "No clause of the macro matches these {} arguments.", args.len()),
+            },
+        };
+        let mut args = args.into_iter();
+        let mut given = clause.args.iter().zip(args.by_ref()).filter(|(name, _)| !name.starts_with('\'')).map(|(name, arg)| (name.clone(), arg)).collect::<Vec<_>>();
+        if let Some(rest) = &clause.rest {
+            given.push((rest.clone(), Expr::new(ExprT::Tuple(args.collect())).span(span)));
+        }
+        let mut uses = vec![];
+        unquoted(&clause.template, &mut uses);
+        let mut bound = vec![];
+        for (name, arg) in &given {
+            let count = uses.iter().filter(|(used, _, kind)| used == name && *kind == Use::Value).count();
+            if count > 1 && !matches!(arg.exprt, ExprT::Var(_) | ExprT::Literal(_)) {
+                bound.push((name.clone(), gensym(name, fresh), arg.clone()));
+            }
+        }
+        let mut expansion = Expansion {
+            args: given,
+            bound: bound.iter().map(|(name, var, _)| (name.clone(), Expr::new(ExprT::Var(var.clone())).span(span))).collect(),
+            renamed: vec![],
+            fresh,
+            span,
+            file,
+        };
+        let body = expansion.expr(&clause.template)?;
+        Ok(bound.into_iter().rev().fold(body, |body, (_, var, value)| {
+            Expr::new(ExprT::Match(Box::new(value), vec![(Pattern::Var(var), span, body)])).span(span)
+        }))
+    }
+}
+
+impl Clause {
+    // A body that is not quasiquoted has all the occurrences of the arguments unquoted.
+    fn new((args, rest, body): MacroClause, file: &str) -> Result<Self> {
+        let names = args.iter().filter(|arg| !arg.starts_with('\'')).chain(&rest).cloned().collect::<Vec<_>>();
+        let template = match body.exprt {
+            ExprT::Quasiquote(template) => *template,
+            _ => unquote(&body, &names),
+        };
+        let mut uses = vec![];
+        unquoted(&template, &mut uses);
+        match uses.iter().find(|(name, ..)| !names.contains(name)) {
+            Some((name, span, _)) => error!(E0002; file, *span => "Not an argument of the macro: {}.", name),
+            None => Ok(Self { args, rest, template }),
+        }
+    }
+    fn matches(&self, args: &[Expr]) -> bool {
+        (args.len() == self.args.len() || (self.rest.is_some() && args.len() > self.args.len()))
+            && self.args.iter().zip(args).all(|(param, arg)| match param.strip_prefix('\'') {
+                Some(keyword) => matches!(&arg.exprt, ExprT::Var(name) if name == keyword),
+                None => true,
+            })
+    }
+}
+
+fn gensym(name: &str, fresh: &mut usize) -> String {
+    *fresh += 1;
+    format!("{}#{}", name, fresh)
+}
+
+// The template of a body that is not quasiquoted.
+fn unquote(expr: &Expr, args: &[String]) -> Expr {
+    let all = |exprs: &[Expr]| exprs.iter().map(|e| unquote(e, args)).collect();
+    let binder = |name: &String| if args.contains(name) { format!(",{}", name) } else { name.clone() };
+    let exprt = match &expr.exprt {
+        ExprT::Var(name) if args.contains(name) => ExprT::Unquote(name.clone()),
+        ExprT::Call(f, exprs) => ExprT::Call(Box::new(unquote(f, args)), all(exprs)),
+        ExprT::Lambda(params, body) => ExprT::Lambda(params.iter().map(binder).collect(), Box::new(unquote(body, args))),
+        ExprT::Def(name, value, impure, signature) => ExprT::Def(binder(name), Box::new(unquote(value, args)), *impure, signature.clone()),
+        ExprT::Constr(name, exprs) => ExprT::Constr(name.clone(), all(exprs)),
+        ExprT::Tuple(exprs) => ExprT::Tuple(all(exprs)),
+        ExprT::Begin(exprs) => ExprT::Begin(all(exprs)),
+        ExprT::Builtin(name, exprs) => ExprT::Builtin(name.clone(), all(exprs)),
+        ExprT::Let(binding, bindings, body) => ExprT::Let(
+            *binding,
+            bindings.iter().map(|(name, value)| (binder(name), unquote(value, args))).collect(),
+            Box::new(unquote(body, args)),
+        ),
+        ExprT::Match(value, arms) => ExprT::Match(
+            Box::new(unquote(value, args)),
+            arms.iter().map(|(pat, span, body)| (unquote_pattern(pat, args), *span, unquote(body, args))).collect(),
+        ),
+        exprt => exprt.clone(),
+    };
+    Expr { exprt, ..expr.clone() }
+}
+fn unquote_pattern(pattern: &Pattern, args: &[String]) -> Pattern {
+    match pattern {
+        Pattern::Var(name) if args.contains(name) => Pattern::Var(format!(",{}", name)),
+        Pattern::Constr(name, pats) => Pattern::Constr(name.clone(), pats.iter().map(|p| unquote_pattern(p, args)).collect()),
+        Pattern::Tuple(pats) => Pattern::Tuple(pats.iter().map(|p| unquote_pattern(p, args)).collect()),
+        pattern => pattern.clone(),
+    }
+}
+
+// The arguments unquoted in a template, and how they are used.
+fn unquoted(expr: &Expr, uses: &mut Vec<(String, Span, Use)>) {
+    let binder = |name: &String, uses: &mut Vec<(String, Span, Use)>| {
+        if let Some(arg) = name.strip_prefix(',') {
+            uses.push((arg.to_string(), expr.span, Use::Binder));
+        }
+    };
+    match &expr.exprt {
+        ExprT::Unquote(name) => uses.push((name.clone(), expr.span, Use::Value)),
+        ExprT::Splice(name) => uses.push((name.clone(), expr.span, Use::Splice)),
+        ExprT::Call(f, exprs) => {
+            unquoted(f, uses);
+            exprs.iter().for_each(|e| unquoted(e, uses));
+        }
+        ExprT::Lambda(params, body) => {
+            params.iter().for_each(|param| binder(param, uses));
+            unquoted(body, uses);
+        }
+        ExprT::Def(name, value, ..) => {
+            binder(name, uses);
+            unquoted(value, uses);
+        }
+        ExprT::Constr(_, exprs) | ExprT::Tuple(exprs) | ExprT::Begin(exprs) | ExprT::Builtin(_, exprs) => exprs.iter().for_each(|e| unquoted(e, uses)),
+        ExprT::Let(_, bindings, body) => {
+            bindings.iter().for_each(|(name, value)| {
+                binder(name, uses);
+                unquoted(value, uses);
+            });
+            unquoted(body, uses);
+        }
+        ExprT::Match(value, arms) => {
+            unquoted(value, uses);
+            arms.iter().for_each(|(pat, span, body)| {
+                let mut vars = vec![];
+                pattern_vars(pat, &mut vars);
+                uses.extend(vars.into_iter().filter_map(|var| var.strip_prefix(',').map(|arg| (arg.to_string(), *span, Use::Binder))));
+                unquoted(body, uses);
+            });
+        }
+        _ => {}
+    }
+}
+fn pattern_vars(pattern: &Pattern, vars: &mut Vec<String>) {
+    match pattern {
+        Pattern::Var(name) => vars.push(name.clone()),
+        Pattern::Constr(_, pats) | Pattern::Tuple(pats) => pats.iter().for_each(|p| pattern_vars(p, vars)),
+        Pattern::Literal(_) => {}
+    }
+}
+
+// The pattern an argument stands for, when it is unquoted in a pattern.
+fn pattern(expr: &Expr, file: &str) -> Result<Pattern> {
+    let all = |exprs: &[Expr]| exprs.iter().map(|e| pattern(e, file)).collect::<Result<Vec<_>>>();
+    Ok(match &expr.exprt {
+        ExprT::Var(name) => Pattern::Var(name.clone()),
+        ExprT::Literal(literal) => Pattern::Literal(literal.clone()),
+        ExprT::Constr(name, exprs) => Pattern::Constr(name.clone(), all(exprs)?),
+        ExprT::Tuple(exprs) => Pattern::Tuple(all(exprs)?),
+        _ => return error!(E0001; file, expr.span => "Expected a pattern, as this argument is unquoted in a pattern."),
+    })
+}
+
+struct Expansion<'a> {
+    args: Vec<(String, Expr)>,
+    bound: Vec<(String, Expr)>, // The variables holding the arguments evaluated before the expansion.
+    renamed: Vec<(String, String)>, // (name, fresh name) of the binders of the template in scope.
+    fresh: &'a mut usize,
+    span: Span,
+    file: &'a str,
+}
+
+impl Expansion<'_> {
+    fn arg(&self, name: &str) -> &Expr {
+        match self.args.iter().find(|(arg, _)| arg == name) {
+            Some((_, expr)) => expr,
+            None => bug!("UNKNOWN_MACRO_ARGUMENT"),
+        }
+    }
+    fn value(&self, name: &str) -> Expr {
+        match self.bound.iter().find(|(arg, _)| arg == name) {
+            Some((_, var)) => var.clone(),
+            None => self.arg(name).clone(),
+        }
+    }
+    // The name of a binder of the template, an identifier given as argument or a fresh name.
+    fn binder(&mut self, name: &str) -> Result<String> {
+        match name.strip_prefix(',') {
+            Some(arg) => match &self.arg(arg).exprt {
+                ExprT::Var(var) => Ok(var.clone()),
+                _ => error!(E0001; self.file, self.arg(arg).span => "Expected an identifier, as this argument is unquoted in a binder."),
+            },
+            None => {
+                let fresh = gensym(name, self.fresh);
+                self.renamed.push((name.to_string(), fresh.clone()));
+                Ok(fresh)
+            }
+        }
+    }
+    fn expr(&mut self, expr: &Expr) -> Result<Expr> {
+        let span = self.span;
+        let exprt = match &expr.exprt {
+            ExprT::Unquote(name) => return Ok(self.value(name)),
+            ExprT::Splice(_) => return error!(E0001; self.file, span => "Splices can only be used in a list of expressions."),
+            ExprT::Var(name) => ExprT::Var(self.renamed.iter().rev().find(|(old, _)| old == name).map_or(name.clone(), |(_, new)| new.clone())),
+            ExprT::Call(f, exprs) => ExprT::Call(Box::new(self.expr(f)?), self.all(exprs)?),
+            ExprT::Lambda(params, body) => {
+                let len = self.renamed.len();
+                let params = params.iter().map(|param| self.binder(param)).collect::<Result<Vec<_>>>()?;
+                let body = self.expr(body)?;
+                self.renamed.truncate(len);
+                ExprT::Lambda(params, Box::new(body))
+            }
+            // The definitions keep their name, a macro may be used to define variables.
+            ExprT::Def(name, value, impure, signature) => {
+                let name = if name.starts_with(',') {
+                    self.binder(name)?
+                } else {
+                    self.renamed.push((name.clone(), name.clone()));
+                    name.clone()
+                };
+                ExprT::Def(name, Box::new(self.expr(value)?), *impure, signature.clone())
+            }
+            ExprT::Constr(name, exprs) => ExprT::Constr(name.clone(), self.all(exprs)?),
+            ExprT::Tuple(exprs) => ExprT::Tuple(self.all(exprs)?),
+            ExprT::Builtin(name, exprs) => ExprT::Builtin(name.clone(), self.all(exprs)?),
+            ExprT::Begin(exprs) => {
+                let len = self.renamed.len();
+                let exprs = self.all(exprs)?;
+                self.renamed.truncate(len);
+                ExprT::Begin(exprs)
+            }
+            // The variables are renamed where they come into scope.
+            ExprT::Let(binding, bindings, body) => {
+                let len = self.renamed.len();
+                let mut names = vec![];
+                if *binding == Binding::Recursive {
+                    names = bindings.iter().map(|(name, _)| self.binder(name)).collect::<Result<Vec<_>>>()?;
+                }
+                let mut values = vec![];
+                for (name, value) in bindings {
+                    values.push(self.expr(value)?);
+                    if *binding == Binding::Sequential {
+                        names.push(self.binder(name)?);
+                    }
+                }
+                if *binding == Binding::Parallel {
+                    names = bindings.iter().map(|(name, _)| self.binder(name)).collect::<Result<Vec<_>>>()?;
+                }
+                let body = self.expr(body)?;
+                self.renamed.truncate(len);
+                ExprT::Let(*binding, names.into_iter().zip(values).collect(), Box::new(body))
+            }
+            ExprT::Match(value, arms) => {
+                let value = self.expr(value)?;
+                let arms = arms.iter().map(|(pat, _, body)| {
+                    let len = self.renamed.len();
+                    let pat = self.pattern(pat)?;
+                    let body = self.expr(body)?;
+                    self.renamed.truncate(len);
+                    Ok((pat, span, body))
+                }).collect::<Result<Vec<_>>>()?;
+                ExprT::Match(Box::new(value), arms)
+            }
+            ExprT::Quasiquote(_) => bug!("NESTED_QUASIQUOTE"),
+            _ => return Ok(expr.relocate(span)),
+        };
+        Ok(Expr::new(exprt).span(span))
+    }
+    // The expressions of a list, with the elements of the spliced arguments.
+    fn all(&mut self, exprs: &[Expr]) -> Result<Vec<Expr>> {
+        let mut all = vec![];
+        for expr in exprs {
+            match &expr.exprt {
+                ExprT::Splice(name) => match &self.arg(name).exprt {
+                    ExprT::Tuple(values) | ExprT::Begin(values) => all.extend(values.iter().cloned()),
+                    _ => return error!(E0001; self.file, self.arg(name).span => "Expected a tuple or a block, as this argument is spliced."),
+                },
+                _ => all.push(self.expr(expr)?),
+            }
+        }
+        Ok(all)
+    }
+    fn pattern(&mut self, pat: &Pattern) -> Result<Pattern> {
+        let mut all = |pats: &[Pattern]| pats.iter().map(|p| self.pattern(p)).collect::<Result<Vec<_>>>();
+        Ok(match pat {
+            Pattern::Var(name) if name == "_" => pat.clone(),
+            Pattern::Var(name) => match name.strip_prefix(',') {
+                Some(arg) => pattern(self.arg(arg), self.file)?,
+                None => Pattern::Var(self.binder(name)?),
+            },
+            Pattern::Constr(name, pats) => Pattern::Constr(name.clone(), all(pats)?),
+            Pattern::Tuple(pats) => Pattern::Tuple(all(pats)?),
+            Pattern::Literal(_) => pat.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{lexer::Lexer, parser::Parser};
+
+    const SPAN: Span = Span { start: 0, end: 0, line: 1, column: 1 };
+
+    fn parse(code: &str) -> Vec<Expr> {
+        Parser::new(Lexer::new(code, "TEST").proc_tokens().unwrap(), "TEST").parse().unwrap()
+    }
+    // The spans of the expansion are the one of the call, or the ones of the arguments.
+    fn relocated(code: &str) -> Expr {
+        parse(code).remove(0).relocate(SPAN)
+    }
+    fn expand(definition: &str, call: &str) -> Result<Expr> {
+        let r#macro = match parse(definition).remove(0).exprt {
+            ExprT::Macro(_, clauses) => Macro::new(clauses, "TEST")?,
+            _ => panic!("Expected a macro."),
+        };
+        match parse(call).remove(0).exprt {
+            ExprT::Call(_, args) => r#macro.expand(args.into_iter().map(|arg| arg.relocate(SPAN)).collect(), SPAN, "TEST", &mut 0, 0),
+            _ => panic!("Expected a call."),
+        }
+    }
+
+    #[test]
+    fn hygiene() -> Result<()> {
+        let expanded = expand("(macro my-or (a b) (match a (True True) (x b)))", "(my-or y x)")?;
+        assert_eq!(expanded, relocated("(match y (True True) (x#1 x))"));
+        let expanded = expand("(macro with (name value body) `(match ,value (,name ,body)))", "(with (Just x) (get) x)")?;
+        assert_eq!(expanded, relocated("(match (get) ((Just x) x))"));
+        Ok(())
+    }
+
+    #[test]
+    fn evaluated_once() -> Result<()> {
+        let expanded = expand("(macro double (n) `(+ ,n ,n))", "(double (f 2))")?;
+        assert_eq!(expanded, relocated("(match (f 2) (n#1 (+ n#1 n#1)))"));
+        let expanded = expand("(macro double (n) `(+ ,n ,n))", "(double x)")?;
+        assert_eq!(expanded, relocated("(+ x x)"));
+        Ok(())
+    }
+
+    #[test]
+    fn splice() -> Result<()> {
+        let expanded = expand("(macro call (f args) `(,f ,@args))", "(call g (, 1 2))")?;
+        assert_eq!(expanded, relocated("(g 1 2)"));
+        assert!(expand("(macro call (f args) `(,f ,@args))", "(call g 1)").is_err());
+        assert!(expand("(macro bad (a) `(,b))", "(bad 1)").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn clauses() -> Result<()> {
+        let all = "(macro all (() True) ((x) x) ((x . rest) `(match ,x (True (all ,@rest)) (False False))))";
+        assert_eq!(expand(all, "(all)")?, relocated("True"));
+        assert_eq!(expand(all, "(all a)")?, relocated("a"));
+        assert_eq!(expand(all, "(all a b c)")?, relocated("(match a (True (all b c)) (False False))"));
+        let pick = "(macro pick (('first a b) a) (('second a b) b))";
+        assert_eq!(expand(pick, "(pick second 1 2)")?, relocated("2"));
+        assert_eq!(expand(pick, "(pick third 1 2)").unwrap_err().message, "No clause of the macro matches these 3 arguments.");
+        let tuple = "(macro tuple (x . rest) `(, ,x ,rest))";
+        assert_eq!(expand(tuple, "(tuple 1 2 3)")?, relocated("(, 1 (, 2 3))"));
+        assert_eq!(expand(tuple, "(tuple)").unwrap_err().message, "Expected at least 1 arguments, found 0.");
+        Ok(())
+    }
+}
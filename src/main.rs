@@ -18,11 +18,18 @@
  *  You should have received a copy of the GNU General Public License
  *  along with Orion.  If not, see <https://www.gnu.org/licenses/>.
  */
+mod asm;
 mod bytecode;
 mod compiler;
+mod disasm;
 mod errors;
 mod lexer;
+mod macros;
+mod modules;
+mod matching;
 mod parser;
+mod printer;
+mod types;
 mod vm;
 mod cli;
 
@@ -32,7 +39,8 @@ mod string;
 
 use crate::cli::cli;
 pub use errors::{OrionError, Result};
-use std::process::exit;
+use errors::{Diagnostic, ErrorFormat, Severity};
+use std::{fs, io::{stderr, IsTerminal}, process::exit};
 
 #[macro_export]
 macro_rules! bug {
@@ -45,40 +53,57 @@ macro_rules! bug {
             )
     };
 }
-#[macro_export]
-macro_rules! table {
-    {$($key:expr => $value:expr),+} => {
-                                           {
-                                               let mut map = HashMap::new();
-
-                                               $(
-                                                   map.insert($key, $value);
-                                                )*
-
-                                                   map
-                                           }
-                                       };
+// The location of a diagnostic, and the lines it refers to underlined, the source being read from the file unless given as (file, content).
+fn locate(d: &Diagnostic, source: Option<(&str, &str)>) -> (Option<String>, Vec<String>) {
+    let location = match (&d.file, d.line, d.span) {
+        (Some(file), _, Some(span)) => Some(format!("{}:{}:{}: ", file, span.line, span.column)),
+        (Some(file), Some(line), None) => Some(format!("{}:{}: ", file, line)),
+        _ => None,
+    };
+    let content = match (&d.file, source) {
+        (Some(file), Some((name, content))) if file == name => Some(content.to_string()),
+        (Some(file), _) if d.span.is_some() || !d.labels.is_empty() => fs::read_to_string(file).ok(),
+        _ => None,
+    };
+    let snippets = match content {
+        Some(content) => d.snippets(&content),
+        None => vec![],
+    };
+    (location, snippets)
 }
-fn print_err(e: OrionError) {
-    eprintln!(
-        "{}{}",
-        if e.0.is_some() && e.1.is_some() {
-            format!("{}:{}: ", e.0.unwrap(), e.1.unwrap())
-        } else {
-            if cfg!(windows) {
-                "Error: "
-            } else {
-                "\x1b[0;31mError: \x1b[0m"
-            }.to_string()
-        },
-        e.2);
+fn print_err(e: &OrionError, source: Option<(&str, &str)>, format: ErrorFormat) {
+    e.diagnostics().iter().for_each(|d| {
+        if format == ErrorFormat::Json {
+            eprintln!("{}", d.to_json());
+            return;
+        }
+        let (location, snippets) = locate(d, source);
+        let color = match d.severity {
+            Severity::Error => "\x1b[0;31m",
+            Severity::Warning => "\x1b[0;33m",
+        };
+        let severity = match d.code {
+            Some(code) => format!("{}[{}]", d.severity.name(), code),
+            None => d.severity.name().to_string(),
+        };
+        let severity = if cfg!(windows) || !stderr().is_terminal() { severity } else { format!("{}{}\x1b[0m", color, severity) };
+        eprintln!("{}{}: {}", location.unwrap_or_default(), severity, d.message);
+        snippets.iter().for_each(|snippet| eprintln!("{}", snippet));
+        d.notes.iter().for_each(|note| eprintln!("  = note: {}", note));
+        d.suggestions.iter().for_each(|suggestion| match &suggestion.replacement {
+            Some((_, code)) => eprintln!("  = help: {}: `{}`", suggestion.message, code),
+            None => eprintln!("  = help: {}", suggestion.message),
+        });
+        if !d.backtrace.is_empty() {
+            eprintln!("Backtrace (most recent call first):");
+            d.backtrace.iter().for_each(|call| eprintln!("    {}", call));
+        }
+    })
 }
 fn main() {
-    match cli() {
-        Ok(()) => {}
-        Err(e) => {
-            print_err(e);
-            exit(1);
-        }
+    let (format, result) = cli();
+    if let Err(e) = result {
+        print_err(&e, None, format);
+        exit(1);
     }
 }
@@ -0,0 +1,363 @@
+/*
+ *  Copyright (C) 2021, Wafelack <wafelack@protonmail.com>
+ *
+ *  ------------------------------------------------------
+ *
+ *     This file is part of Orion.
+ *
+ *  Orion is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  Orion is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with Orion.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::bytecode::{BytecodePattern, OpCode};
+use std::collections::HashMap;
+
+// A pattern whose names have been resolved by the compiler.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Pat {
+    Any(Option<u32>),      // (sym_id)
+    Constr(u32, Vec<Pat>), // (constr_idx, fields)
+    Tuple(Vec<Pat>),       // (fields)
+    Literal(u32),          // (const_id)
+}
+impl Pat {
+    fn fields(&self) -> &[Pat] {
+        match self {
+            Self::Constr(_, fields) | Self::Tuple(fields) => fields,
+            _ => &[],
+        }
+    }
+    // Whether the values matched by both patterns are the same, their fields aside.
+    fn same_head(&self, other: &Pat) -> bool {
+        match (self, other) {
+            (Self::Constr(lhs, _), Self::Constr(rhs, _)) => lhs == rhs,
+            (Self::Tuple(lhs), Self::Tuple(rhs)) => lhs.len() == rhs.len(),
+            (Self::Literal(lhs), Self::Literal(rhs)) => lhs == rhs,
+            _ => false,
+        }
+    }
+    pub fn has_constr(&self) -> bool {
+        matches!(self, Self::Constr(..)) || self.fields().iter().any(Pat::has_constr)
+    }
+}
+
+// The local slots holding the matched value and the fields tested by the patterns, the fields of a value
+// taking consecutive slots whatever the arm being tested.
+pub struct Slots {
+    bases: HashMap<u32, u32>, // (slot, first field slot)
+    pub end: u32,
+}
+impl Slots {
+    pub fn new(scrutinee: u32, pats: &[Pat]) -> Self {
+        #[derive(Default)]
+        struct Shape(Vec<Shape>);
+        fn widen(shape: &mut Shape, pat: &Pat) {
+            let fields = pat.fields();
+            if shape.0.len() < fields.len() {
+                shape.0.resize_with(fields.len(), Shape::default);
+            }
+            shape.0.iter_mut().zip(fields).for_each(|(shape, pat)| widen(shape, pat));
+        }
+        fn allocate(shape: &Shape, slot: u32, slots: &mut Slots) {
+            if !shape.0.is_empty() {
+                let base = slots.end;
+                slots.bases.insert(slot, base);
+                slots.end += shape.0.len() as u32;
+                shape.0.iter().enumerate().for_each(|(idx, shape)| allocate(shape, base + idx as u32, slots));
+            }
+        }
+
+        let mut shape = Shape::default();
+        pats.iter().for_each(|pat| widen(&mut shape, pat));
+        let mut slots = Self {
+            bases: HashMap::new(),
+            end: scrutinee + 1,
+        };
+        allocate(&shape, scrutinee, &mut slots);
+        slots
+    }
+    // The slot of each variable bound by a pattern.
+    pub fn bindings(&self, pat: &Pat, slot: u32) -> Vec<(u32, u32)> { // [(sym_id, slot)]
+        match pat {
+            Pat::Any(Some(sym)) => vec![(*sym, slot)],
+            Pat::Any(None) | Pat::Literal(_) => vec![],
+            Pat::Constr(_, fields) | Pat::Tuple(fields) => fields.iter().enumerate().flat_map(|(idx, pat)| self.bindings(pat, self.bases[&slot] + idx as u32)).collect(),
+        }
+    }
+}
+
+// The patterns of an arm that are left to test, with the slot of the value they test.
+#[derive(Clone)]
+struct Row {
+    columns: Vec<(u32, Pat)>,
+    arm: usize,
+}
+impl Row {
+    fn at(&self, slot: u32) -> Option<&Pat> {
+        self.columns.iter().find(|(s, _)| *s == slot).map(|(_, pat)| pat)
+    }
+}
+
+// The decision tree testing the patterns of a match, whose leaves jump to the arms.
+#[derive(Default)]
+pub struct Tree {
+    pub code: Vec<OpCode>,
+    leaves: Vec<(usize, usize)>, // (jump position, arm)
+}
+impl Tree {
+    fn append(&mut self, other: Tree) {
+        let offset = self.code.len();
+        self.leaves.extend(other.leaves.into_iter().map(|(pos, arm)| (pos + offset, arm)));
+        self.code.extend(other.code);
+    }
+    // Lays the arms out after the tree, each one jumping to the end of the match once evaluated.
+    pub fn link(self, arms: Vec<Vec<OpCode>>, scrutinee: u32) -> Vec<OpCode> {
+        let mut starts = vec![];
+        let mut length = self.code.len();
+        arms.iter().for_each(|arm| {
+            starts.push(length);
+            length += arm.len() + 1;
+        });
+        let mut code = self.code;
+        self.leaves.iter().for_each(|(pos, arm)| code[*pos] = OpCode::Jump((starts[*arm] - pos - 1) as u32));
+        let end = length - 1;
+        arms.into_iter().enumerate().for_each(|(idx, arm)| {
+            code.extend(arm);
+            if idx + 1 != starts.len() {
+                code.push(OpCode::Jump((end - code.len() - 1) as u32));
+            }
+        });
+        code.push(OpCode::EndMatch(scrutinee));
+        code
+    }
+}
+
+// Keeps the rows that may match once the value in `slot` matched `head`, replacing their pattern by the ones of the fields.
+fn specialize(rows: &[Row], slot: u32, head: &Pat, base: Option<u32>) -> Vec<Row> {
+    rows.iter().filter_map(|row| {
+        let mut row = row.clone();
+        if let Some(idx) = row.columns.iter().position(|(s, _)| *s == slot) {
+            let (_, pat) = row.columns.remove(idx);
+            match pat {
+                Pat::Any(_) => {}
+                pat if pat.same_head(head) => {
+                    let fields = pat.fields().iter().enumerate().map(|(i, pat)| (base.unwrap_or(0) + i as u32, pat.clone()));
+                    row.columns.splice(idx..idx, fields);
+                }
+                _ => return None,
+            }
+        }
+        Some(row)
+    }).collect()
+}
+
+// Keeps the rows that may match once the value in `slot` failed to match every pattern accepted by `tested`.
+fn default(rows: &[Row], slot: u32, tested: impl Fn(&Pat) -> bool) -> Vec<Row> {
+    rows.iter().filter_map(|row| {
+        let mut row = row.clone();
+        match row.at(slot) {
+            Some(pat) if tested(pat) => return None,
+            Some(Pat::Any(_)) => row.columns.retain(|(s, _)| *s != slot),
+            _ => {}
+        }
+        Some(row)
+    }).collect()
+}
+
+pub struct Builder<'a> {
+    patterns: &'a mut Vec<BytecodePattern>,
+    slots: &'a Slots,
+}
+impl<'a> Builder<'a> {
+    pub fn new(patterns: &'a mut Vec<BytecodePattern>, slots: &'a Slots) -> Self {
+        Self {
+            patterns,
+            slots,
+        }
+    }
+    fn register(&mut self, pattern: BytecodePattern) -> u32 {
+        match self.patterns.iter().position(|pat| *pat == pattern) {
+            Some(idx) => idx as u32,
+            None => {
+                self.patterns.push(pattern);
+                self.patterns.len() as u32 - 1
+            }
+        }
+    }
+    pub fn build(&mut self, scrutinee: u32, pats: &[Pat]) -> Tree {
+        let rows = pats.iter().enumerate().map(|(arm, pat)| Row {
+            columns: vec![(scrutinee, pat.clone())],
+            arm,
+        }).collect::<Vec<_>>();
+        self.tree(&rows)
+    }
+    // Tests the first pattern of the first row that is not a wildcard, since it has to be tested before running that row.
+    fn tree(&mut self, rows: &[Row]) -> Tree {
+        let first = match rows.first() {
+            Some(row) => row,
+            None => return Tree {
+                code: vec![OpCode::NoMatch],
+                leaves: vec![],
+            },
+        };
+        match first.columns.iter().find(|(_, pat)| !matches!(pat, Pat::Any(_))) {
+            None => Tree {
+                code: vec![OpCode::Jump(0)],
+                leaves: vec![(0, first.arm)],
+            },
+            Some((slot, Pat::Constr(..))) => self.switch(rows, *slot),
+            Some((slot, head)) => {
+                let (slot, head) = (*slot, head.clone());
+                self.test(rows, slot, &head)
+            }
+        }
+    }
+    // Dispatches on the constructor of the value with a jump table, the last jump going to the default case.
+    fn switch(&mut self, rows: &[Row], slot: u32) -> Tree {
+        let idx = |pat: &Pat| if let Pat::Constr(idx, _) = pat { *idx } else { 0 };
+        let mut heads = rows.iter().filter_map(|row| match row.at(slot) {
+            Some(pat @ Pat::Constr(..)) => Some(pat.clone()),
+            _ => None,
+        }).collect::<Vec<_>>();
+        heads.sort_by_key(idx);
+        heads.dedup_by(|lhs, rhs| lhs.same_head(rhs));
+        let first = idx(&heads[0]);
+        let amount = idx(&heads[heads.len() - 1]) - first + 1;
+
+        let mut blocks = heads.iter().map(|head| self.unpack(rows, slot, head)).collect::<Vec<_>>();
+        blocks.push(self.tree(&default(rows, slot, |pat| matches!(pat, Pat::Constr(..)))));
+        let mut starts = vec![];
+        let mut length = amount as usize + 2;
+        blocks.iter().for_each(|block| {
+            starts.push(length);
+            length += block.code.len();
+        });
+
+        let mut tree = Tree {
+            code: vec![OpCode::Switch(slot, first, amount)],
+            leaves: vec![],
+        };
+        (first..=first + amount).for_each(|constr| {
+            let block = heads.iter().position(|head| idx(head) == constr).unwrap_or(heads.len());
+            tree.code.push(OpCode::Jump((starts[block] - tree.code.len() - 1) as u32));
+        });
+        blocks.into_iter().for_each(|block| tree.append(block));
+        tree
+    }
+    // Tests a literal or the length of a tuple, falling back to the other patterns on failure.
+    fn test(&mut self, rows: &[Row], slot: u32, head: &Pat) -> Tree {
+        let pattern = match head {
+            Pat::Tuple(fields) => {
+                let any = self.register(BytecodePattern::Any);
+                BytecodePattern::Tuple(vec![any; fields.len()])
+            }
+            Pat::Literal(id) => BytecodePattern::Literal(*id),
+            _ => BytecodePattern::Any,
+        };
+        let pattern = self.register(pattern);
+        let matched = self.unpack(rows, slot, head);
+        let mut tree = Tree {
+            code: vec![OpCode::JumpIfNoMatch(slot, pattern, matched.code.len() as u32)],
+            leaves: vec![],
+        };
+        tree.append(matched);
+        tree.append(self.tree(&default(rows, slot, |pat| pat.same_head(head))));
+        tree
+    }
+    // Puts the fields of a value known to match `head` in their slots, and goes on with the rows that may still match.
+    fn unpack(&mut self, rows: &[Row], slot: u32, head: &Pat) -> Tree {
+        let base = self.slots.bases.get(&slot).copied();
+        let mut tree = Tree::default();
+        if let (false, Some(base)) = (head.fields().is_empty(), base) {
+            tree.code.push(OpCode::Unpack(slot, base));
+        }
+        tree.append(self.tree(&specialize(rows, slot, head, base)));
+        tree
+    }
+}
+
+// The constructors of the enums, to know whether the patterns cover every value.
+pub struct Enums<'a> {
+    pub constructors: &'a [(u32, u32)], // (amount, sym_id)
+    pub types: &'a [(String, u32, u32)], // (name, first_constr_idx, last_constr_idx)
+}
+impl<'a> Enums<'a> {
+    // The heads that the values matched by `head` may have instead, when there is a finite amount of them.
+    fn signature(&self, head: &Pat) -> Option<Vec<Pat>> {
+        match head {
+            Pat::Constr(idx, _) => self.types.iter().find(|(_, first, last)| (first..=last).contains(&idx)).map(|(_, first, last)| {
+                (*first..=*last).map(|idx| Pat::Constr(idx, vec![Pat::Any(None); self.constructors[idx as usize].0 as usize])).collect()
+            }),
+            Pat::Tuple(fields) => Some(vec![Pat::Tuple(vec![Pat::Any(None); fields.len()])]),
+            _ => None,
+        }
+    }
+    // The signature of the heads of the first column, if they cover all of it.
+    fn complete(&self, heads: &[Pat]) -> Option<Vec<Pat>> {
+        heads.first().and_then(|head| self.signature(head)).filter(|signature| signature.iter().all(|pat| heads.iter().any(|head| head.same_head(pat))))
+    }
+    // Whether some value matched by `row` is matched by none of the `rows`.
+    pub fn useful(&self, rows: &[Vec<Pat>], row: &[Pat]) -> bool {
+        match row.first() {
+            None => rows.is_empty(),
+            Some(Pat::Any(_)) => match self.complete(&heads(rows)) {
+                Some(signature) => signature.iter().any(|head| self.useful(&specialize_rows(rows, head), &specialize_row(row, head).unwrap_or_default())),
+                None => self.useful(&default_rows(rows), &row[1..]),
+            },
+            Some(head) => self.useful(&specialize_rows(rows, head), &specialize_row(row, head).unwrap_or_default()),
+        }
+    }
+    // The values of `width` columns matched by none of the `rows`, as patterns.
+    pub fn missing(&self, rows: &[Vec<Pat>], width: usize) -> Vec<Vec<Pat>> {
+        if width == 0 {
+            return if rows.is_empty() { vec![vec![]] } else { vec![] };
+        }
+        // Every head of an enum is tried, so that all the missing combinations of variants are listed.
+        let heads = heads(rows);
+        match heads.first().and_then(|head| self.signature(head)) {
+            Some(signature) => signature.iter().flat_map(|head| {
+                let arity = head.fields().len();
+                self.missing(&specialize_rows(rows, head), arity + width - 1).into_iter().map(move |mut fields| {
+                    let rest = fields.split_off(arity);
+                    let head = match head {
+                        Pat::Constr(idx, _) => Pat::Constr(*idx, fields),
+                        _ => Pat::Tuple(fields),
+                    };
+                    std::iter::once(head).chain(rest).collect()
+                })
+            }).collect(),
+            None => self.missing(&default_rows(rows), width - 1).into_iter().map(|rest| std::iter::once(Pat::Any(None)).chain(rest).collect()).collect(),
+        }
+    }
+}
+
+fn heads(rows: &[Vec<Pat>]) -> Vec<Pat> {
+    let mut heads: Vec<Pat> = vec![];
+    rows.iter().filter_map(|row| row.first()).for_each(|pat| if !matches!(pat, Pat::Any(_)) && !heads.iter().any(|head| head.same_head(pat)) {
+        heads.push(pat.clone());
+    });
+    heads
+}
+fn specialize_row(row: &[Pat], head: &Pat) -> Option<Vec<Pat>> {
+    let fields = match &row[0] {
+        Pat::Any(_) => vec![Pat::Any(None); head.fields().len()],
+        pat if pat.same_head(head) => pat.fields().to_vec(),
+        _ => return None,
+    };
+    Some(fields.into_iter().chain(row[1..].iter().cloned()).collect())
+}
+fn specialize_rows(rows: &[Vec<Pat>], head: &Pat) -> Vec<Vec<Pat>> {
+    rows.iter().filter_map(|row| specialize_row(row, head)).collect()
+}
+fn default_rows(rows: &[Vec<Pat>]) -> Vec<Vec<Pat>> {
+    rows.iter().filter(|row| matches!(row[0], Pat::Any(_))).map(|row| row[1..].to_vec()).collect()
+}
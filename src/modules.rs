@@ -0,0 +1,187 @@
+/*
+ *  Copyright (C) 2021, Wafelack <wafelack@protonmail.com>
+ *
+ *  ------------------------------------------------------
+ *
+ *     This file is part of Orion.
+ *
+ *  Orion is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  Orion is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with Orion.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::{error, lexer::Span, Result};
+use std::fs;
+
+// The names visible in a file, the last ones hiding the first ones.
+#[derive(Clone, Debug, Default)]
+pub struct Namespace {
+    names: Vec<(String, String)>, // (name, global name)
+    modules: Vec<(String, Vec<(String, String)>)>, // (alias, [(name, global name)]) of the imported modules.
+    module: Option<(String, Vec<String>, Span)>, // (name, exports, span) of the module declared by the file.
+}
+
+// What a file makes visible to the files loading it.
+#[derive(Clone, Debug)]
+enum Exports {
+    Module(String, Vec<(String, String)>), // (name, [(name, global name)])
+    File(Namespace), // A file without module declaration gives everything visible at its end.
+}
+
+// How the names of a module are brought in a file.
+pub enum Import<'a> {
+    All, // `load`, the exports being also visible unqualified.
+    Module(Option<&'a str>, &'a [String]), // `import`, with (alias, names visible unqualified).
+}
+
+// The files loaded so far, and the names visible in the one being compiled. The definitions of a
+// module are given global names qualified by the name of the module, that are only visible to the
+// other files through its exports.
+#[derive(Clone, Debug, Default)]
+pub struct Modules {
+    namespace: Namespace,
+    prelude: Namespace, // The names every loaded file starts with.
+    loaded: Vec<(String, Option<Exports>)>, // (path, exports), without exports while it is being loaded.
+}
+
+impl Modules {
+    // The path a file is known by, so that it is only loaded once however it is written.
+    fn canonical(path: &str) -> String {
+        fs::canonicalize(path).map_or(path.to_string(), |p| p.to_string_lossy().to_string())
+    }
+    pub fn is_loaded(&self, path: &str) -> bool {
+        let path = Self::canonical(path);
+        self.loaded.iter().any(|(loaded, _)| *loaded == path)
+    }
+    // Starts loading a file, returning the namespace of the file loading it.
+    pub fn enter(&mut self, path: &str) -> Namespace {
+        self.loaded.push((Self::canonical(path), None));
+        std::mem::replace(&mut self.namespace, self.prelude.clone())
+    }
+    // Ends loading the file `path`, going back to the namespace of the file loading it.
+    pub fn leave(&mut self, saved: Namespace, path: &str) -> Result<()> {
+        let namespace = std::mem::replace(&mut self.namespace, saved);
+        let exports = match &namespace.module {
+            Some((name, exported, span)) => Exports::Module(name.clone(), exported.iter().map(|export| match namespace.global(export) {
+                Some(global) => Ok((export.clone(), global)),
+                None => error!(E0002; path, *span => "Exported name not defined by the module: {}.", export),
+            }).collect::<Result<Vec<_>>>()?),
+            None => Exports::File(namespace),
+        };
+        let path = Self::canonical(path);
+        match self.loaded.iter_mut().find(|(loaded, _)| *loaded == path) {
+            Some((_, loaded)) => *loaded = Some(exports),
+            None => self.loaded.push((path, Some(exports))),
+        }
+        Ok(())
+    }
+    // The names visible after loading the prelude are visible in all the files.
+    pub fn prelude(&mut self) {
+        self.prelude = self.namespace.clone();
+    }
+    pub fn declare(&mut self, name: &str, exports: &[String], file: &str, span: Span) -> Result<()> {
+        match &self.namespace.module {
+            Some((declared, ..)) => error!(E0006; file, span => "A module has already been declared by this file: {}.", declared),
+            None => {
+                self.namespace.module = Some((name.to_string(), exports.to_vec(), span));
+                Ok(())
+            }
+        }
+    }
+    // Makes the names of a loaded file visible.
+    pub fn import(&mut self, path: &str, import: Import, file: &str, span: Span) -> Result<()> {
+        let path = Self::canonical(path);
+        let exports = match self.loaded.iter().find(|(loaded, _)| *loaded == path) {
+            Some((_, Some(exports))) => exports.clone(),
+            // The file is loading itself, through the files it loads.
+            Some((_, None)) => return error!(E0002; file, span => "Circular load of a file: {}.", path),
+            None => return Ok(()),
+        };
+        match (exports, import) {
+            (Exports::File(namespace), Import::All) => {
+                self.namespace.names.extend(namespace.names);
+                self.namespace.modules.extend(namespace.modules);
+            }
+            (Exports::File(_), Import::Module(..)) => return error!(E0002; file, span => "Not a module: {}, that has to be loaded with `load`.", path),
+            (Exports::Module(name, exports), Import::All) => {
+                self.namespace.names.extend(exports.iter().cloned());
+                self.namespace.modules.push((name, exports));
+            }
+            (Exports::Module(name, exports), Import::Module(alias, names)) => {
+                for imported in names {
+                    match exports.iter().find(|(export, _)| export == imported) {
+                        Some(export) => self.namespace.names.push(export.clone()),
+                        None => return error!(E0002; file, span => "Module {} does not export {}.", name, imported),
+                    }
+                }
+                self.namespace.modules.push((alias.map_or(name, str::to_string), exports));
+            }
+        }
+        Ok(())
+    }
+    // The global name of a definition of the file.
+    pub fn define(&mut self, name: &str) -> String {
+        let global = match &self.namespace.module {
+            Some((module, ..)) => format!("{}/{}", module, name),
+            None => name.to_string(),
+        };
+        self.namespace.names.push((name.to_string(), global.clone()));
+        global
+    }
+    // The global name of a variable that is not bound locally, `alias/name` referring to the exports
+    // of a module.
+    pub fn resolve(&self, name: &str, file: &str, span: Span) -> Result<String> {
+        if let Some(global) = self.namespace.global(name) {
+            return Ok(global);
+        }
+        match name.rsplit_once('/') {
+            Some((alias, member)) if !alias.is_empty() && !member.is_empty() => match self.namespace.modules.iter().rev().find(|(module, _)| module == alias) {
+                Some((_, exports)) => match exports.iter().find(|(export, _)| export == member) {
+                    Some((_, global)) => Ok(global.clone()),
+                    None => error!(E0002; file, span => "Module {} does not export {}.", alias, member),
+                },
+                None => Ok(name.to_string()),
+            },
+            _ => Ok(name.to_string()),
+        }
+    }
+}
+
+impl Namespace {
+    fn global(&self, name: &str) -> Option<String> {
+        self.names.iter().rev().find(|(visible, _)| visible == name).map(|(_, global)| global.clone())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn namespaces() -> Result<()> {
+        let mut modules = Modules::default();
+        let saved = modules.enter("tree.orn");
+        modules.declare("tree", &["map".to_string()], "tree.orn", Span::default())?;
+        assert_eq!(modules.define("map"), "tree/map");
+        assert_eq!(modules.define("helper"), "tree/helper");
+        assert_eq!(modules.resolve("helper", "tree.orn", Span::default())?, "tree/helper");
+        modules.leave(saved, "tree.orn")?;
+
+        assert_eq!(modules.define("map"), "map");
+        modules.import("tree.orn", Import::Module(Some("t"), &[]), "TEST", Span::default())?;
+        assert_eq!(modules.resolve("map", "TEST", Span::default())?, "map");
+        assert_eq!(modules.resolve("t/map", "TEST", Span::default())?, "tree/map");
+        assert!(modules.resolve("t/helper", "TEST", Span::default()).is_err());
+        modules.import("tree.orn", Import::All, "TEST", Span::default())?;
+        assert_eq!(modules.resolve("map", "TEST", Span::default())?, "tree/map");
+        Ok(())
+    }
+}
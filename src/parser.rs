@@ -20,42 +20,68 @@
  */
 use crate::{
     bug, error,
-    lexer::{TType, Token},
-    Result,
+    lexer::{Span, TType, Token},
+    OrionError, Result,
 };
-use std::{collections::HashMap, mem::discriminant};
+use std::mem::discriminant;
 
-#[derive(PartialEq, Debug, Clone)]
+#[derive(Debug, Clone)]
 pub struct Expr {
     pub line: usize,
+    pub span: Span,
     pub exprt: ExprT,
 }
+// The spans are left out, so that expressions are equal wherever they are written on their line.
+impl PartialEq for Expr {
+    fn eq(&self, other: &Self) -> bool {
+        self.line == other.line && self.exprt == other.exprt
+    }
+}
 impl Expr {
     pub fn new(exprt: ExprT) -> Self {
         Self {
             exprt,
             line: 1,
+            span: Span {
+                line: 1,
+                ..Span::default()
+            },
         }
     }
     pub fn line(self, line: usize) -> Self {
         Self {
-            exprt: self.exprt,
             line,
+            span: Span {
+                line,
+                ..self.span
+            },
+            ..self
+        }
+    }
+    pub fn span(self, span: Span) -> Self {
+        Self {
+            line: span.line,
+            span,
+            ..self
         }
     }
-    pub fn replace(&self, from: String, to: Self) -> Self {
+    // Moves an expression and the ones it contains to `span`, as the expansion of a macro written there.
+    pub fn relocate(&self, span: Span) -> Self {
+        let all = |exprs: Vec<Expr>| exprs.into_iter().map(|e| e.relocate(span)).collect();
         match self.exprt.clone() {
-            ExprT::Var(v) if v == from => to,
-            ExprT::Call(f, args) => Expr::new(ExprT::Call(Box::new(f.replace(from.clone(), to.clone())), args.into_iter().map(|e| e.replace(from.clone(), to.clone())).collect())),
-            ExprT::Lambda(args, content) => Expr::new(ExprT::Lambda(args, Box::new(content.replace(from, to)))),
-            ExprT::Def(name, val, impure) => Expr::new(ExprT::Def(name, Box::new(val.replace(from, to)), impure)),
-            ExprT::Constr(name, args) => Expr::new(ExprT::Constr(name, args.into_iter().map(|e| e.replace(from.clone(), to.clone())).collect())),
-            ExprT::Tuple(args) => Expr::new(ExprT::Tuple(args.into_iter().map(|e| e.replace(from.clone(), to.clone())).collect())),
-            ExprT::Match(val, pats) => Expr::new(ExprT::Match(Box::new(val.replace(from.clone(), to.clone())), pats.into_iter().map(|(p, e)| (p, e.replace(from.clone(), to.clone()))).collect())),
-            ExprT::Begin(args) => Expr::new(ExprT::Begin(args.into_iter().map(|e| e.replace(from.clone(), to.clone())).collect())),
-            ExprT::Builtin(name, args) => Expr::new(ExprT::Builtin(name, args.into_iter().map(|e| e.replace(from.clone(), to.clone())).collect())),
-            _ => self.clone(),
-        }.line(self.line)
+            ExprT::Call(f, args) => Expr::new(ExprT::Call(Box::new(f.relocate(span)), all(args))),
+            ExprT::Lambda(args, content) => Expr::new(ExprT::Lambda(args, Box::new(content.relocate(span)))),
+            ExprT::Def(name, val, impure, signature) => Expr::new(ExprT::Def(name, Box::new(val.relocate(span)), impure, signature)),
+            ExprT::Macro(name, clauses) => Expr::new(ExprT::Macro(name, clauses.into_iter().map(|(args, rest, body)| (args, rest, body.relocate(span))).collect())),
+            ExprT::Quasiquote(template) => Expr::new(ExprT::Quasiquote(Box::new(template.relocate(span)))),
+            ExprT::Constr(name, args) => Expr::new(ExprT::Constr(name, all(args))),
+            ExprT::Tuple(args) => Expr::new(ExprT::Tuple(all(args))),
+            ExprT::Match(val, pats) => Expr::new(ExprT::Match(Box::new(val.relocate(span)), pats.into_iter().map(|(p, _, e)| (p, span, e.relocate(span))).collect())),
+            ExprT::Begin(args) => Expr::new(ExprT::Begin(all(args))),
+            ExprT::Builtin(name, args) => Expr::new(ExprT::Builtin(name, all(args))),
+            ExprT::Let(binding, bindings, body) => Expr::new(ExprT::Let(binding, bindings.into_iter().map(|(name, value)| (name, value.relocate(span))).collect(), Box::new(body.relocate(span)))),
+            exprt => Expr::new(exprt),
+        }.span(span)
     }
 }
 
@@ -65,17 +91,45 @@ pub enum ExprT {
     Call(Box<Expr>, Vec<Expr>),
     Lambda(Vec<String>, Box<Expr>),
     Literal(Literal),
-    Def(String, Box<Expr>, bool), // (name, value, impure?)
-    Macro(String, Vec<String>, Box<Expr>),
+    Def(String, Box<Expr>, bool, Option<TypeExpr>), // (name, value, impure?, signature)
+    Macro(String, Vec<MacroClause>),
     Constr(String, Vec<Expr>),
-    Enum(String, HashMap<String, u8>),
+    Enum(String, Vec<(String, Vec<TypeExpr>)>), // (name, [(variant, fields)])
     Tuple(Vec<Expr>),
     Load(Vec<String>),
-    Match(Box<Expr>, Vec<(Pattern, Expr)>),
+    Module(String, Vec<String>), // (name, exports)
+    Import(String, Option<String>, Vec<String>), // (file, alias, names imported unqualified)
+    Match(Box<Expr>, Vec<(Pattern, Span, Expr)>), // (value, [(pattern, span of the pattern, body)])
     Begin(Vec<Expr>),
     Builtin(String, Vec<Expr>),
+    Let(Binding, Vec<(String, Expr)>, Box<Expr>), // (binding, [(variable, value)], body)
+    // In a quasiquote, the arguments of the macro written in the binders of a lambda, a definition or
+    // a pattern are the names of the binders prefixed with a comma.
+    Quasiquote(Box<Expr>),
+    Unquote(String),
+    Splice(String),
+}
+
+// How the values of a `let` see its variables.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Binding {
+    Parallel, // `let`, the values only see the enclosing variables.
+    Sequential, // `let*`, each value sees the variables before it.
+    Recursive, // `letrec`, the values are lambdas that see all the variables.
+}
+impl Binding {
+    pub fn keyword(&self) -> &'static str {
+        match self {
+            Self::Parallel => "let",
+            Self::Sequential => "let*",
+            Self::Recursive => "letrec",
+        }
+    }
 }
 
+// A clause of a macro, whose arguments written `'name` only match the identifier `name`.
+pub type MacroClause = (Vec<String>, Option<String>, Expr); // (arguments, rest argument, body)
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum Literal {
     Integer(i32),
@@ -90,8 +144,34 @@ pub enum Pattern {
     Tuple(Vec<Pattern>),
     Literal(Literal),
 }
+// A type as written in a signature or in the fields of an enum, the lowercase names being type variables.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TypeExpr {
+    Var(String),
+    Named(String, Vec<TypeExpr>),
+    Tuple(Vec<TypeExpr>),
+    Function(Vec<TypeExpr>, Box<TypeExpr>, bool), // (arguments, returned, impure?)
+}
+impl TypeExpr {
+    // Every occurrence of the type variables, in order.
+    pub fn vars(&self, vars: &mut Vec<String>) {
+        match self {
+            Self::Var(name) => vars.push(name.clone()),
+            Self::Named(_, types) | Self::Tuple(types) => types.iter().for_each(|ty| ty.vars(vars)),
+            Self::Function(args, returned, _) => {
+                args.iter().for_each(|ty| ty.vars(vars));
+                returned.vars(vars);
+            }
+        }
+    }
+}
+
+fn is_closing(ttype: &TType) -> bool {
+    matches!(ttype, TType::RParen | TType::RBracket | TType::RBrace)
+}
+
 fn first_char(s: impl ToString) -> char {
-    s.to_string().chars().nth(0).unwrap()
+    s.to_string().chars().next().unwrap()
 }
 
 pub struct Parser {
@@ -99,6 +179,10 @@ pub struct Parser {
     output: Vec<Expr>,
     current: usize,
     file: String,
+    errors: Vec<OrionError>, // The errors that did not prevent the parsing from going on.
+    open: Vec<Token>, // The delimiters popped and not closed yet, innermost last.
+    template: bool, // Whether the next expression is the template of a macro.
+    quasi: bool, // Whether a quasiquote is being parsed.
 }
 
 impl Parser {
@@ -108,59 +192,167 @@ impl Parser {
             output: vec![],
             current: 0usize,
             file: file.to_string(),
+            errors: vec![],
+            open: vec![],
+            template: false,
+            quasi: false,
+        }
+    }
+    // Records an error found in an expression that can still be parsed.
+    fn report(&mut self, result: Result<()>) {
+        if let Err(e) = result {
+            self.errors.push(e);
         }
     }
+    // Pops the next token if it has the expected type, leaving it for the recovery otherwise.
     fn advance(&mut self, expected: TType) -> Result<Token> {
-        let popped = self.pop()?;
-
-        if discriminant(&popped.ttype) != discriminant(&expected) {
-            error!(
-                self.file,
-                popped.line =>
-                "Expected {}, found {}.",
-                expected.get_type(),
-                popped.ttype.get_type()
-                )
-        } else {
-            Ok(popped)
+        match self.peek() {
+            Some(next) if !self.is_at_end() && discriminant(&next.ttype) != discriminant(&expected) => {
+                match self.open.last() {
+                    // A parenthese starting a line is most likely the next top level form.
+                    Some(open) if is_closing(&expected) && next.ttype == TType::LParen && next.span.column == 1 => error!(E0001;
+                        self.file,
+                        open.span =>
+                        "Unclosed {}.",
+                        open.ttype.get_type(),
+                        ).map_err(|e| e.label(next.span, format!("expected a {} before the next form", expected.get_type()))),
+                    open => {
+                        let e = error!(E0001;
+                            self.file,
+                            next.span =>
+                            "Expected {}, found {}.",
+                            expected.get_type(),
+                            next.ttype.get_type()
+                            );
+                        match open {
+                            Some(open) if is_closing(&expected) => e.map_err(|e| e.label(open.span, format!("unclosed {}", open.ttype.get_type()))),
+                            _ => e,
+                        }
+                    }
+                }
+            }
+            _ => self.pop(),
         }
     }
     fn pop(&mut self) -> Result<Token> {
         if self.is_at_end() {
             let previous = &self.input[self.current - 1];
-            error!(
-                self.file,
-                previous.line =>
-                "Unfinished expression.",
-                )
+            match self.open.last() {
+                Some(open) => error!(E0001;
+                    self.file,
+                    open.span =>
+                    "Unclosed {}.",
+                    open.ttype.get_type(),
+                    ).map_err(|e| e.label(previous.span, "the input ends here")),
+                None => error!(E0001;
+                    self.file,
+                    previous.span =>
+                    "Unfinished expression.",
+                    ),
+            }
         } else {
             if self.input.len() != 1 {
                 self.current += 1;
             }
-            Ok(self.input[self.current - if self.input.len() == 1 { 0 } else { 1 }].clone())
+            let popped = self.input[self.current - if self.input.len() == 1 { 0 } else { 1 }].clone();
+            if is_closing(&popped.ttype) {
+                self.open.pop();
+            } else if matches!(popped.ttype, TType::LParen | TType::LBracket | TType::LBrace) {
+                self.open.push(popped.clone());
+            }
+            Ok(popped)
         }
     }
     fn peek(&self) -> Option<Token> {
-        self.input
-            .iter()
-            .nth(self.current)
-            .and_then(|t| Some(t.clone()))
+        self.input.get(self.current).cloned()
     }
     fn is_at_end(&self) -> bool {
         self.input.len() != 1 && self.current >= self.input.len()
     }
-    fn advance_many(&mut self, expected: TType) -> Result<Vec<Token>> {
-        let mut toret = vec![];
+    // An identifier, or in a quasiquote an argument of the macro written `,name`.
+    fn binder(&mut self) -> Result<Token> {
+        if self.quasi && self.peek().map(|t| t.ttype) == Some(TType::Unquote) {
+            self.advance(TType::Unquote)?;
+            let mut token = self.advance(TType::Ident("".to_owned()))?;
+            if let TType::Ident(name) = &mut token.ttype {
+                name.insert(0, ',');
+            }
+            Ok(token)
+        } else {
+            self.advance(TType::Ident("".to_owned()))
+        }
+    }
+    fn ident(&mut self) -> Result<String> {
+        match self.advance(TType::Ident("".to_owned()))?.ttype {
+            TType::Ident(name) => Ok(name),
+            _ => bug!("UNEXPECTED_NON_IDENT"),
+        }
+    }
+
+    fn parse_type(&mut self) -> Result<TypeExpr> {
+        let root = self.pop()?;
 
-        while !self.is_at_end()
-            && std::mem::discriminant(&self.peek().unwrap().ttype) == discriminant(&expected)
-            {
-                toret.push(self.advance(expected.clone())?);
+        Ok(match &root.ttype {
+            TType::Ident(name) if name.as_str() != "->" => if first_char(name).is_ascii_uppercase() {
+                TypeExpr::Named(name.to_string(), vec![])
+            } else {
+                TypeExpr::Var(name.to_string())
             }
+            TType::LParen => {
+                let subroot = self.pop()?;
+                let impure = subroot.ttype == TType::Ident("->".to_string()) && self.peek().map(|t| t.ttype) == Some(TType::Quote);
+                if impure {
+                    self.advance(TType::Quote)?;
+                    let got = self.advance(TType::Ident("".to_string()))?;
+                    if got.ttype != TType::Ident("impure".to_string()) {
+                        return error!(E0001; self.file, got.span => "Expected `impure`, found {}.", got.ttype.get_type());
+                    }
+                }
+                let mut args = vec![];
+                if subroot.ttype != TType::RParen {
+                    while !self.is_at_end() && self.peek().unwrap().ttype != TType::RParen {
+                        args.push(self.parse_type()?);
+                    }
+                    self.advance(TType::RParen)?;
+                }
 
-        Ok(toret)
+                match &subroot.ttype {
+                    TType::RParen | TType::Tuple => TypeExpr::Tuple(args),
+                    TType::Ident(arrow) if arrow.as_str() == "->" => match args.pop() {
+                        Some(returned) => TypeExpr::Function(args, Box::new(returned), impure),
+                        None => return error!(E0001; self.file, subroot.span => "Expected the returned type of the function."),
+                    },
+                    TType::Ident(name) if first_char(name).is_ascii_uppercase() => TypeExpr::Named(name.to_string(), args),
+                    ttype => return error!(E0001; self.file, subroot.span => "Expected a type, found {}.", ttype.get_type()),
+                }
+            }
+            ttype => return error!(E0001; self.file, root.span => "Expected a type, found {}.", ttype.get_type()),
+        })
+    }
+    fn parse_clause(&mut self) -> Result<MacroClause> {
+        self.advance(TType::LParen)?;
+        let (mut args, mut rest) = (vec![], None);
+        while rest.is_none() && !self.is_at_end() && self.peek().unwrap().ttype != TType::RParen {
+            let token = self.pop()?;
+            match token.ttype {
+                TType::Ident(name) if name == "." => rest = Some(self.advance(TType::Ident("".to_owned()))?),
+                TType::Ident(name) => args.push(name),
+                TType::Quote => match self.advance(TType::Ident("".to_owned()))?.ttype {
+                    TType::Ident(name) => args.push(format!("'{}", name)),
+                    _ => bug!("UNEXPECTED_NON_IDENT"),
+                },
+                ttype => return error!(E0001; self.file, token.span => "Expected an argument, found {}.", ttype.get_type()),
+            }
+        }
+        let rest = rest.map(|token| match token.ttype {
+            TType::Ident(name) => name,
+            _ => bug!("UNEXPECTED_NON_IDENT"),
+        });
+        self.advance(TType::RParen)?;
+        self.template = true;
+        let body = self.parse_expr()?;
+        Ok((args, rest, body))
     }
-
     fn parse_pattern(&mut self) -> Result<Pattern> {
         let root = self.pop()?;
 
@@ -169,12 +361,19 @@ impl Parser {
             TType::Number(i) => Pattern::Literal(Literal::Integer(*i)),
             TType::Float(f) => Pattern::Literal(Literal::Single(*f)),
             TType::Ident(v) => {
-                if first_char(&v).is_ascii_uppercase() {
+                if first_char(v).is_ascii_uppercase() {
                     Pattern::Constr(v.to_string(), vec![])
                 } else {
                     Pattern::Var(v.to_string())
                 }
             }
+            TType::Unquote if self.quasi => {
+                self.current -= 1;
+                match self.binder()?.ttype {
+                    TType::Ident(name) => Pattern::Var(name),
+                    _ => bug!("UNEXPECTED_NON_IDENT"),
+                }
+            }
             TType::LParen => {
                 let subroot = self.pop()?;
 
@@ -198,28 +397,27 @@ impl Parser {
                         self.advance(TType::RParen)?;
 
                         if let TType::Ident(x) = &subroot.ttype {
-                            if first_char(x).is_ascii_uppercase() {
-                                Pattern::Constr(x.to_string(), args)
-                            } else {
-                                return error!(
+                            if !first_char(x).is_ascii_uppercase() {
+                                self.report(error!(E0001;
                                     self.file,
-                                    subroot.line =>
+                                    subroot.span =>
                                     "Invalid Enum Variant name, Enum Variant names have to start with an uppercase letter: {}.",
                                     x
-                                    );
+                                    ));
                             }
+                            Pattern::Constr(x.to_string(), args)
                         } else {
-                            return error!(
+                            return error!(E0001;
                                 self.file,
-                                subroot.line =>
+                                subroot.span =>
                                 "Expected an Enum Variant.",
                                 );
                         }
                     }
                     _ => {
-                        return error!(
+                        return error!(E0001;
                             self.file,
-                            subroot.line =>
+                            subroot.span =>
                             "Expected Tuple or Enum Variant, found {}.",
                             subroot.ttype.get_type(),
                             )
@@ -227,9 +425,9 @@ impl Parser {
                 }
             }
             _ => {
-                return error!(
+                return error!(E0001;
                     self.file,
-                    root.line =>
+                    root.span =>
                     "Expected Literal, Identifier, Tuple or Enum Variant, found {}.",
                     root.ttype.get_type()
                     )
@@ -237,21 +435,50 @@ impl Parser {
         })
     }
 
+    // The last token that was popped.
+    fn previous(&self) -> &Token {
+        &self.input[self.current.saturating_sub(1)]
+    }
     fn parse_expr(&mut self) -> Result<Expr> {
+        let start = self.peek().map(|t| t.span).unwrap_or_default();
+        let expr = self.parse_inner()?;
+        Ok(expr.span(start.to(self.previous().span)))
+    }
+    fn parse_inner(&mut self) -> Result<Expr> {
+        let template = std::mem::take(&mut self.template);
         let root = self.pop()?;
 
         Ok(match &root.ttype {
-            TType::Str(s) => Expr::new(ExprT::Literal(Literal::String(s.to_string()))).line(root.line),
-            TType::Float(f) => Expr::new(ExprT::Literal(Literal::Single(*f))).line(root.line),
-            TType::Number(i) => Expr::new(ExprT::Literal(Literal::Integer(*i))).line(root.line),
+            TType::Str(s) => Expr::new(ExprT::Literal(Literal::String(s.to_string()))).line(root.span.line),
+            TType::Float(f) => Expr::new(ExprT::Literal(Literal::Single(*f))).line(root.span.line),
+            TType::Number(i) => Expr::new(ExprT::Literal(Literal::Integer(*i))).line(root.span.line),
             TType::Ident(v) => {
-                if first_char(&v).is_ascii_uppercase() {
-                    Expr::new(ExprT::Constr(v.to_string(), vec![])).line(root.line)
+                if first_char(v).is_ascii_uppercase() {
+                    Expr::new(ExprT::Constr(v.to_string(), vec![])).line(root.span.line)
                 } else {
-                    Expr::new(ExprT::Var(v.to_string())).line(root.line)
+                    Expr::new(ExprT::Var(v.to_string())).line(root.span.line)
+                }
+            }
+            TType::Quote => Expr::new(ExprT::Lambda(vec![], Box::new(self.parse_expr()?))).line(root.span.line),
+            TType::Quasiquote => {
+                if !template {
+                    return error!(E0001; self.file, root.span => "Quasiquotes can only be used as the template of a macro.");
                 }
+                self.quasi = true;
+                let template = self.parse_expr();
+                self.quasi = false;
+                Expr::new(ExprT::Quasiquote(Box::new(template?))).line(root.span.line)
+            }
+            TType::Unquote | TType::Splice => {
+                if !self.quasi {
+                    return error!(E0001; self.file, root.span => "Unquotes can only be used in a quasiquote.");
+                }
+                let name = match self.advance(TType::Ident("".to_owned()))?.ttype {
+                    TType::Ident(name) => name,
+                    _ => bug!("UNEXPECTED_NON_IDENT"),
+                };
+                Expr::new(if root.ttype == TType::Unquote { ExprT::Unquote(name) } else { ExprT::Splice(name) }).line(root.span.line)
             }
-            TType::Quote => Expr::new(ExprT::Lambda(vec![], Box::new(self.parse_expr()?))).line(root.line),
             TType::LBrace => {
                 let mut expressions = vec![];
 
@@ -259,20 +486,20 @@ impl Parser {
                     expressions.push(self.parse_expr()?);
                 }
                 self.advance(TType::RBrace)?;
-                Expr::new(ExprT::Begin(expressions)).line(root.line)
+                Expr::new(ExprT::Begin(expressions)).line(root.span.line)
             }
             TType::LBracket => {
                 let mut exprs = vec![];
                 while !self.is_at_end() && self.peek().unwrap().ttype != TType::RBracket {
                     exprs.push(self.parse_expr()?);
                 }
-                let constr = if exprs.len() >= 1 {
-                    exprs.into_iter().rev().fold(ExprT::Constr("Nil".to_string(), vec![]), |acc, e| ExprT::Constr("Cons".to_string(), vec![e, Expr::new(acc).line(root.line)]))
+                let constr = if !exprs.is_empty() {
+                    exprs.into_iter().rev().fold(ExprT::Constr("Nil".to_string(), vec![]), |acc, e| ExprT::Constr("Cons".to_string(), vec![e, Expr::new(acc).line(root.span.line)]))
                 } else {
                     ExprT::Constr("Nil".to_string(), vec![])
                 };
                 self.advance(TType::RBracket)?;
-                Expr::new(constr).line(root.line)
+                Expr::new(constr).line(root.span.line)
             }
             TType::LParen => {
                 let subroot = self.pop()?;
@@ -286,18 +513,23 @@ impl Parser {
                             bug!("UNEXPECTED_NON_IDENT")
                         };
 
-                        self.advance(TType::LParen)?;
-                        let mut args = vec![];
-                        while !self.is_at_end() && self.peek().unwrap().ttype != TType::RParen {
-                            match self.advance(TType::Ident("".to_string()))?.ttype {
-                                TType::Ident(s) => args.push(s),
-                                _ => bug!("UNEXPECTED_NON_IDENT")
+                        // The arguments of a single clause macro are not in parentheses.
+                        let clauses = if self.input.get(self.current + 1).map(|t| &t.ttype) == Some(&TType::LParen) {
+                            let mut clauses = vec![];
+                            while !self.is_at_end() && self.peek().unwrap().ttype != TType::RParen {
+                                self.advance(TType::LParen)?;
+                                clauses.push(self.parse_clause()?);
+                                self.advance(TType::RParen)?;
                             }
+                            clauses
+                        } else {
+                            vec![self.parse_clause()?]
+                        };
+                        if clauses.is_empty() {
+                            return error!(E0001; self.file, subroot.span => "Expected the clauses of the macro.");
                         }
                         self.advance(TType::RParen)?;
-                        let expr = self.parse_expr()?;
-                        self.advance(TType::RParen)?;
-                        Expr::new(ExprT::Macro(name, args, Box::new(expr))).line(subroot.line)
+                        Expr::new(ExprT::Macro(name, clauses)).line(subroot.span.line)
                     }
                     TType::Builtin(b) => {
                         let mut args = vec![];
@@ -305,7 +537,7 @@ impl Parser {
                             args.push(self.parse_expr()?);
                         }
                         self.advance(TType::RParen)?;
-                        Expr::new(ExprT::Builtin(b.to_string(), args)).line(subroot.line)
+                        Expr::new(ExprT::Builtin(b.to_string(), args)).line(subroot.span.line)
                     }
                     TType::Load => {
                         let mut names = vec![];
@@ -319,40 +551,107 @@ impl Parser {
                             }
                         }
                         self.advance(TType::RParen)?;
-                        Expr::new(ExprT::Load(names)).line(subroot.line)                    
+                        Expr::new(ExprT::Load(names)).line(subroot.span.line)                    
+                    }
+                    TType::Module => {
+                        // The declaration covers the whole file, so it comes before its definitions.
+                        if self.open.len() != 1 || !self.output.is_empty() || !self.errors.is_empty() {
+                            self.report(error!(E0001; self.file, subroot.span => "A module can only be declared by the first expression of a file."));
+                        }
+                        let name = self.ident()?;
+                        self.advance(TType::LParen)?;
+                        let export = self.advance(TType::Ident("".to_owned()))?;
+                        if export.ttype != TType::Ident("export".to_string()) {
+                            return error!(E0001; self.file, export.span => "Expected the `export` list of the module.");
+                        }
+                        let mut exports = vec![];
+                        while !self.is_at_end() && self.peek().unwrap().ttype != TType::RParen {
+                            exports.push(self.ident()?);
+                        }
+                        self.advance(TType::RParen)?;
+                        self.advance(TType::RParen)?;
+                        Expr::new(ExprT::Module(name, exports)).line(subroot.span.line)
+                    }
+                    TType::Import => {
+                        let file = match self.advance(TType::Str("".to_owned()))?.ttype {
+                            TType::Str(file) => file,
+                            _ => bug!("UNEXPECTED_STRING"),
+                        };
+                        let alias = if matches!(self.peek().map(|t| t.ttype), Some(TType::Ident(_))) { Some(self.ident()?) } else { None };
+                        let mut names = vec![];
+                        if self.peek().map(|t| t.ttype) == Some(TType::LParen) {
+                            self.advance(TType::LParen)?;
+                            while !self.is_at_end() && self.peek().unwrap().ttype != TType::RParen {
+                                names.push(self.ident()?);
+                            }
+                            self.advance(TType::RParen)?;
+                        }
+                        self.advance(TType::RParen)?;
+                        Expr::new(ExprT::Import(file, alias, names)).line(subroot.span.line)
                     }
                     TType::Def => {
                         let impure =
-                            if self.peek().and_then(|t| Some(t.ttype)) == Some(TType::Quote) {
+                            if self.peek().map(|t| t.ttype) == Some(TType::Quote) {
                                 self.advance(TType::Quote)?;
                                 let got = self.advance(TType::Ident("".to_string()))?;
-                                if got.ttype == TType::Ident("impure".to_string()) {
-                                    true
-                                } else {
-                                    false
-                                }
+                                got.ttype == TType::Ident("impure".to_string())
                             } else {
                                 false
                             };
-                        let raw_name = self.advance(TType::Ident("".to_owned()))?;
+                        let raw_name = self.binder()?;
                         let name = if let TType::Ident(n) = raw_name.ttype {
                             n
                         } else {
                             bug!("UNEXPECTED_NON_IDENTIFIER");
                         };
                         if first_char(&name).is_ascii_uppercase() {
-                            return error!(
+                            self.report(error!(E0001;
                                 self.file,
-                                subroot.line =>
+                                raw_name.span =>
                                 "Literal names have to start with a lowercase letter.",
-                                );
+                                ));
                         }
 
+                        let signature = if self.peek().map(|t| t.ttype) == Some(TType::Ident("::".to_string())) {
+                            self.advance(TType::Ident("".to_owned()))?;
+                            Some(self.parse_type()?)
+                        } else {
+                            None
+                        };
+
                         let value = self.parse_expr()?;
 
                         self.advance(TType::RParen)?;
 
-                        Expr::new(ExprT::Def(name, Box::new(value), impure)).line(subroot.line)
+                        Expr::new(ExprT::Def(name, Box::new(value), impure, signature)).line(subroot.span.line)
+                    }
+                    TType::Let | TType::LetStar | TType::LetRec => {
+                        let binding = match subroot.ttype {
+                            TType::Let => Binding::Parallel,
+                            TType::LetStar => Binding::Sequential,
+                            _ => Binding::Recursive,
+                        };
+                        self.advance(TType::LParen)?;
+                        let mut bindings: Vec<(String, Expr)> = vec![];
+                        while !self.is_at_end() && self.peek().unwrap().ttype != TType::RParen {
+                            self.advance(TType::LParen)?;
+                            let raw_name = self.binder()?;
+                            let name = if let TType::Ident(n) = raw_name.ttype {
+                                n
+                            } else {
+                                bug!("UNEXPECTED_NON_IDENTIFIER");
+                            };
+                            if binding != Binding::Sequential && bindings.iter().any(|(bound, _)| *bound == name) {
+                                self.report(error!(E0006; self.file, raw_name.span => "Variable bound twice by the same `{}`: {}.", binding.keyword(), name));
+                            }
+                            let value = self.parse_expr()?;
+                            self.advance(TType::RParen)?;
+                            bindings.push((name, value));
+                        }
+                        self.advance(TType::RParen)?;
+                        let body = self.parse_expr()?;
+                        self.advance(TType::RParen)?;
+                        Expr::new(ExprT::Let(binding, bindings, Box::new(body))).line(subroot.span.line)
                     }
                     TType::Begin => {
                         let mut expressions = vec![];
@@ -363,20 +662,22 @@ impl Parser {
 
                         self.advance(TType::RParen)?;
 
-                        Expr::new(ExprT::Begin(expressions)).line(subroot.line)
+                        Expr::new(ExprT::Begin(expressions)).line(subroot.span.line)
                     }
                     TType::Match => {
                         let to_match = self.parse_expr()?;
                         let mut couples = vec![];
                         while !self.is_at_end() && self.peek().unwrap().ttype != TType::RParen {
                             self.advance(TType::LParen)?;
+                            let start = self.peek().map(|t| t.span).unwrap_or_default();
                             let pat = self.parse_pattern()?;
+                            let span = start.to(self.previous().span);
                             let todo = self.parse_expr()?;
-                            couples.push((pat, todo));
+                            couples.push((pat, span, todo));
                             self.advance(TType::RParen)?;
                         }
                         self.advance(TType::RParen)?;
-                        Expr::new(ExprT::Match(Box::new(to_match), couples)).line(subroot.line)
+                        Expr::new(ExprT::Match(Box::new(to_match), couples)).line(subroot.span.line)
                     }
                     TType::Enum => {
                         let r_name = self.advance(TType::Ident("".to_owned()))?;
@@ -388,14 +689,14 @@ impl Parser {
                         };
 
                         if !first_char(&name).is_ascii_uppercase() {
-                            return error!(
+                            self.report(error!(E0001;
                                 self.file,
-                                r_name.line =>
+                                r_name.span =>
                                 "Enum names have to start with a uppercase letter.",
-                                );
+                                ));
                         }
 
-                        let mut var_len = HashMap::new();
+                        let mut variants = vec![];
                         while !self.is_at_end() && self.peek().unwrap().ttype != TType::RParen {
                             let mul = if self.peek().unwrap().ttype == TType::LParen {
                                 self.advance(TType::LParen)?;
@@ -413,16 +714,15 @@ impl Parser {
                             };
 
                             if !first_char(&vname).is_ascii_uppercase() {
-                                return error!(self.file, r_name.line => "Enum variant names have to start with a uppercase letter.");
+                                self.report(error!(E0001; self.file, r_name.span => "Enum variant names have to start with a uppercase letter."));
                             }
 
-                            let length = if mul {
-                                self.advance_many(TType::Ident("".to_owned()))?.len() as u8
-                            } else {
-                                0u8
-                            };
+                            let mut fields = vec![];
+                            while mul && !self.is_at_end() && self.peek().unwrap().ttype != TType::RParen {
+                                fields.push(self.parse_type()?);
+                            }
 
-                            var_len.insert(vname, length);
+                            variants.push((vname, fields));
 
                             if mul {
                                 self.advance(TType::RParen)?;
@@ -431,11 +731,14 @@ impl Parser {
 
                         self.advance(TType::RParen)?;
 
-                        Expr::new(ExprT::Enum(name, var_len)).line(subroot.line)
+                        Expr::new(ExprT::Enum(name, variants)).line(subroot.span.line)
                     }
                     TType::Lambda => {
                         self.advance(TType::LParen)?;
-                        let args = self.advance_many(TType::Ident("".to_owned()))?;
+                        let mut args = vec![];
+                        while matches!(self.peek().map(|t| t.ttype), Some(TType::Ident(_))) || (self.quasi && self.peek().map(|t| t.ttype) == Some(TType::Unquote)) {
+                            args.push(self.binder()?);
+                        }
                         self.advance(TType::RParen)?;
 
                         let args = args
@@ -452,7 +755,7 @@ impl Parser {
                         let body = self.parse_expr()?;
 
                         self.advance(TType::RParen)?;
-                        Expr::new(ExprT::Lambda(args, Box::new(body))).line(subroot.line)
+                        Expr::new(ExprT::Lambda(args, Box::new(body))).line(subroot.span.line)
                     }
                     TType::Tuple => {
                         let mut args = vec![];
@@ -460,11 +763,14 @@ impl Parser {
                             args.push(self.parse_expr()?);
                         }
                         self.advance(TType::RParen)?;
-                        Expr::new(ExprT::Tuple(args)).line(subroot.line)
+                        Expr::new(ExprT::Tuple(args)).line(subroot.span.line)
                     }
-                    TType::RParen => Expr::new(ExprT::Tuple(vec![])).line(subroot.line),
-                    TType::LParen | TType::Ident(_) => {
+                    TType::RParen => Expr::new(ExprT::Tuple(vec![])).line(subroot.span.line),
+                    TType::LParen | TType::Ident(_) | TType::Unquote => {
                         self.current -= 1; // Safe because at least 1 paren
+                        if subroot.ttype == TType::LParen {
+                            self.open.pop();
+                        }
                         let func = self.parse_expr()?;
 
                         let mut args = vec![];
@@ -476,46 +782,71 @@ impl Parser {
 
                         if let TType::Ident(x) = &subroot.ttype {
                             if first_char(x).is_ascii_uppercase() {
-                                Expr::new(ExprT::Constr(x.to_string(), args)).line(subroot.line)
+                                Expr::new(ExprT::Constr(x.to_string(), args)).line(subroot.span.line)
                             } else {
-                                Expr::new(ExprT::Call(Box::new(func), args)).line(subroot.line)
+                                Expr::new(ExprT::Call(Box::new(func), args)).line(subroot.span.line)
                             }
                         } else {
-                            Expr::new(ExprT::Call(Box::new(func), args)).line(subroot.line)
+                            Expr::new(ExprT::Call(Box::new(func), args)).line(subroot.span.line)
                         }
                     }
-                    _ => return error!(self.file, subroot.line => "Unexpected Literal."),
+                    _ => return error!(E0001; self.file, subroot.span => "Unexpected Literal."),
                 }
             }
-            TType::RParen => {
-                return error!(
+            ttype if is_closing(ttype) => {
+                return error!(E0001;
                     self.file,
-                    root.line => 
-                    "Unexpected Closing Parenthese.",
+                    root.span =>
+                    "Unexpected {}.",
+                    ttype.get_type(),
                     )
             }
-            _ => return error!(self.file, root.line => "Unexpected Keyword."),
+            _ => return error!(E0001; self.file, root.span => "Unexpected Keyword."),
         })
     }
 
+    // Skips the tokens of the form an error was found in, up to its closing delimiter or to an
+    // opening parenthese starting a line, that begins the next top level form.
+    fn recover(&mut self) {
+        while !self.open.is_empty() && !self.is_at_end() {
+            let next = self.peek().unwrap();
+            if next.ttype == TType::LParen && next.span.column == 1 {
+                break;
+            }
+            if self.pop().is_err() || self.input.len() == 1 {
+                break;
+            }
+        }
+    }
+
     pub fn parse(&mut self) -> Result<Vec<Expr>> {
         while !self.is_at_end() {
-            let to_push = self.parse_expr()?;
-            self.output.push(to_push);
+            self.open.clear();
+            self.template = false;
+            match self.parse_expr() {
+                Ok(to_push) => self.output.push(to_push),
+                Err(e) => {
+                    self.errors.push(e);
+                    self.recover();
+                }
+            }
 
             if self.input.len() == 1 {
                 break;
             }
         }
 
-        Ok(self.output.clone())
+        match OrionError::merge(std::mem::take(&mut self.errors)) {
+            Some(e) => Err(e),
+            None => Ok(self.output.clone()),
+        }
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::{lexer::Lexer, table};
+    use crate::lexer::Lexer;
 
     #[test]
     fn variables() -> Result<()> {
@@ -547,6 +878,7 @@ mod test {
     }
 
     #[test]
+    #[allow(clippy::approx_constant, clippy::excessive_precision)]
     fn literal() -> Result<()> {
         let tokens = Lexer::new("\"foo\" 42 3.1415926535897932", 0).proc_tokens()?;
         let ast = Parser::new(tokens, "TEST").parse()?;
@@ -581,7 +913,7 @@ mod test {
 
     #[test]
     fn def() -> Result<()> {
-        let tokens = Lexer::new("(def foo 5)(def 'impure moo jsp)", 0).proc_tokens()?;
+        let tokens = Lexer::new("(def foo 5)(def 'impure moo :: (-> 'impure String) jsp)(def bar :: (-> (List a) (, Integer ()) b) baz)", 0).proc_tokens()?;
         let ast = Parser::new(tokens, "TEST").parse()?;
 
         assert_eq!(
@@ -590,11 +922,23 @@ mod test {
             Expr::new(ExprT::Def(
                     "foo".to_string(),
                     Box::new(Expr::new(ExprT::Literal(Literal::Integer(5)))),
-                    false)),
+                    false,
+                    None)),
                     Expr::new(ExprT::Def(
                             "moo".to_string(),
                             Box::new(Expr::new(ExprT::Var("jsp".to_string()))),
-                            true))]);
+                            true,
+                            Some(TypeExpr::Function(vec![], Box::new(TypeExpr::Named("String".to_string(), vec![])), true)))),
+                    Expr::new(ExprT::Def(
+                            "bar".to_string(),
+                            Box::new(Expr::new(ExprT::Var("baz".to_string()))),
+                            false,
+                            Some(TypeExpr::Function(
+                                    vec![
+                                    TypeExpr::Named("List".to_string(), vec![TypeExpr::Var("a".to_string())]),
+                                    TypeExpr::Tuple(vec![TypeExpr::Named("Integer".to_string(), vec![]), TypeExpr::Tuple(vec![])])],
+                                    Box::new(TypeExpr::Var("b".to_string())),
+                                    false))))]);
 
         Ok(())
     }
@@ -615,15 +959,17 @@ mod test {
 
     #[test]
     fn r#enum() -> Result<()> {
-        let tokens = Lexer::new("(enum Maybe (Just x) Nil)", 0).proc_tokens()?;
+        let tokens = Lexer::new("(enum Maybe (Just x) Nil)(enum List (Cons a (List a)) Nil)", 0).proc_tokens()?;
         let ast = Parser::new(tokens, "TEST").parse()?;
 
         assert_eq!(
             ast,
             vec![Expr::new(ExprT::Enum(
                     "Maybe".to_string(),
-                    table! {"Just".to_string() => 1u8, "Nil".to_string() => 0u8}
-                    ))]
+                    vec![("Just".to_string(), vec![TypeExpr::Var("x".to_string())]), ("Nil".to_string(), vec![])])),
+                    Expr::new(ExprT::Enum(
+                    "List".to_string(),
+                    vec![("Cons".to_string(), vec![TypeExpr::Var("a".to_string()), TypeExpr::Named("List".to_string(), vec![TypeExpr::Var("a".to_string())])]), ("Nil".to_string(), vec![])]))]
             );
 
         Ok(())
@@ -666,8 +1012,8 @@ mod test {
             vec![Expr::new(ExprT::Match(
                     Box::new(Expr::new(ExprT::Var("foo".to_string()))),
                     vec![
-                    (Pattern::Var("bar".to_string()), Expr::new(ExprT::Var("x".to_string()))),
-                    (Pattern::Var("_".to_string()),
+                    (Pattern::Var("bar".to_string()), Span { start: 12, end: 15, line: 1, column: 13 }, Expr::new(ExprT::Var("x".to_string()))),
+                    (Pattern::Var("_".to_string()), Span { start: 19, end: 20, line: 1, column: 20 },
                     Expr::new(ExprT::Literal(Literal::Integer(9))))]))]);
 
         Ok(())
@@ -713,4 +1059,30 @@ mod test {
                     Expr::new(ExprT::Var("a".to_string()))]))]);
         Ok(())
     }
+
+    #[test]
+    fn errors() {
+        let tokens = Lexer::new("(def Foo 1)\n(enum maybe (just x))\n(def bar (", "TEST").proc_tokens().unwrap();
+        let errors = Parser::new(tokens, "TEST").parse().unwrap_err();
+        let errors = errors.diagnostics().iter().map(|e| (e.line, e.message.as_str())).collect::<Vec<_>>();
+        assert_eq!(errors, vec![
+            (Some(1), "Literal names have to start with a lowercase letter."),
+            (Some(2), "Enum names have to start with a uppercase letter."),
+            (Some(2), "Enum variant names have to start with a uppercase letter."),
+            (Some(3), "Unclosed Opening Parenthese."),
+        ]);
+    }
+
+    #[test]
+    fn recovery() {
+        let tokens = Lexer::new("(def f (λ (x) (+ x 1))\n(def g (foo ]\n(def h 2)\n)\n(def k [1\n  2", "TEST").proc_tokens().unwrap();
+        let errors = Parser::new(tokens, "TEST").parse().unwrap_err();
+        let errors = errors.diagnostics().iter().map(|e| (e.span.map(|s| (s.line, s.column)), e.message.as_str())).collect::<Vec<_>>();
+        assert_eq!(errors, vec![
+            (Some((1, 1)), "Unclosed Opening Parenthese."),
+            (Some((2, 13)), "Unexpected Closing Bracket."),
+            (Some((4, 1)), "Unexpected Closing Parenthese."),
+            (Some((5, 8)), "Unclosed Opening Bracket."),
+        ]);
+    }
 }
@@ -0,0 +1,255 @@
+/*
+ *  Copyright (C) 2021, Wafelack <wafelack@protonmail.com>
+ *
+ *  ------------------------------------------------------
+ *
+ *     This file is part of Orion.
+ *
+ *  Orion is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  Orion is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with Orion.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::parser::{Expr, ExprT, Literal, Pattern, TypeExpr};
+
+// The width past which the forms are broken over several lines.
+const WIDTH: usize = 80;
+
+enum Form {
+    Atom(String),
+    Prefixed(&'static str, Box<Form>),
+    List(&'static str, Vec<Form>, &'static str, usize), // (opening, elements, closing, amount of elements kept on the first line)
+}
+
+impl Form {
+    fn list(elements: Vec<Form>, kept: usize) -> Self {
+        Self::List("(", elements, ")", kept)
+    }
+    fn flat(&self) -> String {
+        match self {
+            Self::Atom(atom) => atom.clone(),
+            Self::Prefixed(prefix, form) => format!("{}{}", prefix, form.flat()),
+            Self::List(opening, elements, closing, _) => format!("{}{}{}", opening, elements.iter().map(|e| e.flat()).collect::<Vec<_>>().join(" "), closing),
+        }
+    }
+    // The form on its line if it fits, or with its elements after the kept ones indented on their own lines.
+    fn layout(&self, indent: usize, output: &mut String) {
+        let flat = self.flat();
+        match self {
+            Self::List(opening, elements, closing, kept) if indent + flat.chars().count() > WIDTH => {
+                let kept = (*kept).clamp(1, elements.len().max(1)).min(elements.len());
+                output.push_str(opening);
+                for (i, element) in elements.iter().enumerate() {
+                    if i >= kept {
+                        output.push('\n');
+                        output.push_str(&" ".repeat(indent + 2));
+                    } else if i > 0 {
+                        output.push(' ');
+                    }
+                    element.layout(indent + 2, output);
+                }
+                output.push_str(closing);
+            }
+            Self::Prefixed(prefix, form) => {
+                output.push_str(prefix);
+                form.layout(indent + prefix.len(), output);
+            }
+            _ => output.push_str(&flat),
+        }
+    }
+}
+
+// The Orion source of an expression, that parses back to the same expression.
+pub fn show(expr: &Expr) -> String {
+    let mut output = String::new();
+    form(expr).layout(0, &mut output);
+    output
+}
+
+fn atom(atom: impl ToString) -> Form {
+    Form::Atom(atom.to_string())
+}
+
+fn all<T>(items: &[T], f: fn(&T) -> Form) -> impl Iterator<Item = Form> + '_ {
+    items.iter().map(f)
+}
+
+fn literal(literal: &Literal) -> Form {
+    atom(match literal {
+        Literal::Integer(i) => i.to_string(),
+        Literal::Single(f) => format!("{:?}", f),
+        Literal::String(s) => format!(
+            "\"{}\"",
+            s.replace('\\', "\\\\").replace('\x1b', "\\x1b").replace('\n', "\\n").replace('\r', "\\r").replace('\t', "\\t").replace('\0', "\\0")
+            ),
+    })
+}
+
+// The elements of a list written with brackets, that is made of `Cons` ending with `Nil`.
+fn elements(expr: &Expr) -> Option<Vec<&Expr>> {
+    match &expr.exprt {
+        ExprT::Constr(name, args) if name == "Nil" && args.is_empty() => Some(vec![]),
+        ExprT::Constr(name, args) if name == "Cons" && args.len() == 2 => elements(&args[1]).map(|mut tail| {
+            tail.insert(0, &args[0]);
+            tail
+        }),
+        _ => None,
+    }
+}
+
+fn form(expr: &Expr) -> Form {
+    let exprs = |exprs: &[Expr]| all(exprs, form).collect::<Vec<_>>();
+    let headed = |head: &str, rest: &[Expr], kept: usize| Form::list(std::iter::once(atom(head)).chain(all(rest, form)).collect(), kept);
+    match &expr.exprt {
+        ExprT::Var(name) => atom(name),
+        ExprT::Literal(lit) => literal(lit),
+        ExprT::Call(func, args) => Form::list(std::iter::once(form(func)).chain(exprs(args)).collect(), 1),
+        ExprT::Lambda(params, body) => Form::list(vec![atom("λ"), Form::list(all(params, |param| atom(param)).collect(), 0), form(body)], 2),
+        ExprT::Def(name, value, impure, signature) => {
+            let mut forms = vec![atom("def")];
+            if *impure {
+                forms.push(atom("'impure"));
+            }
+            forms.push(atom(name));
+            if let Some(signature) = signature {
+                forms.push(atom("::"));
+                forms.push(r#type(signature));
+            }
+            let kept = forms.len();
+            forms.push(form(value));
+            Form::list(forms, kept)
+        }
+        ExprT::Macro(name, clauses) => {
+            let clause = |(args, rest, body): &(Vec<String>, Option<String>, Expr)| {
+                let mut args = all(args, |arg| atom(arg)).collect::<Vec<_>>();
+                if let Some(rest) = rest {
+                    args.push(atom("."));
+                    args.push(atom(rest));
+                }
+                vec![Form::list(args, 0), form(body)]
+            };
+            match &clauses[..] {
+                [single] => Form::list(vec![atom("macro"), atom(name)].into_iter().chain(clause(single)).collect(), 3),
+                _ => Form::list(vec![atom("macro"), atom(name)].into_iter().chain(clauses.iter().map(|c| Form::list(clause(c), 1))).collect(), 2),
+            }
+        }
+        ExprT::Constr(name, args) => match elements(expr) {
+            Some(elements) if !elements.is_empty() => Form::List("[", elements.into_iter().map(form).collect(), "]", 0),
+            _ if args.is_empty() => atom(name),
+            _ => headed(name, args, 1),
+        },
+        ExprT::Enum(name, variants) => Form::list(
+            vec![atom("enum"), atom(name)].into_iter().chain(variants.iter().map(|(variant, fields)| if fields.is_empty() {
+                atom(variant)
+            } else {
+                Form::list(std::iter::once(atom(variant)).chain(all(fields, r#type)).collect(), 1)
+            })).collect(),
+            2,
+            ),
+        ExprT::Tuple(args) if args.is_empty() => atom("()"),
+        ExprT::Tuple(args) => headed(",", args, 1),
+        ExprT::Load(files) => Form::list(std::iter::once(atom("load")).chain(files.iter().map(|f| literal(&Literal::String(f.clone())))).collect(), files.len() + 1),
+        ExprT::Module(name, exports) => Form::list(
+            vec![atom("module"), atom(name), Form::list(std::iter::once(atom("export")).chain(all(exports, |export| atom(export))).collect(), 1)],
+            3,
+            ),
+        ExprT::Import(file, alias, names) => {
+            let mut forms = vec![atom("import"), literal(&Literal::String(file.clone()))];
+            forms.extend(alias.iter().map(atom));
+            if !names.is_empty() {
+                forms.push(Form::list(all(names, |name| atom(name)).collect(), 0));
+            }
+            let kept = forms.len();
+            Form::list(forms, kept)
+        }
+        ExprT::Match(value, arms) => Form::list(
+            vec![atom("match"), form(value)].into_iter().chain(arms.iter().map(|(pat, _, body)| Form::list(vec![pattern(pat), form(body)], 1))).collect(),
+            2,
+            ),
+        ExprT::Begin(exprs) => headed("begin", exprs, 1),
+        ExprT::Builtin(name, args) => headed(name, args, 1),
+        ExprT::Let(binding, bindings, body) => Form::list(
+            vec![
+                atom(binding.keyword()),
+                Form::list(bindings.iter().map(|(name, value)| Form::list(vec![atom(name), form(value)], 1)).collect(), 0),
+                form(body),
+            ],
+            2,
+            ),
+        ExprT::Quasiquote(template) => Form::Prefixed("`", Box::new(form(template))),
+        ExprT::Unquote(name) => atom(format!(",{}", name)),
+        ExprT::Splice(name) => atom(format!(",@{}", name)),
+    }
+}
+
+fn pattern(pat: &Pattern) -> Form {
+    match pat {
+        Pattern::Var(name) => atom(name),
+        Pattern::Literal(lit) => literal(lit),
+        Pattern::Constr(name, pats) if pats.is_empty() => atom(name),
+        Pattern::Constr(name, pats) => Form::list(std::iter::once(atom(name)).chain(all(pats, pattern)).collect(), 1),
+        Pattern::Tuple(pats) if pats.is_empty() => atom("()"),
+        Pattern::Tuple(pats) => Form::list(std::iter::once(atom(",")).chain(all(pats, pattern)).collect(), 1),
+    }
+}
+
+fn r#type(ty: &TypeExpr) -> Form {
+    match ty {
+        TypeExpr::Var(name) => atom(name),
+        TypeExpr::Named(name, args) if args.is_empty() => atom(name),
+        TypeExpr::Named(name, args) => Form::list(std::iter::once(atom(name)).chain(all(args, r#type)).collect(), 1),
+        TypeExpr::Tuple(types) if types.is_empty() => atom("()"),
+        TypeExpr::Tuple(types) => Form::list(std::iter::once(atom(",")).chain(all(types, r#type)).collect(), 1),
+        TypeExpr::Function(args, returned, impure) => {
+            let head = if *impure { vec![atom("->"), atom("'impure")] } else { vec![atom("->")] };
+            Form::list(head.into_iter().chain(all(args, r#type)).chain(std::iter::once(r#type(returned))).collect(), 1)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{lexer::{Lexer, Span}, parser::Parser};
+
+    const SPAN: Span = Span { start: 0, end: 0, line: 1, column: 1 };
+
+    fn parse(code: &str) -> Vec<Expr> {
+        Parser::new(Lexer::new(code, "TEST").proc_tokens().unwrap(), "TEST").parse().unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        let code = "(module main (export main x))
+(def 'impure main :: (-> 'impure (List (, a Int)) ()) (λ (xs) (match xs (Nil ()) ((Cons (, x 1.5) _) (putStr \"a\\nb\")) (_ { (f [1 2 3]) 'x }))))
+(enum Maybe (Just a) Nothing)
+(macro all (() True) ((x . rest) `(match ,x (True (all ,@rest)) (False False))))
+(load \"list.orn\")
+(import \"list.orn\" l (map))
+(import \"string.orn\")
+(def x (let* ((a 1) (b a)) (letrec ((f (λ () (g))) (g (λ () b))) (let ((c (f))) c))))";
+        for expr in parse(code) {
+            let shown = show(&expr);
+            assert_eq!(parse(&shown).remove(0).relocate(SPAN), expr.relocate(SPAN), "{}", shown);
+        }
+    }
+
+    #[test]
+    fn layout() {
+        let expr = parse("(def f (λ (x) (match (some-long-function-name x) (True (another-long-function-name x)) (False x))))").remove(0);
+        assert_eq!(show(&expr), "(def f
+  (λ (x)
+    (match (some-long-function-name x)
+      (True (another-long-function-name x))
+      (False x))))");
+        assert_eq!(show(&parse("(f [1 2] (, 1 \"a\"))").remove(0)), "(f [1 2] (, 1 \"a\"))");
+    }
+}
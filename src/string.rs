@@ -25,8 +25,8 @@ impl<const STACK_SIZE: usize> VM<STACK_SIZE> {
     pub fn format(&mut self) -> Result<Rc<Value>> {
         let args = self.pop()?;
         let formatter = self.pop()?;
-        if let Value::Tuple(args) = (*args).clone() {
-            if let Value::String(formatter) = (*formatter).clone() {
+        if let Value::Tuple(args) = &*args {
+            if let Value::String(formatter) = &*formatter {
                 let fmt = "{}";
                 let mut prev = 0;
                 let to_ret = formatter.match_indices(fmt).enumerate().map(|(idx, (pos, _))| {
@@ -37,27 +37,27 @@ impl<const STACK_SIZE: usize> VM<STACK_SIZE> {
 
                 Ok(Rc::new(Value::String(format!("{}{}", to_ret, &formatter[prev..]))))
             } else {
-                error!(=> "Expected a String, found a {}.", self.val_type(&formatter)?)
+                error!(E0008; => "Expected a String, found a {}.", self.val_type(&formatter)?)
             }
         } else {
-            error!(=> "Expected a Tuple, found a {}.", self.val_type(&args)?)
+            error!(E0008; => "Expected a Tuple, found a {}.", self.val_type(&args)?)
         }
     }
     pub fn get(&mut self) -> Result<Rc<Value>> {
         let idx = self.pop()?;
         let string = self.pop()?;
-        if let Value::Integer(i) = (*idx).clone() {
-            if let Value::String(s) = (*string).clone() {
+        if let Value::Integer(i) = *idx {
+            if let Value::String(s) = &*string {
                 Ok(Rc::new(Value::String(if i < 0 {
                     "".to_string()
                 } else {
-                    s.chars().nth(i as usize).and_then(|c| Some(format!("{}", c))).unwrap_or("".to_string())
+                    s.chars().nth(i as usize).map(|c| format!("{}", c)).unwrap_or("".to_string())
                 })))
             } else {
-                error!(=> "Expected a String, found a {}.", self.val_type(&string)?)
+                error!(E0008; => "Expected a String, found a {}.", self.val_type(&string)?)
             }
         } else {
-            error!(=> "Expected an Integer, found a {}.", self.val_type(&idx)?)
+            error!(E0008; => "Expected an Integer, found a {}.", self.val_type(&idx)?)
         }
     }
 }
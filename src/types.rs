@@ -0,0 +1,818 @@
+/*
+ *  Copyright (C) 2021, Wafelack <wafelack@protonmail.com>
+ *
+ *  ------------------------------------------------------
+ *
+ *     This file is part of Orion.
+ *
+ *  Orion is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  Orion is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with Orion.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::{
+    compiler::BUILTINS,
+    macros::Macro,
+    modules::{Import, Modules},
+    bug, error,
+    errors::closest,
+    lexer::{Lexer, Span},
+    parser::{Binding, Expr, ExprT, Literal, Parser, Pattern, TypeExpr},
+    Result,
+};
+use std::{collections::HashMap, fs, path::Path};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Type {
+    Var(u32),
+    Param(String), // A variable of a signature, that only matches itself.
+    Integer,
+    Single,
+    String,
+    Tuple(Vec<Type>),
+    Function(Vec<Type>, Box<Type>, bool), // (arguments, returned, impure?)
+    Enum(String, Vec<Type>),
+}
+impl Type {
+    fn unit() -> Self {
+        Self::Tuple(vec![])
+    }
+    fn vars(&self, vars: &mut Vec<u32>) {
+        match self {
+            Self::Var(var) if !vars.contains(var) => vars.push(*var),
+            Self::Tuple(types) | Self::Enum(_, types) => types.iter().for_each(|ty| ty.vars(vars)),
+            Self::Function(args, returned, _) => {
+                args.iter().for_each(|ty| ty.vars(vars));
+                returned.vars(vars);
+            }
+            _ => {}
+        }
+    }
+    fn params(&self, params: &mut Vec<String>) {
+        match self {
+            Self::Param(name) if !params.contains(name) => params.push(name.clone()),
+            Self::Tuple(types) | Self::Enum(_, types) => types.iter().for_each(|ty| ty.params(params)),
+            Self::Function(args, returned, _) => {
+                args.iter().for_each(|ty| ty.params(params));
+                returned.params(params);
+            }
+            _ => {}
+        }
+    }
+    fn replace(&self, vars: &HashMap<u32, Type>) -> Self {
+        let all = |types: &[Type]| types.iter().map(|ty| ty.replace(vars)).collect();
+        match self {
+            Self::Var(var) => vars.get(var).cloned().unwrap_or_else(|| self.clone()),
+            Self::Tuple(types) => Self::Tuple(all(types)),
+            Self::Enum(name, types) => Self::Enum(name.clone(), all(types)),
+            Self::Function(args, returned, impure) => Self::Function(all(args), Box::new(returned.replace(vars)), *impure),
+            _ => self.clone(),
+        }
+    }
+    // Names the type variables `a`, `b`, ... in the order they appear in `names`.
+    fn show(&self, names: &mut Vec<u32>) -> String {
+        let mut all = |types: &[Type]| types.iter().map(|ty| ty.show(names)).collect::<Vec<_>>().join(" ");
+        match self {
+            Self::Var(var) => {
+                let idx = names.iter().position(|name| name == var).unwrap_or_else(|| {
+                    names.push(*var);
+                    names.len() - 1
+                });
+                let letter = (b'a' + (idx % 26) as u8) as char;
+                if idx < 26 { letter.to_string() } else { format!("{}{}", letter, idx / 26) }
+            }
+            Self::Param(name) => name.clone(),
+            Self::Integer => "Integer".to_string(),
+            Self::Single => "Single".to_string(),
+            Self::String => "String".to_string(),
+            Self::Tuple(types) if types.is_empty() => "()".to_string(),
+            Self::Tuple(types) => format!("(, {})", all(types)),
+            Self::Enum(name, types) if types.is_empty() => name.clone(),
+            Self::Enum(name, types) => format!("({} {})", name, all(types)),
+            Self::Function(args, returned, impure) => {
+                let effect = if *impure { "'impure " } else { "" };
+                let args = args.iter().map(|ty| format!("{} ", ty.show(names))).collect::<String>();
+                format!("(-> {}{}{})", effect, args, returned.show(names))
+            }
+        }
+    }
+}
+
+// A type whose `vars` are replaced by new variables each time it is used.
+#[derive(Debug, Clone)]
+struct Scheme {
+    vars: Vec<u32>,
+    ty: Type,
+}
+impl Scheme {
+    fn mono(ty: Type) -> Self {
+        Self { vars: vec![], ty }
+    }
+}
+
+enum Failure {
+    Mismatch,
+    Infinite(u32, Type),
+    NotNumber(Type),
+}
+
+// The types of the builtins, the variables listed in `numeric` standing for an Integer or a Single.
+fn builtin(name: &str) -> Option<(Vec<Type>, Type, bool)> { // (arguments, returned, numeric?)
+    let a = || Type::Var(0);
+    Some(match name {
+        "+" | "-" | "*" | "/" => (vec![a(), a()], a(), true),
+        "neg" => (vec![a()], a(), true),
+        "cos" | "sin" | "tan" | "acos" | "asin" | "atan" => (vec![Type::Single], Type::Single, false),
+        "format" => (vec![Type::String, a()], Type::String, false),
+        "get" => (vec![Type::String, Type::Integer], Type::String, false),
+        "putStr" => (vec![Type::String], Type::unit(), false),
+        "getLine" => (vec![], Type::String, false),
+        "type" => (vec![a()], Type::String, false),
+        "_cmp" => (vec![a(), a()], Type::Integer, false),
+        "panic" => (vec![a()], Type::Var(1), false),
+        _ => return None,
+    })
+}
+
+// Infers the types of a program before it is compiled, rejecting the mismatches that would make the VM fail.
+#[derive(Clone)]
+pub struct Checker {
+    file: String,
+    lib: String,
+    env: Vec<(String, Scheme)>,
+    constructors: HashMap<String, Scheme>,
+    enums: HashMap<String, Vec<Option<(String, usize)>>>, // The (constructor, field) that may be recursive, for each type parameter of an enum.
+    recursive: Vec<(String, usize)>, // The (constructor, field) holding a value of the same enum.
+    found: Option<(String, usize)>,
+    macros: Vec<(String, Macro)>,
+    gensyms: usize, // The amount of names made up by the macro expansions so far.
+    expanding: usize, // The amount of macro expansions the expression being checked comes from.
+    subst: Vec<Option<Type>>,
+    numeric: Vec<bool>,
+    modules: Modules,
+    globals: usize, // The length of the environment before the top level expression being checked, the variables after it being local.
+    depth: usize, // The amount of lambdas the expression being checked is in.
+    program: Vec<Expr>,
+    repl: bool,
+    impure: bool, // Whether the expression being checked may use impure functions.
+    effects: bool, // Whether the function being checked used impure functions.
+}
+
+impl Checker {
+    pub fn new(file: impl ToString, lib: String, repl: bool) -> Self {
+        Self {
+            file: file.to_string(),
+            lib,
+            env: vec![],
+            constructors: HashMap::new(),
+            enums: HashMap::new(),
+            recursive: vec![],
+            found: None,
+            macros: vec![],
+            gensyms: 0,
+            expanding: 0,
+            subst: vec![],
+            numeric: vec![],
+            modules: Modules::default(),
+            globals: 0,
+            depth: 0,
+            program: vec![],
+            repl,
+            impure: repl,
+            effects: false,
+        }
+    }
+    // Checks new expressions after the previous ones, leaving the checker unchanged if they are rejected.
+    pub fn check(&mut self, input: Vec<Expr>) -> Result<()> {
+        let saved = self.clone();
+        let mut exprs = if self.program.is_empty() { vec![Expr::new(ExprT::Load(vec!["prelude.orn".to_string()])).line(0)] } else { vec![] };
+        exprs.extend(input);
+        let mut start = self.program.len();
+        loop {
+            let file = self.file.clone();
+            let checked = self.program.iter().chain(&exprs).skip(start).cloned().collect::<Vec<_>>().iter().try_for_each(|expr| self.top_level(expr).map(|_| ()));
+            self.file = file;
+            match checked {
+                Ok(()) => {
+                    self.program.extend(exprs);
+                    return Ok(());
+                }
+                // A field found to hold the enum itself is no longer a type parameter, so everything is checked again.
+                Err(e) => match self.found.take() {
+                    Some(field) if !self.recursive.contains(&field) => {
+                        self.recursive.push(field);
+                        *self = Self {
+                            file: self.file.clone(),
+                            lib: self.lib.clone(),
+                            recursive: self.recursive.clone(),
+                            program: self.program.clone(),
+                            ..Self::new("", String::new(), self.repl)
+                        };
+                        start = 0;
+                    }
+                    _ => {
+                        *self = saved;
+                        return Err(e);
+                    }
+                },
+            }
+        }
+    }
+    // The scheme of a variable, the local ones hiding the definitions of the files.
+    fn variable(&self, name: &str, span: Span) -> Result<Option<Scheme>> {
+        if let Some((_, scheme)) = self.env[self.globals.min(self.env.len())..].iter().rev().find(|(var, _)| var == name) {
+            return Ok(Some(scheme.clone()));
+        }
+        let global = self.modules.resolve(name, &self.file, span)?;
+        Ok(self.env.iter().rev().find(|(var, _)| *var == global).map(|(_, scheme)| scheme.clone()))
+    }
+    fn fresh(&mut self) -> Type {
+        self.subst.push(None);
+        self.numeric.push(false);
+        Type::Var(self.subst.len() as u32 - 1)
+    }
+    fn resolve(&self, ty: &Type) -> Type {
+        match ty {
+            Type::Var(var) => match &self.subst[*var as usize] {
+                Some(ty) => self.resolve(ty),
+                None => ty.clone(),
+            },
+            Type::Tuple(types) => Type::Tuple(types.iter().map(|ty| self.resolve(ty)).collect()),
+            Type::Enum(name, types) => Type::Enum(name.clone(), types.iter().map(|ty| self.resolve(ty)).collect()),
+            Type::Function(args, returned, impure) => Type::Function(args.iter().map(|ty| self.resolve(ty)).collect(), Box::new(self.resolve(returned)), *impure),
+            _ => ty.clone(),
+        }
+    }
+    fn show(&self, types: &[&Type]) -> Vec<String> {
+        let mut names = vec![];
+        types.iter().map(|ty| self.resolve(ty).show(&mut names)).collect()
+    }
+    fn unify(&mut self, expected: &Type, found: &Type, span: Span) -> Result<()> {
+        match self.unify_inner(expected, found) {
+            Ok(()) => Ok(()),
+            Err(Failure::Mismatch) => {
+                let shown = self.show(&[expected, found]);
+                error!(E0004; self.file, span => "Mismatched types: expected `{}`, found `{}`.", shown[0], shown[1])
+            }
+            Err(Failure::Infinite(var, ty)) => {
+                let shown = self.show(&[&Type::Var(var), &ty]);
+                error!(E0004; self.file, span => "Infinite type: `{}` would be `{}`.", shown[0], shown[1])
+            }
+            Err(Failure::NotNumber(ty)) => error!(E0004; self.file, span => "Expected a Single or an Integer, found `{}`.", self.show(&[&ty])[0]),
+        }
+    }
+    fn unify_inner(&mut self, lhs: &Type, rhs: &Type) -> std::result::Result<(), Failure> {
+        let all = |checker: &mut Self, lhs: &[Type], rhs: &[Type]| if lhs.len() == rhs.len() {
+            lhs.iter().zip(rhs).try_for_each(|(lhs, rhs)| checker.unify_inner(lhs, rhs))
+        } else {
+            Err(Failure::Mismatch)
+        };
+        let (lhs, rhs) = (self.shallow(lhs), self.shallow(rhs));
+        match (&lhs, &rhs) {
+            (Type::Var(lhs), Type::Var(rhs)) if lhs == rhs => Ok(()),
+            (Type::Param(lhs), Type::Param(rhs)) if lhs == rhs => Ok(()),
+            (Type::Var(var), ty) | (ty, Type::Var(var)) => self.bind(*var, ty),
+            (Type::Integer, Type::Integer) | (Type::Single, Type::Single) | (Type::String, Type::String) => Ok(()),
+            (Type::Tuple(lhs), Type::Tuple(rhs)) => all(self, lhs, rhs),
+            (Type::Enum(lname, lhs), Type::Enum(rname, rhs)) if lname == rname => all(self, lhs, rhs),
+            // A pure function can be used where an impure one is expected, and not the other way around.
+            (Type::Function(_, _, false), Type::Function(_, _, true)) => Err(Failure::Mismatch),
+            (Type::Function(largs, lret, _), Type::Function(rargs, rret, _)) => {
+                all(self, rargs, largs)?;
+                self.unify_inner(lret, rret)
+            }
+            _ => Err(Failure::Mismatch),
+        }
+    }
+    fn shallow(&self, ty: &Type) -> Type {
+        match ty {
+            Type::Var(var) => match &self.subst[*var as usize] {
+                Some(ty) => self.shallow(ty),
+                None => ty.clone(),
+            },
+            _ => ty.clone(),
+        }
+    }
+    fn bind(&mut self, var: u32, ty: &Type) -> std::result::Result<(), Failure> {
+        let ty = self.resolve(ty);
+        match &ty {
+            Type::Var(other) => self.numeric[*other as usize] |= self.numeric[var as usize],
+            Type::Integer | Type::Single => {}
+            _ if self.numeric[var as usize] => return Err(Failure::NotNumber(ty)),
+            _ => {}
+        }
+        let mut vars = vec![];
+        ty.vars(&mut vars);
+        if vars.contains(&var) {
+            // An enum containing itself through one of its fields makes that field recursive.
+            if let Type::Enum(name, params) = &ty {
+                if let Some(idx) = params.iter().position(|param| *param == Type::Var(var)) {
+                    self.found = self.enums[name][idx].clone();
+                }
+            }
+            return Err(Failure::Infinite(var, ty));
+        }
+        self.subst[var as usize] = Some(ty);
+        Ok(())
+    }
+    // The type variables that the environment may still refine.
+    fn bound(&self) -> Vec<u32> {
+        let mut bound = vec![];
+        self.env.iter().for_each(|(_, scheme)| {
+            let mut vars = vec![];
+            self.resolve(&scheme.ty).vars(&mut vars);
+            bound.extend(vars.into_iter().filter(|var| !scheme.vars.contains(var)));
+        });
+        bound
+    }
+    fn generalize(&self, ty: &Type) -> Scheme {
+        let ty = self.resolve(ty);
+        let bound = self.bound();
+        let mut vars = vec![];
+        ty.vars(&mut vars);
+        Scheme {
+            vars: vars.into_iter().filter(|var| !bound.contains(var)).collect(),
+            ty,
+        }
+    }
+    fn instantiate(&mut self, scheme: &Scheme) -> Type {
+        let vars = scheme.vars.iter().map(|var| {
+            let fresh = self.fresh();
+            if let Type::Var(id) = fresh {
+                self.numeric[id as usize] = self.numeric[*var as usize];
+            }
+            (*var, fresh)
+        }).collect::<HashMap<_, _>>();
+        scheme.ty.replace(&vars)
+    }
+    fn literal(literal: &Literal) -> Type {
+        match literal {
+            Literal::Integer(_) => Type::Integer,
+            Literal::Single(_) => Type::Single,
+            Literal::String(_) => Type::String,
+        }
+    }
+    // Unifies the arguments given to a function one by one, to report the first mismatching one.
+    fn apply(&mut self, func: Type, args: &[Expr], span: Span) -> Result<Type> {
+        match self.shallow(&func) {
+            Type::Function(_, _, true) if !self.impure => error!(E0005; self.file, span => "Impure function called out of an `impure` declaration."),
+            Type::Function(params, returned, impure) if params.len() == args.len() => {
+                self.effects |= impure;
+                params.iter().zip(args).try_for_each(|(param, arg)| {
+                    let ty = self.infer(arg)?;
+                    self.unify(param, &ty, arg.span)
+                })?;
+                Ok(*returned)
+            }
+            Type::Function(params, ..) => error!(E0003; self.file, span => "Expected {} arguments, found {}.", params.len(), args.len()),
+            _ => {
+                let args = args.iter().map(|arg| self.infer(arg)).collect::<Result<Vec<_>>>()?;
+                let returned = self.fresh();
+                self.effects |= self.impure;
+                self.unify(&func, &Type::Function(args, Box::new(returned.clone()), self.impure), span)?;
+                Ok(returned)
+            }
+        }
+    }
+    fn constructor(&mut self, name: &str, given: usize, span: Span) -> Result<Type> {
+        let scheme = match self.constructors.get(name) {
+            Some(scheme) => scheme.clone(),
+            None => return error!(E0002; self.file, span => "Enum variant {} does not exist.", name),
+        };
+        let ty = self.instantiate(&scheme);
+        let expected = match &ty {
+            Type::Function(args, ..) => args.len(),
+            _ => 0,
+        };
+        if expected != given {
+            error!(E0003; self.file, span => "Constructor {} takes {} values, but {} values were given.", name, expected, given)
+        } else {
+            Ok(ty)
+        }
+    }
+    // Turns a written type into a type, its variables being either new type variables or signature variables.
+    fn translate(&mut self, ty: &TypeExpr, vars: &mut HashMap<String, Type>, rigid: bool, span: Span) -> Result<Type> {
+        let mut all = |checker: &mut Self, types: &[TypeExpr]| types.iter().map(|ty| checker.translate(ty, vars, rigid, span)).collect::<Result<Vec<_>>>();
+        Ok(match ty {
+            TypeExpr::Var(name) => match vars.get(name) {
+                Some(ty) => ty.clone(),
+                None => {
+                    let ty = if rigid { Type::Param(name.clone()) } else { self.fresh() };
+                    vars.insert(name.clone(), ty.clone());
+                    ty
+                }
+            },
+            TypeExpr::Tuple(types) => Type::Tuple(all(self, types)?),
+            TypeExpr::Function(args, returned, impure) => {
+                let args = all(self, args)?;
+                Type::Function(args, Box::new(self.translate(returned, vars, rigid, span)?), *impure)
+            }
+            TypeExpr::Named(name, args) => match (name.as_str(), self.enums.get(name)) {
+                ("Integer", _) | ("Single", _) | ("String", _) if !args.is_empty() => return error!(E0003; self.file, span => "Type {} takes 0 parameters, but {} were given.", name, args.len()),
+                ("Integer", _) => Type::Integer,
+                ("Single", _) => Type::Single,
+                ("String", _) => Type::String,
+                (_, Some(params)) if params.len() != args.len() => return error!(E0003; self.file, span => "Type {} takes {} parameters, but {} were given.", name, params.len(), args.len()),
+                (_, Some(_)) => Type::Enum(name.clone(), all(self, args)?),
+                (_, None) => return error!(E0002; self.file, span => "Unknown type: {}.", name),
+            },
+        })
+    }
+    fn define(&mut self, name: &str, constructors: &[(String, Vec<TypeExpr>)], span: Span) -> Result<()> {
+        // The variables of the fields are the type parameters of the enum, in order of appearance.
+        let mut names = vec![];
+        constructors.iter().flat_map(|(_, fields)| fields).for_each(|field| field.vars(&mut names));
+        let lone = |var: &str| names.iter().filter(|name| name.as_str() == var).count() == 1;
+        // A field being only a variable of its own may turn out to hold the enum itself.
+        let mut recursive = vec![];
+        let mut lone_fields = HashMap::new();
+        constructors.iter().for_each(|(constr, fields)| fields.iter().enumerate().for_each(|(idx, field)| match field {
+            TypeExpr::Var(var) if lone(var) && self.recursive.contains(&(constr.clone(), idx)) => recursive.push(var.clone()),
+            TypeExpr::Var(var) if lone(var) => {
+                lone_fields.insert(var.clone(), (constr.clone(), idx));
+            }
+            _ => {}
+        }));
+        let mut params = vec![];
+        names.into_iter().for_each(|var| if !params.contains(&var) && !recursive.contains(&var) {
+            params.push(var);
+        });
+
+        self.enums.insert(name.to_string(), params.iter().map(|var| lone_fields.get(var).cloned()).collect());
+        let mut vars = params.iter().map(|var| (var.clone(), self.fresh())).collect::<HashMap<_, _>>();
+        let ty = Type::Enum(name.to_string(), params.iter().map(|var| vars[var].clone()).collect());
+        recursive.into_iter().for_each(|var| {
+            vars.insert(var, ty.clone());
+        });
+        let quantified = params.iter().filter_map(|var| if let Type::Var(id) = vars[var] { Some(id) } else { None }).collect::<Vec<_>>();
+        constructors.iter().try_for_each(|(constr, fields)| {
+            let fields = fields.iter().map(|field| self.translate(field, &mut vars, false, span)).collect::<Result<Vec<_>>>()?;
+            let constr_ty = if fields.is_empty() { ty.clone() } else { Type::Function(fields, Box::new(ty.clone()), false) };
+            self.constructors.insert(constr.clone(), Scheme { vars: quantified.clone(), ty: constr_ty });
+            Ok(())
+        })
+    }
+    // Checks a definition against its signature, the signature being the type of the definition.
+    fn signature(&mut self, name: &str, signature: &TypeExpr, value: &Expr, span: Span) -> Result<()> {
+        let mut vars = HashMap::new();
+        let declared = self.translate(signature, &mut vars, true, span)?;
+        let mut vars = HashMap::new();
+        let ty = self.translate(signature, &mut vars, false, span)?;
+        let scheme = Scheme {
+            vars: vars.values().filter_map(|var| if let Type::Var(id) = var { Some(*id) } else { None }).collect(),
+            ty,
+        };
+        let bound = self.bound();
+        self.env.push((name.to_string(), scheme.clone()));
+        let found = self.infer(value)?;
+        self.unify(&declared, &found, span)?;
+        self.env.pop();
+        // The variables of the signature must not be given to the enclosing definitions.
+        let mut params = vec![];
+        bound.iter().for_each(|var| self.resolve(&Type::Var(*var)).params(&mut params));
+        if let Some(param) = params.first() {
+            return error!(E0004; self.file, span => "The type of {} is less general than its signature: `{}` cannot be any type.", name, param);
+        }
+        self.env.push((name.to_string(), scheme));
+        Ok(())
+    }
+    // The type of an expression, once the expressions checked so far are defined.
+    pub fn type_of(&self, expr: &Expr) -> Result<String> {
+        let mut checker = self.clone();
+        checker.check(vec![])?;
+        let ty = checker.top_level(expr)?;
+        let scheme = checker.generalize(&ty);
+        Ok(checker.show(&[&scheme.ty]).remove(0))
+    }
+    fn pattern(&mut self, pat: &Pattern, ty: &Type, span: Span) -> Result<()> {
+        match pat {
+            Pattern::Var(name) => {
+                if name != "_" {
+                    self.env.push((name.clone(), Scheme::mono(ty.clone())));
+                }
+                Ok(())
+            }
+            Pattern::Literal(literal) => self.unify(ty, &Self::literal(literal), span),
+            Pattern::Tuple(inside) => {
+                let fields = inside.iter().map(|_| self.fresh()).collect::<Vec<_>>();
+                self.unify(ty, &Type::Tuple(fields.clone()), span)?;
+                inside.iter().zip(&fields).try_for_each(|(pat, field)| self.pattern(pat, field, span))
+            }
+            Pattern::Constr(name, inside) => {
+                let (fields, constr) = match self.constructor(name, inside.len(), span)? {
+                    Type::Function(fields, constr, _) => (fields, *constr),
+                    constr => (vec![], constr),
+                };
+                self.unify(ty, &constr, span)?;
+                inside.iter().zip(&fields).try_for_each(|(pat, field)| self.pattern(pat, field, span))
+            }
+        }
+    }
+    fn top_level(&mut self, expr: &Expr) -> Result<Type> {
+        self.globals = self.env.len();
+        self.infer(expr)
+    }
+    fn load(&mut self, file: &str, import: Import, span: Span) -> Result<()> {
+        let lib_path = format!("{}/{}", self.lib, file);
+        let fname = if Path::new(&lib_path).exists() {
+            lib_path
+        } else if Path::new(file).exists() {
+            file.to_string()
+        } else {
+            return error!(E0007; self.file, span => "File not found: {}.", file);
+        };
+        if !self.modules.is_loaded(&fname) {
+            let content = match fs::read_to_string(&fname) {
+                Ok(content) => content,
+                Err(e) => return error!(E0007; self.file, span => "Failed to read file: {}: {}.", fname, e),
+            };
+            let tokens = Lexer::new(content, &fname).proc_tokens()?;
+            let expressions = Parser::new(tokens, &fname).parse()?;
+            let saved = (std::mem::replace(&mut self.file, fname.clone()), std::mem::replace(&mut self.impure, true), self.modules.enter(&fname), self.globals);
+            expressions.iter().try_for_each(|expr| self.top_level(expr).map(|_| ()))?;
+            self.file = saved.0;
+            self.impure = saved.1;
+            self.globals = saved.3;
+            self.modules.leave(saved.2, &fname)?;
+        }
+        self.modules.import(&fname, import, &self.file, span)
+    }
+    fn infer(&mut self, expr: &Expr) -> Result<Type> {
+        let span = expr.span;
+        match &expr.exprt {
+            ExprT::Literal(literal) => Ok(Self::literal(literal)),
+            ExprT::Var(name) if name == "__LINE__" => Ok(Type::Integer),
+            ExprT::Var(name) if name == "__FILE__" => Ok(Type::String),
+            ExprT::Var(name) => match self.variable(name, span)? {
+                Some(scheme) => Ok(self.instantiate(&scheme)),
+                None => {
+                    let similar = closest(name, self.env.iter().map(|(var, _)| var.as_str()));
+                    error!(E0002; self.file, span => "Variable not in scope: {}.", name).map_err(|e| match similar {
+                        Some(similar) => e.suggest("A variable with a similar name exists", Some((span, similar))),
+                        None => e,
+                    })
+                }
+            },
+            ExprT::Load(files) => {
+                files.iter().try_for_each(|file| self.load(file, Import::All, span))?;
+                if expr.line == 0 {
+                    // The implicit prelude.
+                    self.modules.prelude();
+                }
+                Ok(Type::unit())
+            }
+            ExprT::Import(file, alias, names) => {
+                self.load(file, Import::Module(alias.as_deref(), names), span)?;
+                Ok(Type::unit())
+            }
+            ExprT::Module(name, exports) => {
+                self.modules.declare(name, exports, &self.file, span)?;
+                Ok(Type::unit())
+            }
+            ExprT::Def(name, value, impure, signature) => {
+                let name = &if self.depth == 0 { self.modules.define(name) } else { name.clone() };
+                let saved = std::mem::replace(&mut self.impure, *impure);
+                match signature {
+                    Some(signature) => self.signature(name, signature, value, span)?,
+                    None => {
+                        let ty = self.fresh();
+                        self.env.push((name.clone(), Scheme::mono(ty.clone())));
+                        let found = self.infer(value)?;
+                        self.unify(&ty, &found, span)?;
+                        self.env.pop();
+                        let scheme = self.generalize(&ty);
+                        self.env.push((name.clone(), scheme));
+                    }
+                }
+                self.impure = saved;
+                Ok(Type::unit())
+            }
+            ExprT::Macro(name, clauses) => {
+                let r#macro = Macro::new(clauses.clone(), &self.file)?;
+                self.macros.push((name.clone(), r#macro));
+                Ok(Type::unit())
+            }
+            ExprT::Call(func, args) => {
+                if let ExprT::Var(name) = &func.exprt {
+                    if let Some((_, r#macro)) = self.macros.iter().find(|(macro_name, _)| macro_name == name) {
+                        let expanded = r#macro.expand(args.clone(), span, &self.file, &mut self.gensyms, self.expanding)?;
+                        self.expanding += 1;
+                        let ty = self.infer(&expanded);
+                        self.expanding -= 1;
+                        return ty;
+                    }
+                }
+                let func = self.infer(func)?;
+                self.apply(func, args, span)
+            }
+            ExprT::Builtin(name, args) => match builtin(name) {
+                Some((params, returned, numeric)) => {
+                    let (a, b) = (self.fresh(), self.fresh());
+                    if let Type::Var(var) = a {
+                        self.numeric[var as usize] = numeric;
+                    }
+                    let vars = vec![(0, a), (1, b)].into_iter().collect::<HashMap<_, _>>();
+                    let impure = BUILTINS.iter().any(|(builtin, impure)| builtin == name && *impure);
+                    let func = Type::Function(params, Box::new(returned), impure).replace(&vars);
+                    self.apply(func, args, span)
+                }
+                // Unknown builtins are reported by the compiler.
+                None => Ok(self.fresh()),
+            },
+            ExprT::Lambda(args, body) => {
+                let len = self.env.len();
+                let args = args.iter().map(|arg| {
+                    let ty = self.fresh();
+                    self.env.push((arg.clone(), Scheme::mono(ty.clone())));
+                    ty
+                }).collect::<Vec<_>>();
+                let saved = std::mem::replace(&mut self.effects, false);
+                self.depth += 1;
+                let returned = self.infer(body);
+                self.depth -= 1;
+                let returned = returned?;
+                self.env.truncate(len);
+                let impure = std::mem::replace(&mut self.effects, saved);
+                Ok(Type::Function(args, Box::new(returned), impure))
+            }
+            ExprT::Begin(exprs) => {
+                let len = self.env.len();
+                let ty = exprs.iter().try_fold(Type::unit(), |_, expr| self.infer(expr))?;
+                self.env.truncate(len);
+                Ok(ty)
+            }
+            // The variables are generalized, as the definitions are.
+            ExprT::Let(binding, bindings, body) => {
+                let len = self.env.len();
+                match binding {
+                    Binding::Parallel => {
+                        let types = bindings.iter().map(|(_, value)| self.infer(value)).collect::<Result<Vec<_>>>()?;
+                        let schemes = types.iter().map(|ty| self.generalize(ty)).collect::<Vec<_>>();
+                        self.env.extend(bindings.iter().map(|(name, _)| name.clone()).zip(schemes));
+                    }
+                    Binding::Sequential => for (name, value) in bindings {
+                        let ty = self.infer(value)?;
+                        let scheme = self.generalize(&ty);
+                        self.env.push((name.clone(), scheme));
+                    }
+                    Binding::Recursive => {
+                        let types = bindings.iter().map(|(name, _)| {
+                            let ty = self.fresh();
+                            self.env.push((name.clone(), Scheme::mono(ty.clone())));
+                            ty
+                        }).collect::<Vec<_>>();
+                        for ((_, value), ty) in bindings.iter().zip(&types) {
+                            let found = self.infer(value)?;
+                            self.unify(ty, &found, value.span)?;
+                        }
+                        self.env.truncate(len);
+                        let schemes = types.iter().map(|ty| self.generalize(ty)).collect::<Vec<_>>();
+                        self.env.extend(bindings.iter().map(|(name, _)| name.clone()).zip(schemes));
+                    }
+                }
+                let ty = self.infer(body);
+                self.env.truncate(len);
+                ty
+            }
+            ExprT::Tuple(exprs) => Ok(Type::Tuple(exprs.iter().map(|expr| self.infer(expr)).collect::<Result<Vec<_>>>()?)),
+            ExprT::Enum(name, constructors) => {
+                self.define(name, constructors, span)?;
+                Ok(Type::unit())
+            }
+            ExprT::Constr(name, args) => {
+                let constr = self.constructor(name, args.len(), span)?;
+                if args.is_empty() {
+                    Ok(constr)
+                } else {
+                    self.apply(constr, args, span)
+                }
+            }
+            ExprT::Match(value, arms) => {
+                let value = self.infer(value)?;
+                let returned = self.fresh();
+                arms.iter().try_for_each(|(pat, pat_span, body)| {
+                    let len = self.env.len();
+                    self.pattern(pat, &value, *pat_span)?;
+                    let ty = self.infer(body)?;
+                    self.env.truncate(len);
+                    self.unify(&returned, &ty, body.span)
+                })?;
+                Ok(returned)
+            }
+            ExprT::Quasiquote(_) | ExprT::Unquote(_) | ExprT::Splice(_) => bug!("UNEXPANDED_QUASIQUOTE"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn check(code: &str) -> Result<Checker> {
+        let tokens = Lexer::new(code, "TEST").proc_tokens()?;
+        let ast = Parser::new(tokens, "TEST").parse()?;
+        let mut checker = Checker::new("TEST", format!("{}/lib", env!("CARGO_MANIFEST_DIR")), false);
+        checker.check(ast)?;
+        Ok(checker)
+    }
+
+    #[test]
+    fn prelude() -> Result<()> {
+        let checker = check("(def xs (map (range 0 10) (λ (x) (+ x 1))))(def ys (map (chars \"abc\") (λ (s) (format \"{}\" (, s)))))")?;
+        let (_, scheme) = checker.env.iter().rev().find(|(name, _)| name == "list/map").unwrap();
+        assert_eq!(checker.show(&[&scheme.ty])[0], "(-> (List a) (-> a b) (List b))");
+        let (_, scheme) = checker.env.iter().rev().find(|(name, _)| name == "ys").unwrap();
+        assert_eq!(checker.show(&[&scheme.ty])[0], "(List String)");
+        Ok(())
+    }
+
+    #[test]
+    fn modules() -> Result<()> {
+        let checker = check("(import \"list.orn\" l (length))(def map (λ (x) x))(def n (length (l/map [1 2] map)))")?;
+        let (_, scheme) = checker.env.iter().rev().find(|(name, _)| name == "map").unwrap();
+        assert_eq!(checker.show(&[&scheme.ty])[0], "(-> a a)");
+        let error = |code| check(code).err().map(|e| e.message.clone());
+        assert_eq!(error("(def x (_chars \"ab\" 0 Nil))"), Some("Variable not in scope: _chars.".to_string()));
+        assert_eq!(error("(def x (list/_range 0 1 Nil))"), Some("Module list does not export _range.".to_string()));
+        assert_eq!(error("(import \"list.orn\" (_range))"), Some("Module list does not export _range.".to_string()));
+        assert_eq!(error("(import \"prelude.orn\" p)"), Some(format!("Not a module: {}/lib/prelude.orn, that has to be loaded with `load`.", env!("CARGO_MANIFEST_DIR"))));
+        Ok(())
+    }
+
+    #[test]
+    fn mismatches() {
+        let error = |code| check(code).err().map(|e| (e.line, e.message.clone()));
+        assert_eq!(error("(def x (+ 1 2.5))"), Some((Some(1), "Mismatched types: expected `Integer`, found `Single`.".to_string())));
+        assert_eq!(error("(def f (λ (x) (+ x 1)))\n(def y (f \"a\"))"), Some((Some(2), "Mismatched types: expected `Integer`, found `String`.".to_string())));
+        assert_eq!(error("(def x (+ \"a\" \"b\"))"), Some((Some(1), "Expected a Single or an Integer, found `String`.".to_string())));
+        assert_eq!(error("(def x (Cons 1 (Cons 2.0 Nil)))"), Some((Some(1), "Mismatched types: expected `(List Integer)`, found `(List Single)`.".to_string())));
+        assert_eq!(error("(def f (λ (x) (x x)))").map(|(line, _)| line), Some(Some(1)));
+        assert!(check("(def id (λ (x) x))(def a (, (id 1) (id \"b\")))").is_ok());
+    }
+
+    #[test]
+    fn local_bindings() -> Result<()> {
+        let checker = check("(def a (let ((id (λ (x) x))) (, (id 1) (id \"b\"))))
+        (def b (letrec ((even? (λ (n) (match n (0 True) (_ (odd? (- n 1))))))
+                        (odd? (λ (n) (match n (0 False) (_ (even? (- n 1)))))))
+          even?))")?;
+        let (_, scheme) = checker.env.iter().rev().find(|(name, _)| name == "a").unwrap();
+        assert_eq!(checker.show(&[&scheme.ty])[0], "(, Integer String)");
+        let (_, scheme) = checker.env.iter().rev().find(|(name, _)| name == "b").unwrap();
+        assert_eq!(checker.show(&[&scheme.ty])[0], "(-> Integer Bool)");
+        assert!(check("(def c (let* ((x 1) (y (+ x 1))) y))").is_ok());
+        assert!(check("(def d (let ((x 1) (y (+ x 1))) y))").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn signatures() -> Result<()> {
+        let checker = check("(enum Tree (Leaf a) (Node (Tree a) (Tree a)))
+        (def leaves :: (-> (Tree a) Integer) (λ (t) (match t ((Leaf _) 1) ((Node l r) (+ (leaves l) (leaves r))))))")?;
+        let ty = |code| -> Result<String> {
+            let tokens = Lexer::new(code, "TEST").proc_tokens()?;
+            checker.type_of(&Parser::new(tokens, "TEST").parse()?[0])
+        };
+        assert_eq!(ty("leaves")?, "(-> (Tree a) Integer)");
+        assert_eq!(ty("(Node (Leaf 1.0) (Leaf 2.0))")?, "(Tree Single)");
+        assert_eq!(ty("(λ (x) (Just (, x (Cons x Nil))))")?, "(-> a (Maybe (, a (List a))))");
+
+        let error = |code| check(code).err().map(|e| e.message.clone());
+        assert_eq!(error("(def id :: (-> a b) (λ (x) x))"), Some("Mismatched types: expected `(-> a b)`, found `(-> a a)`.".to_string()));
+        assert_eq!(error("(def f :: (-> Integer) (λ () 1.0))"), Some("Mismatched types: expected `(-> Integer)`, found `(-> Single)`.".to_string()));
+        assert_eq!(error("(def f :: (Maybe a b) 1)"), Some("Type Maybe takes 1 parameters, but 2 were given.".to_string()));
+        assert!(error("(def f (λ (x) (begin (def g :: (-> a a) (λ (y) x)) x)))").is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn effects() -> Result<()> {
+        let defs = "(def apply (λ (f x) (f x)))(def 'impure each (λ (xs f) (match xs ((Cons x n) (begin (f x) (each n f))) (Nil ()))))";
+        let checker = check(defs)?;
+        let ty = |code| -> Result<String> {
+            let tokens = Lexer::new(code, "TEST").proc_tokens()?;
+            checker.type_of(&Parser::new(tokens, "TEST").parse()?[0])
+        };
+        assert_eq!(ty("putStrLn")?, "(-> 'impure String ())");
+        assert_eq!(ty("each")?, "(-> 'impure (List a) (-> 'impure a b) ())");
+
+        let error = |code| check(&format!("{}{}", defs, code)).err().map(|e| e.message.clone());
+        let impure = "Mismatched types: expected `(-> a b)`, found `(-> 'impure String ())`.".to_string();
+        assert_eq!(error("(def 'impure main (λ () (apply putStrLn \"a\")))"), Some(impure.clone()));
+        assert_eq!(error("(def 'impure main (λ () (apply (λ (s) (putStrLn s)) \"a\")))"), Some(impure));
+        assert_eq!(error("(def 'impure run (λ (g) (apply g \"a\")))(def 'impure main (λ () (run putStrLn)))"), Some("Mismatched types: expected `(-> String a)`, found `(-> 'impure String ())`.".to_string()));
+        assert_eq!(error("(def 'impure f :: (-> String ()) (λ (s) (putStrLn s)))"), Some("Mismatched types: expected `(-> String ())`, found `(-> 'impure String ())`.".to_string()));
+        assert_eq!(error("(def f :: (-> (-> 'impure String ()) ()) (λ (g) (g \"a\")))"), Some("Mismatched types: expected `(-> (-> 'impure String ()) ())`, found `(-> (-> String a) a)`.".to_string()));
+        assert!(check("(def 'impure f :: (-> 'impure (-> 'impure String ()) ()) (λ (g) (g \"a\")))(def 'impure main (λ () (f (λ (s) ()))))").is_ok());
+        assert!(check(&format!("{}{}", defs, "(def 'impure main (λ () (begin (each [1 2] print) (print (map [1 2] (λ (x) (+ x 1)))))))")).is_ok());
+        Ok(())
+    }
+}
@@ -19,12 +19,13 @@
  *  along with Orion.  If not, see <https://www.gnu.org/licenses/>.
  */
 use crate::{    
-    bytecode::{Bytecode, BytecodePattern, OpCode},
+    bytecode::{Bytecode, BytecodePattern, OpCode, Position, MAX_SLOTS},
     error, bug,
+    lexer::Span,
     parser::Literal,
-    Result,
+    OrionError, Result,
 };
-use std::io::{self, Write};
+use std::{cmp::Ordering, fmt::{self, Display, Formatter}, io::{self, Write}};
 
 use std::rc::Rc;
 
@@ -33,20 +34,96 @@ pub enum Value {
     Integer(i32),
     Single(f32),
     String(String),
-    Lambda(u16, u16, Vec<u16>),
-    Constructor(u16, Vec<Rc<Value>>),
+    Lambda(u32, Env), // (chunk_id, upvalues)
+    Constructor(u32, Vec<Rc<Value>>),
     Tuple(Vec<Rc<Value>>),
 }
+// The children a value owns alone are dropped in a loop, as a long list would otherwise overflow the native stack.
+impl Drop for Value {
+    fn drop(&mut self) {
+        let mut children = vec![];
+        take_children(self, &mut children);
+        while let Some(child) = children.pop() {
+            if let Ok(mut child) = Rc::try_unwrap(child) {
+                take_children(&mut child, &mut children);
+            }
+        }
+    }
+}
+fn take_children(value: &mut Value, children: &mut Vec<Rc<Value>>) {
+    match value {
+        Value::Constructor(_, args) | Value::Tuple(args) => children.append(args),
+        Value::Lambda(_, env) => if let Some(env) = Rc::get_mut(env) {
+            children.append(env)
+        }
+        _ => {}
+    }
+}
+
+pub type Builtin<const STACK_SIZE: usize> = fn(&mut VM<STACK_SIZE>) -> Result<Rc<Value>>;
+pub type State = Vec<Option<Rc<Value>>>; // The globals, indexed by symbol.
+pub type Env = Rc<Vec<Rc<Value>>>; // The values captured by a closure, shared by its copies.
+
+pub const MAX_DEPTH: usize = 100_000;
+const BACKTRACE_LENGTH: usize = 16; // The amount of calls shown in a backtrace.
+
+// The instructions run by a frame.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Code {
+    Main,
+    Chunk(u32), // (chunk_id)
+}
+impl Display for Code {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::Main => write!(f, "main"),
+            Self::Chunk(id) => write!(f, "chunk {:04X}", id),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Frame {
+    pub code: Code,
+    pub ip: usize,
+    pub locals: Vec<Rc<Value>>, // The closure being run, its arguments and its local variables.
+    pub env: Env,
+    pub base: usize, // The height of the stack when the frame was called, that it is brought back to on return.
+}
+impl Frame {
+    fn new(code: Code, locals: Vec<Rc<Value>>, env: Env, base: usize) -> Self {
+        Self {
+            code,
+            ip: 0,
+            locals,
+            env,
+            base,
+        }
+    }
+}
 
 pub struct VM<const STACK_SIZE: usize> {
     pub input: Bytecode,
     pub stack: Vec<Rc<Value>>,
-    saves: Vec<Vec<Rc<Value>>>,
-    pub builtins: Vec<(
-        fn(&mut VM<STACK_SIZE>) -> Result<Rc<Value>>,
-        u8,
-        )>,
-        pub ip: usize,
+    globals: State,
+    pub builtins: Vec<(Builtin<STACK_SIZE>, u32)>,
+    pub frames: Vec<Frame>,
+    max_depth: usize,
+}
+// What is left to write when showing a value, kept on an explicit stack for long lists not to overflow the native one.
+enum Item<'a> {
+    Value(&'a Value, bool), // (value, quotes)
+    Text(&'a str),
+}
+// The slots skipped by the paths of a match that were not taken hold the unit value.
+fn reserve(locals: &mut Vec<Rc<Value>>, length: usize) -> Result<()> {
+    if length > MAX_SLOTS as usize {
+        return error!(E0008; => "Too much local slots are used, the limit is {}.", MAX_SLOTS);
+    }
+    if locals.len() < length {
+        locals.resize(length, Rc::new(Value::Tuple(vec![])));
+    }
+    Ok(())
 }
 fn to_val(lit: &Literal) -> Value {
     match lit {
@@ -56,7 +133,7 @@ fn to_val(lit: &Literal) -> Value {
     }
 }
 impl<const STACK_SIZE: usize> VM<STACK_SIZE> {
-    pub fn new(input: Bytecode, saves: Vec<Vec<Rc<Value>>>) -> Self {
+    pub fn new(input: Bytecode, globals: State) -> Self {
         let mut to_ret = Self {
             input,
             stack: {
@@ -65,8 +142,9 @@ impl<const STACK_SIZE: usize> VM<STACK_SIZE> {
                 stack
             },
             builtins: vec![],
-            saves,
-            ip: 0,
+            globals,
+            frames: vec![],
+            max_depth: MAX_DEPTH,
         };
         to_ret.register_builtin(Self::add, 2);
         to_ret.register_builtin(Self::sub, 2);
@@ -90,101 +168,115 @@ impl<const STACK_SIZE: usize> VM<STACK_SIZE> {
         to_ret.register_builtin(Self::cmp, 2);
         to_ret
     }
+    pub fn depth(self, max_depth: usize) -> Self {
+        Self {
+            max_depth,
+            ..self
+        }
+    }
     pub fn display_value(&self, val: Rc<Value>, quotes: bool) -> String {
-
-        match &*val {
-            Value::Integer(i) => format!("{}", i),
-            Value::Single(r) => format!("{}{}", r, if r.fract() == 0.0 { "." } else { "" }),
-            Value::String(s) => format!("{}{}{}", if quotes { "\"" } else { "" }, s, if quotes { "\"" } else { "" }),
-            Value::Lambda(u, ..) => format!("λ{}", u),
-            Value::Constructor(id, args) => {
-                let name = self.input.symbols[self.input.constructors[*id as usize].1 as usize].clone();
-                if args.len() == 0 {
-                    name
-                } else {
-                    format!( "({} {})", self.input.symbols[self.input.constructors[*id as usize].1 as usize], args.into_iter().map(|a| format!("{}", self.display_value(a.clone(), true))).fold("".to_string(), |acc, c| format!("{}{}{}", acc, if acc.as_str() == "" { "" } else { " " }, c)).trim())
+        let mut to_ret = String::new();
+        let mut items = vec![Item::Value(&val, quotes)];
+        while let Some(item) = items.pop() {
+            let (val, quotes) = match item {
+                Item::Text(text) => {
+                    to_ret.push_str(text);
+                    continue;
+                }
+                Item::Value(val, quotes) => (val, quotes),
+            };
+            match val {
+                Value::Integer(i) => to_ret.push_str(&format!("{}", i)),
+                Value::Single(r) => to_ret.push_str(&format!("{}{}", r, if r.fract() == 0.0 { "." } else { "" })),
+                Value::String(s) => to_ret.push_str(&format!("{}{}{}", if quotes { "\"" } else { "" }, s, if quotes { "\"" } else { "" })),
+                Value::Lambda(u, ..) => to_ret.push_str(&format!("λ{}", u)),
+                Value::Constructor(id, args) => {
+                    let name = &self.input.symbols[self.input.constructors[*id as usize].1 as usize];
+                    if args.is_empty() {
+                        to_ret.push_str(name);
+                    } else {
+                        to_ret.push_str(&format!("({}", name));
+                        items.push(Item::Text(")"));
+                        args.iter().rev().for_each(|a| items.extend([Item::Value(a, true), Item::Text(" ")]));
+                    }
+                }
+                Value::Tuple(args) => {
+                    to_ret.push('(');
+                    items.push(Item::Text(")"));
+                    args.iter().enumerate().rev().for_each(|(idx, a)| {
+                        items.push(Item::Value(a, true));
+                        if idx != 0 {
+                            items.push(Item::Text(" "));
+                        }
+                    });
                 }
-            }            
-            Value::Tuple(args) => format!("({})", args.into_iter().map(|a| format!("{}", self.display_value(a.clone(), true))).fold("".to_string(), |acc, c| format!("{}{}{}", acc, if acc.as_str() == "" { "" } else { " " }, c)).trim()),
+            }
         }
+        to_ret
     }
-    fn _cmp(&mut self, lhs: &Value, rhs: &Value) -> Result<std::cmp::Ordering> {
-        use std::cmp::Ordering;
-        match lhs {
-            Value::Single(lhs) => match rhs {
-                Value::Single(rhs) => {
-                    Ok(lhs.partial_cmp(&rhs).unwrap())
+    fn _cmp(&mut self, lhs: &Value, rhs: &Value) -> Result<Ordering> {
+        // The pairs left to compare, in order, kept on an explicit stack for long lists not to overflow the native one.
+        let mut pairs = vec![(lhs, rhs)];
+        while let Some((lhs, rhs)) = pairs.pop() {
+            let res = match lhs {
+                Value::Single(lhs) => match rhs {
+                    Value::Single(rhs) => {
+                        lhs.partial_cmp(rhs).unwrap()
+                    }
+                    _ => return error!(E0008; => "Expected a Single, found a {}.", self.val_type(rhs)?),
                 }
-                _ => error!(=> "Expected a Single, found a {}.", self.val_type(&rhs)?),
-            }
 
-            Value::Integer(lhs) => match rhs {
-                Value::Integer(rhs) => {
-                    Ok(lhs.cmp(&rhs))
+                Value::Integer(lhs) => match rhs {
+                    Value::Integer(rhs) => {
+                        lhs.cmp(rhs)
+                    }
+                    _ => return error!(E0008; => "Expected an Integer, found a {}.", self.val_type(rhs)?),
                 }
-                _ => error!(=> "Expected an Integer, found a {}.", self.val_type(&rhs)?),
-            }
-            Value::String(lhs) => match rhs {
-                Value::String(rhs) => {
-                    Ok(lhs.cmp(&rhs))
+                Value::String(lhs) => match rhs {
+                    Value::String(rhs) => {
+                        lhs.cmp(rhs)
+                    }
+                    _ => return error!(E0008; => "Expected a String, found a {}.", self.val_type(rhs)?),
                 }
-                _ => error!(=> "Expected a String, found a {}.", self.val_type(&rhs)?),
-            }
-            Value::Constructor(lid, vlhs) => match &rhs {
-                Value::Constructor(rid, vrhs) => {
-                    let tlhs = self.val_type(&lhs)?;
-                    let trhs = self.val_type(&rhs)?;
-                    if tlhs != trhs {
-                        error!(=> "Expected a {}, found a {}.", tlhs, trhs)
-                    } else {
+                Value::Constructor(lid, vlhs) => match &rhs {
+                    Value::Constructor(rid, vrhs) => {
+                        let tlhs = self.val_type(lhs)?;
+                        let trhs = self.val_type(rhs)?;
+                        if tlhs != trhs {
+                            return error!(E0008; => "Expected a {}, found a {}.", tlhs, trhs);
+                        }
+                        // The variants of an enum are ordered as they are declared.
                         if lid != rid {
-                            error!(=> "Not the same enum variants, expected 0x{:04x}, found 0x{:04x}", lid, rid)
+                            lid.cmp(rid)
                         } else {
-                            let mut to_ret = Ordering::Equal;
-
-                            for idx in 0..vlhs.len() {
-                                let lhs = &vlhs[idx];
-                                let rhs = &vrhs[idx];
-                                let res = self._cmp(lhs, rhs)?;
-                                if res != Ordering::Equal {
-                                    to_ret = res;
-                                    break;
-                                }
-                            }
-                            Ok(to_ret)
+                            pairs.extend(vlhs.iter().zip(vrhs).rev().map(|(l, r)| (&**l, &**r)));
+                            Ordering::Equal
                         }
                     }
+                    _ => return error!(E0008; => "Expected a Constructor, found a {}.", self.val_type(rhs)?),
                 }
-                _ => error!(=> "Expected a Constructor, found a {}.", self.val_type(&rhs)?),
-            }
-            Value::Tuple(vlhs) => match rhs {
-                Value::Tuple(vrhs) => {
-                    let tlhs = self.val_type(&lhs)?;
-                    let trhs = self.val_type(&rhs)?;
-                    if tlhs != trhs {
-                        error!(=> "Expected a {}, found a {}.", tlhs, trhs)
-                    } else {
-                        let mut to_ret = Ordering::Equal;
-                        for idx in 0..vlhs.len() {
-                            let lhs = &vlhs[idx];
-                            let rhs = &vrhs[idx];
-                            let res = self._cmp(lhs, rhs)?;
-                            if res != Ordering::Equal {
-                                to_ret = res; 
-                                break;
-                            }
+                Value::Tuple(vlhs) => match rhs {
+                    Value::Tuple(vrhs) => {
+                        let tlhs = self.val_type(lhs)?;
+                        let trhs = self.val_type(rhs)?;
+                        if tlhs != trhs {
+                            return error!(E0008; => "Expected a {}, found a {}.", tlhs, trhs);
                         }
-                        Ok(to_ret)
+                        pairs.extend(vlhs.iter().zip(vrhs).rev().map(|(l, r)| (&**l, &**r)));
+                        Ordering::Equal
                     }
+                    _ => return error!(E0008; => "Expected a Tuple, found a {}.", self.val_type(rhs)?),
                 }
-                _ => error!(=> "Expected a Tuple, found a {}.", self.val_type(&rhs)?),
+                _ => return error!(E0008; => "Expected a String, found a {}.", self.val_type(rhs)?),
+            };
+            if res != Ordering::Equal {
+                return Ok(res);
             }
-            _ => error!(=> "Expected a String, found a {}.", self.val_type(&rhs)?),
         }
+        Ok(Ordering::Equal)
     }
 
     fn cmp(&mut self) -> Result<Rc<Value>> {
-        use std::cmp::Ordering;
         let rhs = self.pop()?;
         let lhs = self.pop()?;
         let correspondance = [Ordering::Less, Ordering::Equal, Ordering::Greater];
@@ -193,51 +285,84 @@ impl<const STACK_SIZE: usize> VM<STACK_SIZE> {
     }
     fn r#type(&mut self) -> Result<Rc<Value>> {
         let popped = self.pop()?;
-        let to_ret = Ok(Rc::new(Value::String(self.val_type(&popped)?)));
-        to_ret
+        Ok(Rc::new(Value::String(self.val_type(&popped)?)))
     }
     pub fn val_type(&mut self, popped: &Value) -> Result<String> {
-        let to_ret = Ok(match popped {
-            Value::Constructor(idx, _) => self.input.types[self.input.types.iter().position(|(_, start, end)| (start..=end).contains(&&idx)).unwrap()].0.clone(),
-            Value::Tuple(content) => format!("({})", content.iter().map(|v|{
-                let to_ret = self.val_type(v)?;
-                Ok(to_ret)
-            }).collect::<Result<Vec<String>>>()?.join(" ")),
-            Value::String(_) => "String".to_string(),
-            Value::Single(_) => "Single".to_string(),
-            Value::Integer(_) => "Integer".to_string(),
-            Value::Lambda(..) => "Lambda".to_string(),
-        });
-        to_ret
+        let mut to_ret = String::new();
+        let mut items = vec![Item::Value(popped, false)];
+        while let Some(item) = items.pop() {
+            match item {
+                Item::Text(text) => to_ret.push_str(text),
+                Item::Value(Value::Constructor(idx, _), _) => to_ret.push_str(&self.input.types[self.input.types.iter().position(|(_, start, end)| (start..=end).contains(&idx)).unwrap()].0),
+                Item::Value(Value::Tuple(content), _) => {
+                    to_ret.push('(');
+                    items.push(Item::Text(")"));
+                    content.iter().enumerate().rev().for_each(|(idx, v)| {
+                        items.push(Item::Value(v, false));
+                        if idx != 0 {
+                            items.push(Item::Text(" "));
+                        }
+                    });
+                }
+                Item::Value(Value::String(_), _) => to_ret.push_str("String"),
+                Item::Value(Value::Single(_), _) => to_ret.push_str("Single"),
+                Item::Value(Value::Integer(_), _) => to_ret.push_str("Integer"),
+                Item::Value(Value::Lambda(..), _) => to_ret.push_str("Lambda"),
+            }
+        }
+        Ok(to_ret)
     }
     fn register_builtin(
         &mut self,
         func: fn(&mut VM<STACK_SIZE>) -> Result<Rc<Value>>,
-        argc: u8,
+        argc: u32,
         ) {
         self.builtins.push((func, argc))
     }
     pub fn pop(&mut self) -> Result<Rc<Value>> {
         match self.stack.pop() {
             Some(v) => Ok(v),
-            None => error!(=> "Stack underflow."),
+            None => error!(E0008; => "Stack underflow."),
         }
 
     }
-    fn decl(&mut self, sym_id: u16, val: Rc<Value>, ctx: &mut Vec<Rc<Value>>, sym_ref: &mut Vec<u16>) {
-        let id = if !sym_ref.contains(&sym_id) {
-            sym_ref.push(sym_id);
-            sym_ref.len() - 1
-        } else {
-            sym_ref.iter().position(|id| id == &sym_id).unwrap()
-        };
-        if id == ctx.len() {
-            ctx.push(val);
+    fn instructions(&self, code: Code) -> &[OpCode] {
+        match code {
+            Code::Main => &self.input.instructions,
+            Code::Chunk(id) => &self.input.chunks[id as usize].instructions,
+        }
+    }
+    fn frame(&mut self) -> &mut Frame {
+        match self.frames.last_mut() {
+            Some(f) => f,
+            None => bug!("VM_NO_FRAME"),
+        }
+    }
+    fn push_frame(&mut self, frame: Frame) -> Result<()> {
+        if self.frames.len() >= self.max_depth {
+            error!(E0008; => "Stack overflow.")
         } else {
-            ctx[id] = val;
+            self.frames.push(frame);
+            Ok(())
+        }
+    }
+    // A call is in tail position when nothing but jumps and scope exits follow it, it then
+    // replaces the current frame instead of nesting a new one, so that recursive loops run
+    // in constant space.
+    fn is_tail(&self) -> bool {
+        let frame = self.frames.last().unwrap_or_else(|| bug!("VM_NO_FRAME"));
+        let instructions = self.instructions(frame.code);
+        let mut ip = frame.ip;
+        frame.code != Code::Main && loop {
+            match instructions.get(ip) {
+                None => break true,
+                Some(OpCode::Jump(offset)) => ip += *offset as usize + 1,
+                Some(OpCode::EndMatch(_)) => ip += 1,
+                Some(_) => break false,
+            }
         }
     }
-    fn eval_opcode(&mut self, opcode: OpCode, ctx: &mut Vec<Rc<Value>>, sym_ref: &mut Vec<u16>, instructions: &[OpCode]) -> Result<()> {
+    fn eval_opcode(&mut self, opcode: OpCode) -> Result<()> {
         match opcode {
             OpCode::Panic(file, line) => if let Literal::Integer(line) = self.input.constants[line as usize] {
                 if let Literal::String(file) = self.input.constants[file as usize].clone() {
@@ -247,88 +372,76 @@ impl<const STACK_SIZE: usize> VM<STACK_SIZE> {
                 }
             }
             OpCode::LoadConst(id) => self.stack.push(Rc::new(to_val(&self.input.constants[id as usize]))),
-            OpCode::LoadSym(id) => {
-                let local_id = if !sym_ref.contains(&id) {
-                    error!(=> "Unbound variable: {}.", self.input.symbols[id as usize])
-                } else {
-                    Ok(sym_ref.iter().position(|sid| sid == &id).unwrap())                
-                }?;
-                self.stack.push(ctx[local_id as usize].clone())
+            OpCode::LoadGlobal(id) => match self.globals.get(id as usize).cloned().flatten() {
+                Some(val) => self.stack.push(val),
+                None => return error!(E0008; => "Unbound variable: {}.", self.input.symbols[id as usize]),
             },
-            OpCode::Def(sym_id, instr_length) => {
-                let saved = self.ip;
-                while self.ip < saved + instr_length as usize {
-                    self.ip += 1;
-                    let instr = instructions[self.ip];
-                    self.eval_opcode(instr, ctx, sym_ref, &instructions)?;
-                }
-                let popped = self.pop()?;
-                let id = if !sym_ref.contains(&sym_id) {
-                    sym_ref.push(sym_id);
-                    sym_ref.len() - 1
-                } else {
-                    sym_ref.iter().position(|id| id == &sym_id).unwrap()
-                };
-                let popped = if let Value::Lambda(idx, save, _) = (*popped).clone() {
-                    let to_ret = Rc::new(Value::Lambda(idx, save, (*sym_ref).clone()));
-                    if id == self.saves[save as usize].len() {
-                        self.saves[save as usize].push(to_ret.clone());
-                    } else {
-                        self.saves[save as usize][id] = to_ret.clone();
-                    }
-                    to_ret
-                } else {
-                    popped
+            OpCode::LoadLocal(slot) => {
+                let val = match self.frame().locals.get(slot as usize) {
+                    Some(val) => val.clone(),
+                    None => return error!(E0008; => "Invalid local slot: 0x{:04x}.", slot),
                 };
-                if id == ctx.len() {
-                    ctx.push(popped);
-                } else {
-                    ctx[id] = popped;
+                self.stack.push(val);
+            }
+            OpCode::LoadUpvalue(idx) => {
+                let val = self.frame().env[idx as usize].clone();
+                self.stack.push(val);
+            }
+            OpCode::LoadSibling(chunk_id) => {
+                let env = self.frame().env.clone();
+                self.stack.push(Rc::new(Value::Lambda(chunk_id, env)));
+            }
+            OpCode::DefGlobal(sym_id) => {
+                let popped = self.pop()?;
+                if self.globals.len() <= sym_id as usize {
+                    self.globals.resize(sym_id as usize + 1, None);
                 }
+                self.globals[sym_id as usize] = Some(popped);
+            }
+            OpCode::DefLocal(slot) => {
+                let popped = self.pop()?;
+                let locals = &mut self.frame().locals;
+                reserve(locals, slot as usize + 1)?;
+                locals[slot as usize] = popped;
+            }
+            OpCode::Constructor(idx) => {
+                let (amount, _) = self.input.constructors[idx as usize];
+                let mut vals = (0..amount)
+                    .map(|_| self.pop())
+                    .collect::<Result<Vec<Rc<Value>>>>()?;
+                vals.reverse();
+                self.stack.push(Rc::new(Value::Constructor(idx, vals)));
+            }
+            OpCode::Tuple(valc) => {
+                let mut vals = (0..valc)
+                    .map(|_| self.pop())
+                    .collect::<Result<Vec<Rc<Value>>>>()?;
+                vals.reverse();
+                self.stack.push(Rc::new(Value::Tuple(vals)));
             }
             OpCode::Lambda(chunk_id) => {
-                self.saves.push(ctx.clone());
-                self.stack.push(Rc::new(Value::Lambda(chunk_id, self.saves.len() as u16 - 1, sym_ref.clone())));
+                let captures = self.input.chunks[chunk_id as usize].captures.len();
+                let mut env = (0..captures)
+                    .map(|_| self.pop())
+                    .collect::<Result<Vec<Rc<Value>>>>()?;
+                env.reverse();
+                self.stack.push(Rc::new(Value::Lambda(chunk_id, Rc::new(env))));
             },
             OpCode::Call(argc) => {
-                let mut args = vec![];
-                for _ in 0..argc {
-                    args.push(self.pop()?);
-                }
-                args.reverse();
-                let func = self.pop()?;
-                if let Value::Lambda(chunk, ctx_id, mut sym_ref) = (*func).clone() {
-                    let mut ctx = (*self.saves[ctx_id as usize]).to_vec();
-                    let chunk = self.input.chunks[chunk as usize].clone();
-                    if chunk.reference.len() != args.len() {
-                        return error!(
-                            => "Expected {} arguments, found {}.",
-                            chunk.reference.len(),
-                            args.len()
-                            );
-                    }
-                    for idx in 0..chunk.reference.len() {
-                        // Fetch arguments and replace the symbol table.
-                        let val = args[idx].clone();
-                        let sym_id = chunk.reference[idx];
-                        self.decl(sym_id, val, &mut ctx, &mut sym_ref);
-                    }
-                    let prev_ip = self.ip;
-                    self.ip = 0; // Reset the instruction counter to fit chunk instructions
-                    while self.ip < chunk.instructions.len() {
-                        let instr = chunk.instructions[self.ip];
-                        self.eval_opcode(instr, &mut ctx, &mut sym_ref, &chunk.instructions)?; // Eval chunk body.
-                        self.ip += 1;
-                    }
-                    self.ip = prev_ip;
-                } else {
-                    return error!(=> "Expected a Lambda, found a {}.", self.val_type(&*func)?);
+                let mut frame = self.call(argc)?;
+                if self.is_tail() {
+                    // The values left by the replaced frame are dropped when the new one returns.
+                    frame.base = self.frames.pop().map_or(frame.base, |replaced| replaced.base);
                 }
+                self.push_frame(frame)?;
             }
             OpCode::Builtin(idx, argc) => {
-                let (f, f_argc) = self.builtins[idx as usize];
-                if f_argc != argc as u8 {
-                    return error!(
+                let (f, f_argc) = match self.builtins.get(idx as usize) {
+                    Some(b) => *b,
+                    None => return error!(E0008; => "No such builtin: 0x{:02x}.", idx),
+                };
+                if f_argc != argc {
+                    return error!(E0008;
                         => "Builtin 0x{:02x} takes {} arguments, but {} arguments were supplied.",
                         idx, f_argc, argc
                         );
@@ -336,186 +449,160 @@ impl<const STACK_SIZE: usize> VM<STACK_SIZE> {
                 let to_push = f(self)?;
                 self.stack.push(to_push);
             }
-            OpCode::Constructor(idx, to_eval) => {
-                let (amount, _) = self.input.constructors[idx as usize];
-                let saved = self.ip;
-                while self.ip < saved + to_eval as usize {
-                    self.ip += 1;
-                    let instruction = instructions[self.ip].clone();
-                    self.eval_opcode(instruction, ctx, sym_ref, instructions.clone())?;
+            OpCode::Jump(offset) => self.frame().ip += offset as usize,
+            OpCode::JumpIfNoMatch(slot, pat, offset) => {
+                let val = self.local(slot)?;
+                if !self.test(pat, &val) {
+                    self.frame().ip += offset as usize;
                 }
-                let mut vals = (0..amount)
-                    .map(|_| self.pop())
-                    .collect::<Result<Vec<Rc<Value>>>>()?;
-                vals.reverse();
-                self.stack.push(Rc::new(Value::Constructor(idx, vals)));
             }
-            OpCode::Tuple(to_eval, valc) => {
-                let saved = self.ip;
-                while self.ip < saved + to_eval as usize {
-                    self.ip += 1;
-                    let instr = instructions[self.ip];
-                    self.eval_opcode(instr, ctx, sym_ref, instructions)?;
+            OpCode::Switch(slot, first, amount) => {
+                let val = self.local(slot)?;
+                // Lands on the jump of the constructor, or on the last one.
+                self.frame().ip += match &*val {
+                    Value::Constructor(idx, _) if *idx >= first && idx - first < amount => (idx - first) as usize,
+                    _ => amount as usize,
                 };
-                let mut vals = (0..valc)
-                    .map(|_| self.pop())
-                    .collect::<Result<Vec<Rc<Value>>>>()?;
-                vals.reverse();
-                self.stack.push(Rc::new(Value::Tuple(vals)));
             }
-            OpCode::Match(idx) => {
-                let to_match = self.pop()?;
-                let patterns = self.input.matches[idx as usize].clone();
-                let plausible = patterns.into_iter().map(|(pat, to_exec)| {
-                    if self.is_plausible(pat, &to_match) {
-                        Some((pat, to_exec))
-                    } else {
-                        None
-                    }
-                }).filter(|p| !p.is_none()).map(|p| p.unwrap()).collect::<Vec<(u16, Vec<OpCode>)>>();
-                for plausible in plausible.into_iter() {
-                    match self.match_and_bound(&to_match, plausible.0) {
-                        Some(to_bind) => {
-                            let mut new_ctx = ctx.clone();
-                            let mut new_ref = sym_ref.clone();
-                            let mut new_stack = (0..to_bind.len()).map(|_| self.pop()).rev().collect::<Result<Vec<_>>>()?;
-                            to_bind.into_iter().for_each(|sym_id| {
-                                let val = new_stack.pop().unwrap();
-                                self.decl(sym_id, val, &mut new_ctx, &mut new_ref);    
-                            });
-                            let saved = self.ip;
-                            self.ip = 0;
-                            while self.ip < plausible.1.len() {
-                                let instr = plausible.1[self.ip];
-                                self.eval_opcode(instr, &mut new_ctx, &mut new_ref, &plausible.1)?;
-                                self.ip += 1;
-                            }
-                            self.ip = saved;
-                            return Ok(());
-                        },
-                        None => {},
-                    }
-                }
-                return error!(=> "No pattern to be matched.");
+            OpCode::Unpack(slot, first) => {
+                let val = self.local(slot)?;
+                let fields = match &*val {
+                    Value::Constructor(_, fields) | Value::Tuple(fields) => fields,
+                    _ => return error!(E0008; => "Expected a Constructor or a Tuple, found a {}.", self.val_type(&val)?),
+                };
+                let locals = &mut self.frame().locals;
+                let first = first as usize;
+                reserve(locals, first + fields.len())?;
+                locals[first..first + fields.len()].clone_from_slice(fields);
             }
+            OpCode::EndMatch(slots) => self.frame().locals.truncate(slots as usize),
+            OpCode::NoMatch => return error!(E0008; => "No pattern to be matched."),
+            OpCode::Position(_) => bug!("VM_POSITION_MARKER"),
         }
 
         Ok(())
     }
-    fn match_and_bound(&mut self, val: &Rc<Value>, pat_idx: u16) -> Option<Vec<u16>> {
-        let pat = &self.input.patterns[pat_idx as usize];
-        match pat {
-            BytecodePattern::Any => Some(vec![]),
-            BytecodePattern::Var(idx) => {
-                self.stack.push((*val).clone());
-                Some(vec![*idx])
-            }
-            BytecodePattern::Literal(idx) => {
-                let lit = &self.input.constants[*idx as usize];
-                match (**val).clone() {
-                    Value::Integer(lhs) => match lit {
-                        Literal::Integer(rhs) => if lhs == *rhs { Some(vec![]) } else { None },
-                        _ => None,
-                    }
-                    Value::Single(lhs) => match lit {
-                        Literal::Single(rhs) => if lhs == *rhs { Some(vec![]) } else { None },
-                        _ => None,
-                    }
-                    Value::String(lhs) => match lit {
-                        Literal::String(rhs) => if lhs == *rhs { Some(vec![]) } else { None },
-                        _ => None
-                    }
-                    _ => bug!("FAILED_PLAUSIBLE_UNEXPECTED_VALUE")
-                }
-            }
-            BytecodePattern::Tuple(pats) => if let Value::Tuple(vals) = (**val).clone() {
-                let pats = pats.clone();
-                if vals.len() == pats.len() {
-                    let mut to_ret = vec![];
-                    for i in 0..vals.len() {
-                        if !self.is_plausible(pats[i], &vals[i]) {
-                            return None;
-                        } else {
-                            match self.match_and_bound(&vals[i], pats[i]) {
-                                Some(ctx) => to_ret.extend(ctx),
-                                None => return None,
-                            }
-                        }
-                    }
-                    Some(to_ret)
-                } else {
-                    None
-                }
-            } else {
-                None
+    // Pops the function and its arguments, and returns the frame to run its body in.
+    fn call(&mut self, argc: u32) -> Result<Frame> {
+        let mut locals = Vec::with_capacity(argc as usize + 1);
+        for _ in 0..=argc {
+            locals.push(self.pop()?);
+        }
+        locals.reverse();
+        let func = locals[0].clone();
+        if let Value::Lambda(chunk, env) = &*func {
+            let expected = self.input.chunks[*chunk as usize].reference.len();
+            if expected != argc as usize {
+                return error!(E0008;
+                    => "Expected {} arguments, found {}.",
+                    expected,
+                    argc
+                    );
             }
-            BytecodePattern::Constr(idx, pats) => if let Value::Constructor(to_match_idx, vals) = (**val).clone() {
-                if to_match_idx == *idx {
-                    let pats = pats.clone();
-                    let mut to_ret = vec![];
-                    for i in 0..vals.len() {
-                        if !self.is_plausible(pats[i], &vals[i]) {
-                            return None;
-                        } else {
-                            match self.match_and_bound(&vals[i], pats[i]) {
-                                Some(ctx) => to_ret.extend(ctx),
-                                None => return None,
-                            }
-                        }
-                    }
-                    Some(to_ret)
-                } else {
-                    None
-                }
-            } else {
-                None
+            Ok(Frame::new(Code::Chunk(*chunk), locals, env.clone(), self.stack.len()))
+        } else {
+            error!(E0008; => "Expected a Lambda, found a {}.", self.val_type(&func)?)
+        }
+    }
+    fn local(&mut self, slot: u32) -> Result<Rc<Value>> {
+        match self.frame().locals.get(slot as usize) {
+            Some(val) => Ok(val.clone()),
+            None => error!(E0008; => "Invalid local slot: 0x{:04x}.", slot),
+        }
+    }
+    // Tests the outermost layer of a pattern, the fields being tested by the following instructions.
+    fn test(&self, pat: u32, val: &Value) -> bool {
+        match (&self.input.patterns[pat as usize], val) {
+            (BytecodePattern::Var(_), _) | (BytecodePattern::Any, _) => true,
+            (BytecodePattern::Constr(idx, _), Value::Constructor(val_idx, _)) => idx == val_idx,
+            (BytecodePattern::Tuple(pats), Value::Tuple(vals)) => pats.len() == vals.len(),
+            (BytecodePattern::Literal(id), val) => match (&self.input.constants[*id as usize], val) {
+                (Literal::Integer(lhs), Value::Integer(rhs)) => lhs == rhs,
+                (Literal::Single(lhs), Value::Single(rhs)) => lhs == rhs,
+                (Literal::String(lhs), Value::String(rhs)) => lhs == rhs,
+                _ => false,
             },
+            _ => false,
+        }
+    }
+    // The source file and position of the instruction a frame is running.
+    fn position(&self, frame: &Frame) -> Option<(String, Position)> {
+        let lines = match frame.code {
+            Code::Main => &self.input.lines,
+            Code::Chunk(id) => &self.input.chunks[id as usize].lines,
+        };
+        let pos = Position::find(lines, frame.ip.saturating_sub(1))?;
+        match self.input.constants.get(pos.file as usize) {
+            Some(Literal::String(file)) => Some((file.clone(), pos)),
+            _ => None,
         }
     }
-    fn is_plausible(&self, pat: u16, to_match: &Value) -> bool {
-        let pat = self.input.patterns[pat as usize].clone();
-        match pat {
-            BytecodePattern::Var(_) | BytecodePattern::Any => true,
-            BytecodePattern::Constr(_, _) => if let Value::Constructor(_, _) = to_match {
-                true
-            } else {
-                false
+    // Locates an error raised by the running instruction, and lists the calls that led to it.
+    fn trace(&self, mut e: OrionError) -> OrionError {
+        if e.file.is_some() {
+            return e;
+        }
+        if let Some((file, pos)) = self.frames.last().and_then(|frame| self.position(frame)) {
+            let span = Span { start: pos.start as usize, end: pos.end as usize, line: pos.line as usize, column: pos.column as usize };
+            e.file = Some(file);
+            e.line = Some(span.line);
+            e.span = Some(span).filter(|span| span.end > span.start);
+        }
+        if self.frames.len() > 1 {
+            let mut calls = self.frames.iter().rev().take(BACKTRACE_LENGTH).map(|frame| {
+                let name = match frame.code {
+                    Code::Main => "top level".to_string(),
+                    Code::Chunk(id) => self.input.chunks[id as usize].name.map_or("λ".to_string(), |sym| self.input.symbols[sym as usize].clone()),
+                };
+                match self.position(frame) {
+                    Some((file, pos)) => format!("{} at {}:{}:{}", name, file, pos.line, pos.column),
+                    None => name,
+                }
+            }).collect::<Vec<_>>();
+            if self.frames.len() > BACKTRACE_LENGTH {
+                calls.push(format!("... and {} more calls.", self.frames.len() - BACKTRACE_LENGTH));
             }
-            BytecodePattern::Tuple(_) => if let Value::Tuple(_) = to_match {
-                true
-            } else {
-                false
+            e.backtrace = calls;
+        }
+        e
+    }
+    // Executes the next instruction, returns false once the main frame is over.
+    fn step(&mut self) -> Result<bool> {
+        loop {
+            let frame = self.frames.last().unwrap_or_else(|| bug!("VM_NO_FRAME"));
+            let ip = frame.ip;
+            let instructions = self.instructions(frame.code);
+            if ip < instructions.len() {
+                let instr = instructions[ip];
+                self.frame().ip += 1;
+                return match self.eval_opcode(instr) {
+                    Ok(()) => Ok(true),
+                    Err(e) => Err(self.trace(e)),
+                };
+            } else if self.frames.len() == 1 {
+                return Ok(false);
             }
-            BytecodePattern::Literal(lid) => match &self.input.constants[lid as usize] {
-                Literal::Integer(_) => match to_match {
-                    Value::Integer(_) => true,
-                    _ => false,
-                }
-                Literal::Single(_) => match to_match {
-                    Value::Single(_) => true,
-                    _ => false
-                }
-                Literal::String(_) => match to_match {
-                    Value::String(_) => true,
-                    _ => false,
-                }
+            // Only the returned value is kept, the values of the expressions of a `begin` but the last
+            // one being left on the stack.
+            let base = self.frames.pop().map_or(0, |frame| frame.base);
+            if self.stack.len() > base {
+                let returned = self.pop()?;
+                self.stack.truncate(base);
+                self.stack.push(returned);
             }
         }
     }
-    pub fn eval(&mut self, mut sym_ref: Vec<u16>, mut ctx: Vec<Rc<Value>>, mut step: bool) -> Result<(Vec<Rc<Value>>, Vec<u16>, Vec<Vec<Rc<Value>>>)> {
+    pub fn eval(&mut self, mut step: bool) -> Result<State> {
         if step {
             println!("Welcome to the Orion DeBugger, type `h' to get help.");
         }
-        while self.ip < self.input.instructions.len() {
-            let instruction = self.input.instructions[self.ip];
-            let instrs = self.input.instructions.clone();
-            self.eval_opcode(instruction, &mut ctx, &mut sym_ref, &instrs)?;
+        self.frames = vec![Frame::new(Code::Main, vec![], Rc::new(vec![]), 0)];
+        while self.step()? {
             if step {
                 step = self.dbg_step();
             }
-            self.ip += 1;
         }
-        Ok((ctx, sym_ref, self.saves.clone()))
+        Ok(self.globals.clone())
     }
     pub fn dbg_step(&mut self) -> bool {
         loop {
@@ -529,26 +616,47 @@ n\tExecute next opcode.
 c\tPrint next opcode.
 q\tExit ODB.
 s\tDisplay stack.
+b\tDisplay the call frames.
+l\tDisplay the local variables of the current frame.
 i\tDisplay the 15 instructions around the instruction pointer.";
+            let frame = self.frames.last().unwrap_or_else(|| bug!("VM_NO_FRAME"));
+            let instructions = self.instructions(frame.code);
+            let ip = frame.ip;
             match buffer.trim() {
                 "h" => println!("{}", help),
                 "n" => return true,
                 "q" => return false,
-                "c" => println!("{}", self.input.instructions[self.ip]),
-                "s" => println!("[{}]", self.stack.iter().skip(1).fold(self.stack.iter().nth(0).and_then(|e| Some(self.display_value(e.clone(), true))).unwrap_or("".to_string()), |acc, x| format!("{}, {}", acc, self.display_value(x.clone(), true)))),
+                "c" => match instructions.get(ip) {
+                    Some(instr) => println!("{}", instr),
+                    None => println!("End of {}.", frame.code),
+                }
+                "s" => println!("[{}]", self.stack.iter().skip(1).fold(self.stack.first().map(|e| self.display_value(e.clone(), true)).unwrap_or("".to_string()), |acc, x| format!("{}, {}", acc, self.display_value(x.clone(), true)))),
+                "b" => self.frames.iter().rev().enumerate().for_each(|(idx, frame)| {
+                    println!("#{} {} at {:04X}", idx, frame.code, frame.ip);
+                }),
+                "l" => {
+                    frame.locals.iter().enumerate().for_each(|(slot, val)| {
+                        println!("#{} = {}", slot, self.display_value(val.clone(), true));
+                    });
+                    if let Code::Chunk(id) = frame.code {
+                        self.input.chunks[id as usize].captures.iter().zip(frame.env.iter()).for_each(|(sym, val)| {
+                            println!("{} = {}", self.input.symbols[*sym as usize], self.display_value(val.clone(), true));
+                        });
+                    }
+                }
                 "i" => {
-                    let start = if 7 > self.ip {
-                        (0, -(self.ip as i32))
+                    let start = if 7 > ip {
+                        (0, -(ip as i32))
                     } else {
-                        (self.ip - 7, -7)
+                        (ip - 7, -7)
                     };
-                    let end = if self.ip + 7  > self.input.instructions.len() {
-                        (self.input.instructions.len(), self.input.instructions.len() as i32 - self.ip as i32)
+                    let end = if ip + 7  > instructions.len() {
+                        (instructions.len(), instructions.len() as i32 - ip as i32)
                     } else {
-                        (self.ip + 7, 7 as i32)
+                        (ip + 7, 7_i32)
                     };
                     let indices = (start.1..end.1).collect::<Vec<i32>>();
-                    self.input.instructions[start.0..end.0].iter().enumerate().for_each(|(idx, i)| {
+                    instructions[start.0..end.0].iter().enumerate().for_each(|(idx, i)| {
                         println!("{}{}    {}", if indices[idx] > -1 { " " } else { "" }, indices[idx], i);
                     })
                 }
@@ -562,35 +670,179 @@ i\tDisplay the 15 instructions around the instruction pointer.";
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::lexer::Lexer;
-    use crate::parser::Parser;
-    use crate::compiler::Compiler;
-    use std::time::Instant;
+    use crate::{compiler::Compiler, lexer::Lexer, modules::Modules, parser::Parser};
+
+    fn eval(code: &str, max_depth: usize) -> Result<Rc<Value>> {
+        let ast = Parser::new(Lexer::new(code, "TEST").proc_tokens()?, "TEST").parse()?;
+        let (bytecode, ..) = Compiler::new(ast, "TEST", Bytecode::new(), vec![], true, "".to_string(), true, vec![], Modules::default())?.compile(vec![])?;
+        let mut vm = VM::<256>::new(bytecode, vec![]).depth(max_depth);
+        vm.eval(false)?;
+        vm.pop()
+    }
+
+    #[test]
+    fn tail_calls() -> Result<()> {
+        // Deep enough to overflow the native stack of the test thread without tail calls.
+        let value = eval("(def loop (λ (n) (match n (0 \"done\") (_ (begin (def m (- n 1)) (loop m))))))
+        (loop 100000)", 8)?;
+        assert!(matches!(&*value, Value::String(s) if s == "done"));
+        Ok(())
+    }
+
+    #[test]
+    fn deep_recursion() -> Result<()> {
+        let sum = "(def sum (λ (n) (match n (0 0) (_ (+ n (sum (- n 1)))))))";
+        let value = eval(&format!("{} (sum 50000)", sum), MAX_DEPTH)?;
+        assert!(matches!(&*value, Value::Integer(1250025000)));
+        let err = eval(&format!("{} (sum 50000)", sum), 1000).unwrap_err();
+        assert_eq!(err.message, "Stack overflow.");
+        Ok(())
+    }
+
+    #[test]
+    fn deep_data() -> Result<()> {
+        // The frames and values left when the calls are too deep must also be torn down without overflowing.
+        let build = "(enum List (Cons x xs) Nil)
+        (def build (λ (n) (match n (0 Nil) (_ (Cons n (build (- n 1)))))))
+        (def wrap (λ (n f) (match n (0 f) (_ (wrap (- n 1) (λ () f))))))";
+        let value = eval(&format!("{} (, (build 50000) (wrap 100000 build))", build), MAX_DEPTH)?;
+        assert!(matches!(&*value, Value::Tuple(values) if matches!(&*values[0], Value::Constructor(..)) && matches!(&*values[1], Value::Lambda(..))));
+        drop(value);
+        let err = eval(&format!("{} (def l (wrap 100000 (build 20000))) (build 50000)", build), 30000).unwrap_err();
+        assert_eq!(err.message, "Stack overflow.");
+        Ok(())
+    }
+
+    #[test]
+    fn library_depth() -> Result<()> {
+        // The functions of the library over lists and strings run in constant depth, whatever the size of their input.
+        let code = "(, (list/length (list/range 0 100000)) (string/strlen (format \"{}\" (, (list/range 0 1000)))))";
+        let ast = Parser::new(Lexer::new(code, "TEST").proc_tokens()?, "TEST").parse()?;
+        let lib = format!("{}/lib", env!("CARGO_MANIFEST_DIR"));
+        let (bytecode, ..) = Compiler::new(ast, "TEST", Bytecode::new(), vec![], false, lib, true, vec![], Modules::default())?.compile(vec![])?;
+        let mut vm = VM::<256>::new(bytecode, vec![]).depth(64);
+        vm.eval(false)?;
+        let value = vm.pop()?;
+        let shown = (0..1000).rev().fold("Nil".to_string(), |acc, n| format!("(Cons {} {})", n, acc));
+        assert!(matches!(&*value, Value::Tuple(values) if matches!((&*values[0], &*values[1]), (Value::Integer(100000), Value::Integer(n)) if *n as usize == shown.len())));
+        Ok(())
+    }
+
+    #[test]
+    fn large_values() -> Result<()> {
+        // Long enough to overflow the native stack of the test thread if shown, compared or dropped recursively.
+        let list = "(enum List (Cons x xs) Nil)
+        (def build (λ (n acc) (match n (0 acc) (_ (build (- n 1) (Cons n acc))))))
+        (def l (build 100000 Nil))";
+        let value = eval(&format!("{} (, (format \"{{}}\" (, l)) (_cmp l (build 100000 Nil)) (type l) l)", list), 8)?;
+        let values = match &*value {
+            Value::Tuple(values) => values,
+            _ => panic!("Expected a tuple."),
+        };
+        assert!(matches!(&*values[0], Value::String(s) if s.starts_with("(Cons 1 (Cons 2 ") && s.contains(" (Cons 100000 Nil)")));
+        assert!(matches!(&*values[1], Value::Integer(1)));
+        assert!(matches!(&*values[2], Value::String(s) if s == "List"));
+        assert!(matches!(&*values[3], Value::Constructor(..)));
+        Ok(())
+    }
+
+    #[test]
+    fn local_slots() -> Result<()> {
+        // The slots a function uses are bounded, even when a value has more fields than expected.
+        let bytecode = Bytecode {
+            instructions: vec![OpCode::Tuple(0), OpCode::Tuple(0), OpCode::Tuple(2), OpCode::DefLocal(0), OpCode::Unpack(0, MAX_SLOTS - 1)],
+            ..Bytecode::new()
+        };
+        bytecode.validate()?;
+        let mut vm = VM::<16>::new(bytecode, vec![]);
+        assert_eq!(vm.eval(false).unwrap_err().message, format!("Too much local slots are used, the limit is {}.", MAX_SLOTS));
+        Ok(())
+    }
+
+    #[test]
+    fn closures() -> Result<()> {
+        let value = eval("(def adder (λ (n) (λ (x) (+ x n))))
+        (def times (λ (n) (begin (def go (λ (i acc) (match i (0 acc) (_ (go (- i 1) (+ acc n)))))) (go 3 0))))
+        ((adder (times 2)) 1)", 16)?;
+        assert!(matches!(&*value, Value::Integer(7)));
+        let value = eval("(def f (λ (x) { (+ x 1) (, x) x }))
+        (def second (λ (a b) b))
+        (second (f 1) (f (f 2)))", 16)?;
+        assert!(matches!(&*value, Value::Integer(2)));
+        Ok(())
+    }
+
+    #[test]
+    fn local_bindings() -> Result<()> {
+        let value = eval("(def x 10) (+ (let ((x 1) (y x)) (+ x y)) (* 100 (let* ((x 1) (y x)) (+ x y))))", 16)?;
+        assert!(matches!(&*value, Value::Integer(211)));
+        // The functions call each other in tail position, and capture a variable of the enclosing one.
+        let value = eval("(def parity (λ (n yes)
+          (letrec ((even? (λ (n) (match n (0 yes) (_ (odd? (- n 1))))))
+                   (odd? (λ (n) (match n (0 \"odd\") (_ (even? (- n 1)))))))
+            (let ((check (λ () (even? n)))) (check)))))
+        (, (parity 100001 \"even\") (parity 100000 \"even\"))", 8)?;
+        assert!(matches!(&*value, Value::Tuple(v) if matches!(&*v[0], Value::String(s) if s == "odd") && matches!(&*v[1], Value::String(s) if s == "even")));
+        Ok(())
+    }
+
+    #[test]
+    fn decision_trees() -> Result<()> {
+        let value = eval("(enum Shape (Circle r) (Rect w h) Dot)
+        (def area (λ (s) (match s ((Circle r) (* r r)) ((Rect w 0) 0) ((Rect w h) (* w h)) (_ 7))))
+        (def kind (λ (v) (match v ((, (Rect 1 _) \"a\") 1) ((, (Rect _ 2) _) 2) ((, _ \"a\") 3) (0 4) (_ 5))))
+        (, (area (Circle 3)) (area (Rect 3 0)) (area (Rect 3 4)) (area Dot) (area 42)
+           (kind (, (Rect 1 2) \"a\")) (kind (, (Rect 1 2) \"b\")) (kind (, Dot \"a\")) (kind 0) (kind (, 1 2 3)))", 16)?;
+        let values = match &*value {
+            Value::Tuple(values) => values.iter().map(|v| match **v {
+                Value::Integer(i) => i,
+                _ => -1,
+            }).collect::<Vec<_>>(),
+            _ => vec![],
+        };
+        assert_eq!(values, vec![9, 0, 12, 7, 7, 1, 2, 3, 4, 5]);
+        assert_eq!(eval("(match 1 (0 0))", 16).unwrap_err().message, "No pattern to be matched.");
+        assert!(matches!(&*eval("(enum Size Small Big) (_cmp Big Small)", 16)?, Value::Integer(2)));
+        Ok(())
+    }
+
+    #[test]
+    fn locations() {
+        let err = eval("(def add (λ (a b) (+ a b)))
+(def f (λ (x) (, (add x \"a\"))))
+(f 1)", 16).unwrap_err();
+        assert_eq!((err.file.as_deref(), err.line), (Some("TEST"), Some(1)));
+        assert_eq!(err.span.map(|span| (span.column, span.end - span.start)), Some((19, 7)));
+        assert_eq!(err.backtrace, vec!["add at TEST:1:19", "f at TEST:2:18", "top level at TEST:3:1"]);
+        let err = eval("(def loop (λ (n) (match n (0 (loop n)))))\n(loop 1)", 16).unwrap_err();
+        assert_eq!(err.backtrace, vec!["loop at TEST:1:18", "top level at TEST:2:1"]);
+    }
 
     #[cfg(not(debug_assertions))] // Run only in Release
     #[test]
     fn ackermann() -> Result<()> {
+        use std::time::Instant;
         let tokens = Lexer::new("(def ack (λ (m n)
         (match (, m n)
          ((, 0 _) (+ n 1))
          ((, _ 0) (ack (- m 1) 1))
          (_ (ack (- m 1) (ack m (- n 1)))))))", "TEST").proc_tokens()?;
         let ast = Parser::new(tokens, "TEST").parse()?;
-        let (bytecode, symbols, _) = Compiler::new(ast, "TEST", Bytecode::new(), vec![], true, "".to_string(), true)?.compile(vec![])?;
+        let (bytecode, symbols, ..) = Compiler::new(ast, "TEST", Bytecode::new(), vec![], true, "".to_string(), true, vec![], Modules::default())?.compile(vec![])?;
 
-        let (ctx, sym_ref, saves) = VM::<256>::new(bytecode.clone(), vec![]).eval(vec![], vec![])?;
-        let (call_bytecode, ..) = Compiler::new(Parser::new(Lexer::new("(ack 3 6)", "TEST").proc_tokens()?, "TEST").parse()?, "TEST", bytecode, vec![], true, "".to_string(), true)?.compile(symbols)?;
+        let globals = VM::<256>::new(bytecode.clone(), vec![]).eval(false)?;
+        let (call_bytecode, ..) = Compiler::new(Parser::new(Lexer::new("(ack 3 6)", "TEST").proc_tokens()?, "TEST").parse()?, "TEST", bytecode, vec![], true, "".to_string(), true, vec![], Modules::default())?.compile(symbols)?;
         let mut vals = (0..200).map(|_| {
-            let mut vm = VM::<16000>::new(call_bytecode.clone(), saves.clone());
+            let mut vm = VM::<16000>::new(call_bytecode.clone(), globals.clone());
             let start = Instant::now();
-            vm.eval(sym_ref.clone(), ctx.clone())?;
+            vm.eval(false)?;
             let elapsed = start.elapsed();
             Ok(elapsed.as_millis() as u32)
         }).collect::<Result<Vec<u32>>>()?;
         vals.sort();
         let total = vals.iter().sum::<u32>() as f32;
         let average = total / vals.len() as f32;
-        let stddev = (0..vals.len()).into_iter().map(|i| {
+        let stddev = (0..vals.len()).map(|i| {
             (vals[i] as f32 - average).powi(2)
         }).sum::<f32>().sqrt();
         println!("Total: {}ms ; Average: {}ms ; Median: {}ms ; Amplitude: {}ms ; Stddev: {}us", total, average, vals[vals.len() / 2], vals[vals.len() - 1] - vals[0], stddev);